@@ -53,6 +53,7 @@ run_clippy() {
         do
             cargo clippy --no-default-features --features "$SIDE,$KEYMAP" -- -D warnings
         done
+        cargo clippy --no-default-features --features "$SIDE,keymap_borisfaure,half_duplex,matrix_sync,bootloader" -- -D warnings
     done
 }
 
@@ -67,17 +68,21 @@ run_check() {
         do
             cargo check --no-default-features --features "$SIDE,$KEYMAP"
         done
+        cargo check --no-default-features --features "$SIDE,keymap_borisfaure,half_duplex,matrix_sync,bootloader"
     done
 }
 
 run_test() {
-    cargo test
+    # The tests run on the host, without the examples built for the board
+    HOST=x86_64-unknown-linux-gnu
+    cargo test --target "$HOST" --bins
     for SIDE in "${SIDES[@]}"
     do
         for KEYMAP in "${KEYMAPS[@]}"
         do
-            cargo test --no-default-features --features "$SIDE,$KEYMAP"
+            cargo test --target "$HOST" --bins --no-default-features --features "$SIDE,$KEYMAP"
         done
+        cargo test --target "$HOST" --bins --no-default-features --features "$SIDE,keymap_borisfaure,half_duplex,matrix_sync,bootloader"
     done
 }
 
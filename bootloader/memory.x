@@ -0,0 +1,12 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* Sectors 0 and 1, the firmware follows, see ../src/image.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 32K
+  RAM : ORIGIN = 0x20000000, LENGTH = 64K
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
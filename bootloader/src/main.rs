@@ -0,0 +1,137 @@
+//! Bootloader of the Cantor36 keyboard
+//!
+//! Installs the firmware update waiting in the DFU partition, if its CRC is
+//! valid, then starts the firmware.
+
+#![no_std]
+#![no_main]
+#![warn(missing_docs)]
+#![warn(clippy::missing_docs_in_private_items)]
+
+use cortex_m_rt::entry;
+use defmt::*;
+use defmt_rtt as _;
+use embassy_stm32::flash::{Blocking, Error, Flash, FLASH_BASE};
+use panic_probe as _;
+
+/// Layout of the firmware images in flash, shared with the firmware
+#[path = "../../src/image.rs"]
+mod image;
+
+use image::{
+    crc32_update, ImageHeader, ACTIVE_OFFSET, ACTIVE_SIZE, DFU_OFFSET, DFU_SIZE, HEADER_SIZE,
+    IMAGE_OFFSET,
+};
+
+/// Size of the buffer used to read and copy the images
+const BUF_SIZE: usize = 1024;
+/// The images are copied by blocks of this size, to respect the write size
+/// of the flash
+const BLOCK_SIZE: u32 = 32;
+/// Number of times the update is copied over the active firmware before
+/// giving up, when the copy is not valid
+const INSTALL_ATTEMPTS: usize = 3;
+
+/// Read the header of the image waiting in the DFU partition, if any
+fn read_header(flash: &mut Flash<'_, Blocking>) -> Result<Option<ImageHeader>, Error> {
+    let mut bytes = [0u8; HEADER_SIZE as usize];
+    flash.blocking_read(DFU_OFFSET, &mut bytes)?;
+    Ok(ImageHeader::from_bytes(&bytes))
+}
+
+/// Compute the CRC-32 of the `size` bytes of the flash at `offset`
+fn flash_crc(flash: &mut Flash<'_, Blocking>, offset: u32, size: u32) -> Result<u32, Error> {
+    let mut buf = [0u8; BUF_SIZE];
+    let mut crc = 0;
+    let mut pos = 0;
+    while pos < size {
+        let len = BUF_SIZE.min((size - pos) as usize);
+        flash.blocking_read(offset + pos, &mut buf[..len])?;
+        crc = crc32_update(crc, &buf[..len]);
+        pos += len as u32;
+    }
+    Ok(crc)
+}
+
+/// Copy the image of the DFU partition over the active firmware and check it
+///
+/// Returns whether the copy is valid.
+fn install(flash: &mut Flash<'_, Blocking>, header: ImageHeader) -> Result<bool, Error> {
+    flash.blocking_erase(ACTIVE_OFFSET, ACTIVE_OFFSET + ACTIVE_SIZE)?;
+    // The image is followed by erased bytes in the DFU partition
+    let size = header.size.next_multiple_of(BLOCK_SIZE);
+    let mut buf = [0u8; BUF_SIZE];
+    let mut pos = 0;
+    while pos < size {
+        let len = BUF_SIZE.min((size - pos) as usize);
+        flash.blocking_read(IMAGE_OFFSET + pos, &mut buf[..len])?;
+        flash.blocking_write(ACTIVE_OFFSET + pos, &buf[..len])?;
+        pos += len as u32;
+    }
+    Ok(flash_crc(flash, ACTIVE_OFFSET, header.size)? == header.crc)
+}
+
+/// Install the update waiting in the DFU partition, if any
+///
+/// Returns whether the active firmware can be started: left as is, or
+/// replaced by the update and checked.  The flash errors returned happen
+/// while the active firmware is in one of those states, the errors during
+/// the installation being retried.
+fn update(flash: &mut Flash<'_, Blocking>) -> Result<bool, Error> {
+    let Some(header) = read_header(flash)? else {
+        return Ok(true);
+    };
+    info!("Update of {} bytes found", header.size);
+    if flash_crc(flash, IMAGE_OFFSET, header.size)? != header.crc {
+        warn!("Corrupted update, discarding it");
+        flash.blocking_erase(DFU_OFFSET, DFU_OFFSET + DFU_SIZE)?;
+        return Ok(true);
+    }
+    for attempt in 1..=INSTALL_ATTEMPTS {
+        match install(flash, header) {
+            Ok(true) => {
+                info!("Update installed");
+                flash.blocking_erase(DFU_OFFSET, DFU_OFFSET + DFU_SIZE)?;
+                return Ok(true);
+            }
+            Ok(false) => error!(
+                "Update not installed correctly, attempt {}/{}",
+                attempt, INSTALL_ATTEMPTS
+            ),
+            Err(e) => error!(
+                "Flash error while installing the update: {:?}, attempt {}/{}",
+                e, attempt, INSTALL_ATTEMPTS
+            ),
+        }
+    }
+    // Keep the update to try again on the next reset
+    Ok(false)
+}
+
+/// Start the firmware whose vector table is at `address`
+///
+/// # Safety
+/// A valid firmware must be at `address`.
+unsafe fn start_firmware(address: u32) -> ! {
+    let scb = &*cortex_m::peripheral::SCB::PTR;
+    scb.vtor.write(address);
+    cortex_m::asm::bootload(address as *const u32)
+}
+
+#[entry]
+fn main() -> ! {
+    let p = embassy_stm32::init(Default::default());
+    let mut flash = Flash::new_blocking(p.FLASH);
+    let valid = update(&mut flash).unwrap_or_else(|e| {
+        error!("Flash error during update: {:?}", e);
+        true
+    });
+    if !valid {
+        error!("No valid firmware to start, reset to install the update again");
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+    // SAFETY: the firmware was either left as is, or installed and checked
+    unsafe { start_firmware(FLASH_BASE as u32 + ACTIVE_OFFSET) }
+}
@@ -0,0 +1,44 @@
+//! Provide the memory layout to the linker: the whole flash, or only the
+//! partition of the active firmware when started by the bootloader, and
+//! hash the source of the keymap to identify it
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory = if env::var_os("CARGO_FEATURE_BOOTLOADER").is_some() {
+        "memory-bootloader.x"
+    } else {
+        "memory.x"
+    };
+    fs::copy(memory, out.join("memory.x")).unwrap();
+    println!("cargo:rustc-link-search={}", out.display());
+    println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-bootloader.x");
+
+    // FNV-1a hash of the source of the keymap, the same on both halves when
+    // built from the same keymap
+    let keymap = ["keymap_basic", "keymap_borisfaure", "keymap_test"]
+        .into_iter()
+        .find(|k| env::var_os(format!("CARGO_FEATURE_{}", k.to_uppercase())).is_some());
+    let hash = keymap.map_or(0, |k| {
+        let path = format!("src/{}.rs", k);
+        println!("cargo:rerun-if-changed={}", path);
+        fs::read(path)
+            .unwrap()
+            .iter()
+            .fold(0x811c_9dc5u32, |hash, &b| {
+                (hash ^ b as u32).wrapping_mul(0x0100_0193)
+            })
+    });
+    fs::write(
+        out.join("keymap_hash.rs"),
+        format!(
+            "/// Hash of the source of the keymap\nconst KEYMAP_HASH: u32 = {:#010x};\n",
+            hash
+        ),
+    )
+    .unwrap();
+}
@@ -0,0 +1,13 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* Active firmware partition, after the bootloader, see src/image.rs */
+  /* Limited to what fits in the DFU partition, after the image header */
+  FLASH : ORIGIN = 0x08008000, LENGTH = 127K
+  RAM : ORIGIN = 0x20000000, LENGTH = 64K
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
@@ -0,0 +1,282 @@
+use crate::layout::{CustomEvent, AUTO_SHIFT_ROW};
+use defmt::info;
+use keyberon::action::Action;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::Event;
+
+/// Coordinates of a key, as (row, column)
+type Key = (u8, u8);
+
+/// Configuration of the auto-shift
+#[derive(Debug, Clone, Copy)]
+pub struct AutoShiftConfig {
+    /// Time a key has to be held to be shifted, in ms
+    pub timeout: u16,
+    /// Key codes never shifted
+    pub excluded: &'static [KeyCode],
+}
+
+/// Key held, not sent to the layout yet
+struct Pending {
+    /// Coordinates of the key
+    key: Key,
+    /// Time since the key was pressed, in ms
+    elapsed: u16,
+}
+
+/// Auto-shift engine, between the key events and the layout
+///
+/// The alpha, number and symbol keys held long enough are sent with the
+/// virtual key of Shift of the auto-shift row, released with the key or as
+/// soon as another key is pressed. The keys with any other action, like the
+/// hold tap keys, are left alone.
+pub struct AutoShift {
+    /// Configuration of the keymap
+    config: AutoShiftConfig,
+    /// Whether auto-shift is on
+    enabled: bool,
+    /// Key held, not sent to the layout yet
+    pending: Option<Pending>,
+    /// Key held shifted, while the virtual key of Shift is held
+    shifted: Option<Key>,
+}
+
+impl AutoShift {
+    /// Create a new auto-shift engine
+    pub fn new(config: AutoShiftConfig) -> Self {
+        AutoShift {
+            config,
+            enabled: false,
+            pending: None,
+            shifted: None,
+        }
+    }
+
+    /// Switch auto-shift on or off
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+        info!("Auto-shift: {}", if self.enabled { "on" } else { "off" });
+    }
+
+    /// Whether a key with `action` is shifted when held
+    fn is_shiftable(&self, action: &Action<CustomEvent>) -> bool {
+        let Action::KeyCode(kc) = *action else {
+            return false;
+        };
+        let code = kc as u8;
+        ((KeyCode::A as u8..=KeyCode::Kb0 as u8).contains(&code)
+            || (KeyCode::Minus as u8..=KeyCode::Slash as u8).contains(&code))
+            && !self.config.excluded.contains(&kc)
+    }
+
+    /// Send the pending key to the layout, unshifted
+    fn resolve(&mut self, emit: &mut impl FnMut(Event)) {
+        if let Some(pending) = self.pending.take() {
+            emit(Event::Press(pending.key.0, pending.key.1));
+        }
+    }
+
+    /// Release the virtual key of Shift, if held
+    fn unshift(&mut self, emit: &mut impl FnMut(Event)) {
+        if self.shifted.take().is_some() {
+            emit(Event::Release(AUTO_SHIFT_ROW, 0));
+        }
+    }
+
+    /// Process a key event with `action`, sending it to the layout through
+    /// `emit`
+    pub fn event(
+        &mut self,
+        event: Event,
+        action: &Action<CustomEvent>,
+        mut emit: impl FnMut(Event),
+    ) {
+        match event {
+            Event::Press(i, j) => {
+                // Another key pressed while holding a key ends its hold,
+                // and is not shifted along with it
+                self.resolve(&mut emit);
+                self.unshift(&mut emit);
+                if self.enabled && self.is_shiftable(action) {
+                    self.pending = Some(Pending {
+                        key: (i, j),
+                        elapsed: 0,
+                    });
+                } else {
+                    emit(event);
+                }
+            }
+            Event::Release(i, j) => {
+                if self.pending.as_ref().is_some_and(|p| p.key == (i, j)) {
+                    self.resolve(&mut emit);
+                }
+                emit(event);
+                if self.shifted == Some((i, j)) {
+                    self.unshift(&mut emit);
+                }
+            }
+        }
+    }
+
+    /// Count the time elapsed, sending the pending key shifted once held
+    /// long enough
+    pub fn tick(&mut self, mut emit: impl FnMut(Event)) {
+        let Some(pending) = &mut self.pending else {
+            return;
+        };
+        pending.elapsed = pending.elapsed.saturating_add(1);
+        if pending.elapsed < self.config.timeout {
+            return;
+        }
+        let key = pending.key;
+        self.pending = None;
+        self.shifted = Some(key);
+        emit(Event::Press(AUTO_SHIFT_ROW, 0));
+        emit(Event::Press(key.0, key.1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::action::k;
+    use std::vec;
+
+    /// Configuration of the tests, Minus never shifted
+    const CONFIG: AutoShiftConfig = AutoShiftConfig {
+        timeout: 100,
+        excluded: &[KeyCode::Minus],
+    };
+    /// Press of the virtual key of Shift
+    const SHIFT: Event = Event::Press(AUTO_SHIFT_ROW, 0);
+    /// Release of the virtual key of Shift
+    const UNSHIFT: Event = Event::Release(AUTO_SHIFT_ROW, 0);
+
+    /// Process a key event with `action`, returning the events emitted
+    fn event(
+        auto_shift: &mut AutoShift,
+        event: Event,
+        action: &Action<CustomEvent>,
+    ) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        auto_shift.event(event, action, |e| events.push(e));
+        events
+    }
+
+    /// Count `ms` of time, returning the events emitted
+    fn tick(auto_shift: &mut AutoShift, ms: u16) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        for _ in 0..ms {
+            auto_shift.tick(|e| events.push(e));
+        }
+        events
+    }
+
+    /// Create an auto-shift engine, switched on
+    fn enabled() -> AutoShift {
+        let mut auto_shift = AutoShift::new(CONFIG);
+        auto_shift.toggle();
+        auto_shift
+    }
+
+    #[test]
+    fn threshold() {
+        let a = k(KeyCode::A);
+        let mut auto_shift = enabled();
+
+        // Tapped
+        assert!(event(&mut auto_shift, Event::Press(0, 0), &a).is_empty());
+        assert!(tick(&mut auto_shift, 99).is_empty());
+        assert_eq!(
+            event(&mut auto_shift, Event::Release(0, 0), &a),
+            [Event::Press(0, 0), Event::Release(0, 0)]
+        );
+
+        // Held, Shift released with the key
+        event(&mut auto_shift, Event::Press(0, 0), &a);
+        assert_eq!(tick(&mut auto_shift, 100), [SHIFT, Event::Press(0, 0)]);
+        assert_eq!(
+            event(&mut auto_shift, Event::Release(0, 0), &a),
+            [Event::Release(0, 0), UNSHIFT]
+        );
+    }
+
+    #[test]
+    fn opt_out() {
+        let mut auto_shift = enabled();
+
+        // Excluded key
+        let minus = k(KeyCode::Minus);
+        assert_eq!(
+            event(&mut auto_shift, Event::Press(0, 2), &minus),
+            [Event::Press(0, 2)]
+        );
+        assert!(tick(&mut auto_shift, 100).is_empty());
+
+        // Key with another action
+        let layer = Action::Layer(1);
+        assert_eq!(
+            event(&mut auto_shift, Event::Press(0, 3), &layer),
+            [Event::Press(0, 3)]
+        );
+        assert!(tick(&mut auto_shift, 100).is_empty());
+    }
+
+    #[test]
+    fn interrupting_key() {
+        let a = k(KeyCode::A);
+        let b = k(KeyCode::B);
+        let mut auto_shift = enabled();
+
+        // Pressed before the threshold: the first key is sent unshifted
+        event(&mut auto_shift, Event::Press(0, 0), &a);
+        tick(&mut auto_shift, 50);
+        assert_eq!(
+            event(&mut auto_shift, Event::Press(0, 1), &b),
+            [Event::Press(0, 0)]
+        );
+        assert_eq!(
+            event(&mut auto_shift, Event::Release(0, 0), &a),
+            [Event::Release(0, 0)]
+        );
+        assert_eq!(
+            event(&mut auto_shift, Event::Release(0, 1), &b),
+            [Event::Press(0, 1), Event::Release(0, 1)]
+        );
+
+        // Pressed once shifted: Shift is released before it
+        event(&mut auto_shift, Event::Press(0, 0), &a);
+        tick(&mut auto_shift, 100);
+        assert_eq!(event(&mut auto_shift, Event::Press(0, 1), &b), [UNSHIFT]);
+        assert_eq!(
+            event(&mut auto_shift, Event::Release(0, 0), &a),
+            [Event::Release(0, 0)]
+        );
+        assert_eq!(
+            event(&mut auto_shift, Event::Release(0, 1), &b),
+            [Event::Press(0, 1), Event::Release(0, 1)]
+        );
+    }
+
+    #[test]
+    fn disabled() {
+        let a = k(KeyCode::A);
+        let mut auto_shift = AutoShift::new(CONFIG);
+        assert_eq!(
+            event(&mut auto_shift, Event::Press(0, 0), &a),
+            [Event::Press(0, 0)]
+        );
+        assert!(tick(&mut auto_shift, 100).is_empty());
+        event(&mut auto_shift, Event::Release(0, 0), &a);
+
+        // Switched on, then off again
+        auto_shift.toggle();
+        assert!(event(&mut auto_shift, Event::Press(0, 0), &a).is_empty());
+        event(&mut auto_shift, Event::Release(0, 0), &a);
+        auto_shift.toggle();
+        assert_eq!(
+            event(&mut auto_shift, Event::Press(0, 0), &a),
+            [Event::Press(0, 0)]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use crate::hid::KbReport;
+use crate::layout::CustomEvent;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::CustomEvent as KbCustomEvent;
+
+/// Time without any key pressed after which Caps Word turns off, in ms
+const CAPS_WORD_TIMEOUT: u16 = 5000;
+
+/// How a key code is typed during a Caps Word
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Typed with Shift
+    Shifted,
+    /// Typed as is
+    Kept,
+    /// Ends the word
+    Break,
+}
+
+/// Caps Word: shift the letters typed until the end of the word
+///
+/// It only changes the keyboard reports and does not depend on the Caps
+/// Lock state of the host.
+pub struct CapsWord {
+    /// Key codes shifted in addition to the letters, `Minus` to type `_`
+    shifted: &'static [KeyCode],
+    /// Whether Caps Word is on
+    active: bool,
+    /// Time since the last key press, in ms
+    idle: u16,
+    /// Keyboard report of the previous tick, before Caps Word
+    previous: KbReport,
+    /// Key code pressed last, deciding whether Shift is added
+    last: Option<KeyCode>,
+}
+
+impl CapsWord {
+    /// Create a new Caps Word, off
+    pub fn new(shifted: &'static [KeyCode]) -> Self {
+        CapsWord {
+            shifted,
+            active: false,
+            idle: 0,
+            previous: KbReport::default(),
+            last: None,
+        }
+    }
+
+    /// Whether Caps Word is on
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// How `kc` is typed during a Caps Word
+    fn kind(&self, kc: KeyCode) -> Kind {
+        let code = kc as u8;
+        if (KeyCode::A as u8..=KeyCode::Z as u8).contains(&code) || self.shifted.contains(&kc) {
+            Kind::Shifted
+        } else if (KeyCode::Kb1 as u8..=KeyCode::Kb0 as u8).contains(&code)
+            || matches!(kc, KeyCode::BSpace | KeyCode::Delete)
+        {
+            Kind::Kept
+        } else {
+            Kind::Break
+        }
+    }
+
+    /// Process a custom event of the layout, toggling Caps Word
+    pub fn custom_event(&mut self, event: &KbCustomEvent<CustomEvent>) {
+        if let KbCustomEvent::Press(CustomEvent::CapsWord) = event {
+            self.active = !self.active;
+            self.idle = 0;
+            self.last = None;
+        }
+    }
+
+    /// Apply Caps Word to the keyboard report made of the `keycodes`
+    ///
+    /// Shift is added while the key pressed last is a shifted one, so that
+    /// a digit rolled over with a letter is not typed as a symbol, nor the
+    /// letter typed after a digit still held in lowercase. Among the keys
+    /// pressed at the same time, a kept one wins.
+    pub fn apply(&mut self, keycodes: impl Iterator<Item = KeyCode>, report: &mut KbReport) {
+        let previous = core::mem::replace(&mut self.previous, *report);
+        if !self.active {
+            return;
+        }
+        let mut last = None;
+        for kc in keycodes.filter(|&kc| kc != KeyCode::No && !kc.is_modifier()) {
+            if previous.is_pressed(kc) {
+                continue;
+            }
+            self.idle = 0;
+            match self.kind(kc) {
+                Kind::Break => {
+                    self.active = false;
+                    self.last = None;
+                    return;
+                }
+                Kind::Shifted if last.is_some() => {}
+                Kind::Shifted | Kind::Kept => last = Some(kc),
+            }
+        }
+        if last.is_some() {
+            self.last = last;
+        }
+        if let Some(kc) = self.last {
+            if report.is_pressed(kc) && self.kind(kc) == Kind::Shifted {
+                report.press(KeyCode::LShift);
+            }
+        }
+    }
+
+    /// Count the time elapsed, turning Caps Word off when idle
+    pub fn tick(&mut self) {
+        if !self.active {
+            return;
+        }
+        self.idle = self.idle.saturating_add(1);
+        if self.idle >= CAPS_WORD_TIMEOUT {
+            self.active = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Caps Word turned on
+    fn caps_word() -> CapsWord {
+        let mut caps_word = CapsWord::new(&[KeyCode::Minus]);
+        caps_word.custom_event(&KbCustomEvent::Press(&CustomEvent::CapsWord));
+        caps_word
+    }
+
+    /// Apply Caps Word to the report made of `keycodes`, returning whether
+    /// Shift is added
+    fn shifted(caps_word: &mut CapsWord, keycodes: &[KeyCode]) -> bool {
+        let mut report = KbReport::default();
+        for &kc in keycodes {
+            report.press(kc);
+        }
+        caps_word.apply(keycodes.iter().copied(), &mut report);
+        report.is_pressed(KeyCode::LShift)
+    }
+
+    #[test]
+    fn letters() {
+        let mut caps_word = caps_word();
+        assert!(shifted(&mut caps_word, &[KeyCode::A]));
+        assert!(!shifted(&mut caps_word, &[]));
+        assert!(shifted(&mut caps_word, &[KeyCode::Minus]));
+        assert!(caps_word.is_active());
+    }
+
+    #[test]
+    fn off() {
+        let mut caps_word = CapsWord::new(&[]);
+        assert!(!shifted(&mut caps_word, &[KeyCode::A]));
+    }
+
+    #[test]
+    fn rolls() {
+        // V2A, each key pressed before the previous one is released
+        let mut caps_word = caps_word();
+        assert!(shifted(&mut caps_word, &[KeyCode::V]));
+        assert!(!shifted(&mut caps_word, &[KeyCode::V, KeyCode::Kb2]));
+        assert!(!shifted(&mut caps_word, &[KeyCode::Kb2]));
+        assert!(shifted(&mut caps_word, &[KeyCode::Kb2, KeyCode::A]));
+        assert!(shifted(&mut caps_word, &[KeyCode::A]));
+    }
+
+    #[test]
+    fn pressed_together() {
+        let mut caps_word = caps_word();
+        assert!(!shifted(&mut caps_word, &[KeyCode::A, KeyCode::Kb2]));
+    }
+
+    #[test]
+    fn word_break() {
+        let mut caps_word = caps_word();
+        shifted(&mut caps_word, &[KeyCode::A]);
+        assert!(!shifted(&mut caps_word, &[KeyCode::A, KeyCode::Space]));
+        assert!(!caps_word.is_active());
+        assert!(!shifted(&mut caps_word, &[KeyCode::B]));
+    }
+
+    #[test]
+    fn timeout() {
+        let mut caps_word = caps_word();
+        for _ in 0..CAPS_WORD_TIMEOUT {
+            caps_word.tick();
+        }
+        assert!(!caps_word.is_active());
+    }
+}
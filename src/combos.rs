@@ -0,0 +1,345 @@
+use crate::layout::{CustomEvent, COMBO_ROW, RIGHT_FIRST_COL};
+use defmt::warn;
+use heapless::Vec;
+use keyberon::action::Action;
+use keyberon::layout::Event;
+
+/// Maximum number of keys in a combo
+const MAX_COMBO_KEYS: usize = 4;
+/// Maximum number of combos held at the same time
+const MAX_ACTIVE_COMBOS: usize = 4;
+/// Time added to the timeout of the combos with keys on both halves, to
+/// absorb the latency of the split link, in ms
+const SPLIT_LATENCY: u16 = 10;
+
+/// Coordinates of a key, as (row, column)
+type Key = (u8, u8);
+
+/// Keys pressed together to trigger an action
+#[derive(Debug, Clone, Copy)]
+pub struct Combo {
+    /// Keys of the combo
+    pub keys: &'static [Key],
+    /// Action triggered by the combo
+    pub action: Action<CustomEvent>,
+    /// Maximum time between the first and the last key presses, in ms
+    pub timeout: u16,
+    /// Layers on which the combo is active, all of them if empty
+    pub layers: &'static [usize],
+}
+
+impl Combo {
+    /// Whether the combo is active on `layer`
+    fn is_active_on(&self, layer: usize) -> bool {
+        self.layers.is_empty() || self.layers.contains(&layer)
+    }
+
+    /// Timeout of the combo, longer when its keys are on both halves
+    fn timeout(&self) -> u16 {
+        let on_left = self.keys.iter().any(|&(_, j)| j < RIGHT_FIRST_COL);
+        let on_right = self.keys.iter().any(|&(_, j)| j >= RIGHT_FIRST_COL);
+        if on_left && on_right {
+            self.timeout + SPLIT_LATENCY
+        } else {
+            self.timeout
+        }
+    }
+
+    /// Whether all the `keys` are part of the combo
+    fn contains(&self, keys: &[Key]) -> bool {
+        keys.iter().all(|k| self.keys.contains(k))
+    }
+}
+
+/// Combo whose virtual key is pressed
+struct ActiveCombo {
+    /// Index of the combo
+    index: usize,
+    /// Keys of the combo still held
+    held: Vec<Key, MAX_COMBO_KEYS>,
+}
+
+/// Combos engine, between the key events and the layout
+///
+/// The presses of the keys that may start a combo are held back until the
+/// combo is completed, or can no longer be.
+pub struct Combos {
+    /// Combos of the keymap
+    combos: &'static [Combo],
+    /// Keys pressed that may be part of a combo, not sent to the layout yet
+    pending: Vec<Key, MAX_COMBO_KEYS>,
+    /// Time since the first pending key was pressed, in ms
+    elapsed: u16,
+    /// Layer when the first pending key was pressed
+    layer: usize,
+    /// Combos whose virtual key is pressed
+    active: Vec<ActiveCombo, MAX_ACTIVE_COMBOS>,
+}
+
+impl Combos {
+    /// Create a new combos engine
+    pub fn new(combos: &'static [Combo]) -> Self {
+        Combos {
+            combos,
+            pending: Vec::new(),
+            elapsed: 0,
+            layer: 0,
+            active: Vec::new(),
+        }
+    }
+
+    /// Whether a combo made of `keys` and at least `more` other keys may
+    /// still be completed
+    fn may_complete(&self, keys: &[Key], more: usize) -> bool {
+        self.combos.iter().any(|c| {
+            c.is_active_on(self.layer)
+                && c.keys.len() >= keys.len() + more
+                && c.contains(keys)
+                && self.elapsed < c.timeout()
+        })
+    }
+
+    /// Index of the combo made of exactly the pending keys, if any
+    fn completed(&self) -> Option<usize> {
+        self.combos.iter().position(|c| {
+            c.is_active_on(self.layer)
+                && c.keys.len() == self.pending.len()
+                && c.contains(&self.pending)
+        })
+    }
+
+    /// Press the virtual key of the combo made of the pending keys
+    fn fire(&mut self, index: usize, emit: &mut impl FnMut(Event)) {
+        let held = core::mem::take(&mut self.pending);
+        match self.active.push(ActiveCombo { index, held }) {
+            Ok(()) => emit(Event::Press(COMBO_ROW, index as u8)),
+            Err(ActiveCombo { held, .. }) => {
+                warn!("Too many combos held, sending the keys instead");
+                for &(i, j) in held.iter() {
+                    emit(Event::Press(i, j));
+                }
+            }
+        }
+    }
+
+    /// Fire the combo made of the pending keys, unless a bigger combo may
+    /// still be completed
+    fn fire_if_completed(&mut self, emit: &mut impl FnMut(Event)) {
+        if let Some(index) = self.completed() {
+            if !self.may_complete(&self.pending, 1) {
+                self.fire(index, emit);
+            }
+        }
+    }
+
+    /// Fire the combo made of the pending keys, or send them to the layout
+    fn resolve(&mut self, emit: &mut impl FnMut(Event)) {
+        match self.completed() {
+            Some(index) => self.fire(index, emit),
+            None => {
+                for &(i, j) in self.pending.iter() {
+                    emit(Event::Press(i, j));
+                }
+                self.pending.clear();
+            }
+        }
+    }
+
+    /// Process a key event, pressed while `layer` is the current layer
+    ///
+    /// The events to send to the layout are given to `emit`.
+    pub fn event(&mut self, event: Event, layer: usize, mut emit: impl FnMut(Event)) {
+        match event {
+            Event::Press(i, j) => {
+                if !self.pending.is_empty() {
+                    let mut keys = self.pending.clone();
+                    if keys.push((i, j)).is_ok() && self.may_complete(&keys, 0) {
+                        self.pending = keys;
+                        self.fire_if_completed(&mut emit);
+                        return;
+                    }
+                    self.resolve(&mut emit);
+                }
+                self.layer = layer;
+                self.elapsed = 0;
+                if self.may_complete(&[(i, j)], 0) {
+                    // Cannot fail, no key is pending
+                    let _ = self.pending.push((i, j));
+                    self.fire_if_completed(&mut emit);
+                } else {
+                    emit(event);
+                }
+            }
+            Event::Release(i, j) => {
+                // Keep the order of the events
+                if !self.pending.is_empty() {
+                    self.resolve(&mut emit);
+                }
+                match self.active.iter().position(|a| a.held.contains(&(i, j))) {
+                    Some(pos) => {
+                        let active = &mut self.active[pos];
+                        // The virtual key is released with the first key
+                        if active.held.len() == self.combos[active.index].keys.len() {
+                            emit(Event::Release(COMBO_ROW, active.index as u8));
+                        }
+                        active.held.retain(|k| *k != (i, j));
+                        if active.held.is_empty() {
+                            self.active.swap_remove(pos);
+                        }
+                    }
+                    None => emit(event),
+                }
+            }
+        }
+    }
+
+    /// Count the time elapsed, resolving the pending keys once no combo can
+    /// be completed anymore
+    pub fn tick(&mut self, mut emit: impl FnMut(Event)) {
+        if self.pending.is_empty() {
+            return;
+        }
+        self.elapsed = self.elapsed.saturating_add(1);
+        if !self.may_complete(&self.pending, 1) {
+            self.resolve(&mut emit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+
+    /// Combos of the tests: two keys, three keys including the same two, and
+    /// three keys on both halves only active on layer 1
+    static COMBOS: [Combo; 3] = [
+        Combo {
+            keys: &[(0, 0), (0, 1)],
+            action: Action::NoOp,
+            timeout: 50,
+            layers: &[],
+        },
+        Combo {
+            keys: &[(0, 0), (0, 1), (0, 2)],
+            action: Action::NoOp,
+            timeout: 50,
+            layers: &[],
+        },
+        Combo {
+            keys: &[(1, 3), (1, 4), (1, 5)],
+            action: Action::NoOp,
+            timeout: 50,
+            layers: &[1],
+        },
+    ];
+
+    /// Process `event` on layer 0, returning the events emitted
+    fn event(combos: &mut Combos, event: Event) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        combos.event(event, 0, |e| events.push(e));
+        events
+    }
+
+    /// Count `ms` of time, returning the events emitted
+    fn tick(combos: &mut Combos, ms: u16) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        for _ in 0..ms {
+            combos.tick(|e| events.push(e));
+        }
+        events
+    }
+
+    #[test]
+    fn plain_key() {
+        let mut combos = Combos::new(&COMBOS);
+        assert_eq!(event(&mut combos, Event::Press(2, 0)), [Event::Press(2, 0)]);
+        assert_eq!(
+            event(&mut combos, Event::Release(2, 0)),
+            [Event::Release(2, 0)]
+        );
+    }
+
+    #[test]
+    fn combo_on_other_layer() {
+        let mut combos = Combos::new(&COMBOS);
+        assert_eq!(event(&mut combos, Event::Press(1, 3)), [Event::Press(1, 3)]);
+    }
+
+    #[test]
+    fn partial_combo_timeout() {
+        let mut combos = Combos::new(&COMBOS[2..]);
+        let mut events = vec![];
+        combos.event(Event::Press(1, 3), 1, |e| events.push(e));
+        combos.event(Event::Press(1, 4), 1, |e| events.push(e));
+        assert!(events.is_empty());
+        // Across both halves, the timeout is extended by the split latency
+        assert!(tick(&mut combos, 50).is_empty());
+        assert_eq!(
+            tick(&mut combos, SPLIT_LATENCY),
+            [Event::Press(1, 3), Event::Press(1, 4)]
+        );
+        assert_eq!(
+            event(&mut combos, Event::Release(1, 3)),
+            [Event::Release(1, 3)]
+        );
+    }
+
+    #[test]
+    fn partial_combo_interrupted() {
+        let mut combos = Combos::new(&COMBOS);
+        assert!(event(&mut combos, Event::Press(0, 0)).is_empty());
+        assert_eq!(
+            event(&mut combos, Event::Press(2, 0)),
+            [Event::Press(0, 0), Event::Press(2, 0)]
+        );
+    }
+
+    #[test]
+    fn longest_combo() {
+        let mut combos = Combos::new(&COMBOS);
+        assert!(event(&mut combos, Event::Press(0, 0)).is_empty());
+        // The smaller combo waits for the bigger one to be completed
+        assert!(event(&mut combos, Event::Press(0, 1)).is_empty());
+        assert_eq!(
+            event(&mut combos, Event::Press(0, 2)),
+            [Event::Press(COMBO_ROW, 1)]
+        );
+    }
+
+    #[test]
+    fn shorter_combo_on_timeout() {
+        let mut combos = Combos::new(&COMBOS);
+        event(&mut combos, Event::Press(0, 1));
+        event(&mut combos, Event::Press(0, 0));
+        assert!(tick(&mut combos, 49).is_empty());
+        assert_eq!(tick(&mut combos, 1), [Event::Press(COMBO_ROW, 0)]);
+    }
+
+    #[test]
+    fn release_order() {
+        let mut combos = Combos::new(&COMBOS);
+        event(&mut combos, Event::Press(0, 2));
+        event(&mut combos, Event::Press(0, 0));
+        event(&mut combos, Event::Press(0, 1));
+        // The virtual key is released with the first key, whatever it is
+        assert_eq!(
+            event(&mut combos, Event::Release(0, 1)),
+            [Event::Release(COMBO_ROW, 1)]
+        );
+        assert!(event(&mut combos, Event::Release(0, 2)).is_empty());
+        assert!(event(&mut combos, Event::Release(0, 0)).is_empty());
+        // All its keys released, the combo is done
+        assert!(combos.active.is_empty());
+    }
+
+    #[test]
+    fn release_before_completion() {
+        let mut combos = Combos::new(&COMBOS);
+        event(&mut combos, Event::Press(0, 0));
+        assert_eq!(
+            event(&mut combos, Event::Release(0, 0)),
+            [Event::Press(0, 0), Event::Release(0, 0)]
+        );
+    }
+}
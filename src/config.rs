@@ -60,6 +60,8 @@ pub fn usb_config() -> embassy_usb::Config<'static> {
     config.serial_number = Some(env!("CARGO_PKG_VERSION"));
     config.max_power = 100;
     config.max_packet_size_0 = 64;
+    // Let the Wake Up key wake the host from suspend
+    config.supports_remote_wakeup = true;
 
     // Required for windows compatibility.
     // https://developer.nordicsemi.com/nRF_Connect_SDK/doc/1.9.1/kconfig/CONFIG_CDC_ACM_IAD.html#help
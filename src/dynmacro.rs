@@ -0,0 +1,225 @@
+use crate::hid::KbReport;
+use crate::layout::CustomEvent;
+use defmt::{info, warn};
+use heapless::Vec;
+use keyberon::layout::CustomEvent as KbCustomEvent;
+
+/// Number of macro slots
+const NB_SLOTS: usize = 2;
+/// Maximum number of keyboard reports in a macro
+const MAX_MACRO_REPORTS: usize = 64;
+/// Time each report of a macro is played, in ms, for the reports not to be
+/// sent faster than the layout would
+const PLAYBACK_INTERVAL: u16 = 10;
+
+/// Macro being played
+struct Playback {
+    /// Slot of the macro
+    slot: usize,
+    /// Index of the report played
+    index: usize,
+    /// Time since the report is played, in ms
+    elapsed: u16,
+}
+
+/// Macros recorded at runtime from the keyboard reports of the layout
+///
+/// The reports are recorded when they change, modifiers included, and
+/// played back instead of the reports of the layout.
+pub struct DynMacros {
+    /// Reports of the macro of each slot
+    slots: [Vec<KbReport, MAX_MACRO_REPORTS>; NB_SLOTS],
+    /// Slot being recorded
+    recording: Option<usize>,
+    /// Macro being played
+    playing: Option<Playback>,
+    /// Last report of the layout
+    last: KbReport,
+}
+
+impl DynMacros {
+    /// Create empty macro slots
+    pub fn new() -> Self {
+        DynMacros {
+            slots: [Vec::new(), Vec::new()],
+            recording: None,
+            playing: None,
+            last: KbReport::default(),
+        }
+    }
+
+    /// Stop the recording, if any
+    fn stop(&mut self) {
+        if let Some(slot) = self.recording.take() {
+            info!(
+                "Macro {} recorded: {} reports",
+                slot,
+                self.slots[slot].len()
+            );
+        }
+    }
+
+    /// Process a custom event of the layout, recording, stopping or playing
+    /// the macros
+    pub fn custom_event(&mut self, event: &KbCustomEvent<CustomEvent>) {
+        match *event {
+            KbCustomEvent::Press(&CustomEvent::DynMacroRecord(slot)) => {
+                self.stop();
+                if slot >= NB_SLOTS {
+                    warn!("No macro slot {}", slot);
+                    return;
+                }
+                info!("Recording macro {}", slot);
+                self.playing = None;
+                self.slots[slot].clear();
+                self.recording = Some(slot);
+            }
+            KbCustomEvent::Press(CustomEvent::DynMacroStop) => self.stop(),
+            KbCustomEvent::Press(&CustomEvent::DynMacroPlay(slot)) => {
+                self.stop();
+                match self.slots.get(slot) {
+                    Some(reports) if !reports.is_empty() => {
+                        self.playing = Some(Playback {
+                            slot,
+                            index: 0,
+                            elapsed: 0,
+                        });
+                    }
+                    _ => warn!("No macro in slot {}", slot),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Record the keyboard report of the layout if it changed
+    pub fn record(&mut self, report: &KbReport) {
+        if *report == self.last {
+            return;
+        }
+        self.last = *report;
+        if let Some(slot) = self.recording {
+            if self.slots[slot].push(*report).is_err() {
+                warn!("Macro {} full", slot);
+                self.stop();
+            }
+        }
+    }
+
+    /// Report of the macro being played, if any, to send instead of the
+    /// report of the layout
+    pub fn play(&mut self) -> Option<KbReport> {
+        let playback = self.playing.as_mut()?;
+        let reports = &self.slots[playback.slot];
+        let report = reports[playback.index];
+        playback.elapsed += 1;
+        if playback.elapsed >= PLAYBACK_INTERVAL {
+            playback.elapsed = 0;
+            playback.index += 1;
+            if playback.index == reports.len() {
+                self.playing = None;
+            }
+        }
+        Some(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::key_code::KeyCode;
+
+    /// Events recording each slot, and one past the last slot
+    static RECORD: [CustomEvent; NB_SLOTS + 1] = [
+        CustomEvent::DynMacroRecord(0),
+        CustomEvent::DynMacroRecord(1),
+        CustomEvent::DynMacroRecord(2),
+    ];
+    /// Events playing each slot, and one past the last slot
+    static PLAY: [CustomEvent; NB_SLOTS + 1] = [
+        CustomEvent::DynMacroPlay(0),
+        CustomEvent::DynMacroPlay(1),
+        CustomEvent::DynMacroPlay(2),
+    ];
+
+    /// Report made of `keycodes`
+    fn report(keycodes: &[KeyCode]) -> KbReport {
+        let mut report = KbReport::default();
+        for &kc in keycodes {
+            report.press(kc);
+        }
+        report
+    }
+
+    /// Record the reports made of each of `reports` into `slot`
+    fn record(dyn_macros: &mut DynMacros, slot: usize, reports: &[&[KeyCode]]) {
+        dyn_macros.custom_event(&KbCustomEvent::Press(&RECORD[slot]));
+        for keycodes in reports {
+            dyn_macros.record(&report(keycodes));
+        }
+        dyn_macros.custom_event(&KbCustomEvent::Press(&CustomEvent::DynMacroStop));
+    }
+
+    /// Play the macro of `slot`, returning the reports sent until it ends
+    fn play(dyn_macros: &mut DynMacros, slot: usize) -> std::vec::Vec<KbReport> {
+        dyn_macros.custom_event(&KbCustomEvent::Press(&PLAY[slot]));
+        core::iter::from_fn(|| dyn_macros.play()).collect()
+    }
+
+    #[test]
+    fn record_and_play() {
+        let mut dyn_macros = DynMacros::new();
+        record(
+            &mut dyn_macros,
+            0,
+            &[&[KeyCode::A], &[KeyCode::A], &[KeyCode::LShift], &[]],
+        );
+        let reports = play(&mut dyn_macros, 0);
+        // Recorded only when changed, played again
+        assert_eq!(reports.len(), 3 * PLAYBACK_INTERVAL as usize);
+        assert_eq!(play(&mut dyn_macros, 0), reports);
+    }
+
+    #[test]
+    fn stopped() {
+        let mut dyn_macros = DynMacros::new();
+        record(&mut dyn_macros, 1, &[&[KeyCode::A], &[]]);
+        dyn_macros.record(&report(&[KeyCode::B]));
+        assert_eq!(
+            play(&mut dyn_macros, 1).len(),
+            2 * PLAYBACK_INTERVAL as usize
+        );
+        // The other slot is left empty
+        assert!(play(&mut dyn_macros, 0).is_empty());
+        assert!(play(&mut dyn_macros, NB_SLOTS).is_empty());
+    }
+
+    #[test]
+    fn pacing() {
+        let mut dyn_macros = DynMacros::new();
+        record(&mut dyn_macros, 0, &[&[KeyCode::A], &[KeyCode::B], &[]]);
+        let reports = play(&mut dyn_macros, 0);
+        let interval = PLAYBACK_INTERVAL as usize;
+        for (i, keycodes) in [&[KeyCode::A][..], &[KeyCode::B], &[]].iter().enumerate() {
+            let played = &reports[i * interval..(i + 1) * interval];
+            assert!(played.iter().all(|r| *r == report(keycodes)));
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        let mut dyn_macros = DynMacros::new();
+        dyn_macros.custom_event(&KbCustomEvent::Press(&RECORD[0]));
+        for i in 0..MAX_MACRO_REPORTS + 2 {
+            let keycodes: &[KeyCode] = if i % 2 == 0 { &[KeyCode::A] } else { &[] };
+            dyn_macros.record(&report(keycodes));
+        }
+        // The recording stopped once full
+        assert_eq!(dyn_macros.recording, None);
+        let reports = play(&mut dyn_macros, 0);
+        assert_eq!(
+            reports.len(),
+            MAX_MACRO_REPORTS * PLAYBACK_INTERVAL as usize
+        );
+    }
+}
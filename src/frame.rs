@@ -0,0 +1,279 @@
+use heapless::Vec;
+
+/// Start of frame marker
+pub const SOF: u8 = 0xA5;
+/// Maximum size of the payload of a frame
+pub const MAX_PAYLOAD_SIZE: usize = 32;
+/// Size of the frame header: start marker, payload length and sequence number
+const HEADER_SIZE: usize = 3;
+/// Size of the CRC at the end of a frame
+const CRC_SIZE: usize = 2;
+/// Maximum size of an encoded frame
+pub const MAX_FRAME_SIZE: usize = HEADER_SIZE + MAX_PAYLOAD_SIZE + CRC_SIZE;
+
+/// Compute the CRC-16/CCITT-FALSE of `data`
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Errors detected while decoding the byte stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FrameError {
+    /// The length field is larger than `MAX_PAYLOAD_SIZE`
+    Length,
+    /// The CRC does not match the content of the frame
+    Crc,
+}
+
+/// A frame received from the other half of the keyboard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Sequence number of the frame
+    pub seq: u8,
+    /// Number of frames lost between the previous frame and this one
+    pub lost: u8,
+    /// Payload of the frame
+    pub payload: Vec<u8, MAX_PAYLOAD_SIZE>,
+}
+
+/// Frame encoder, numbering the frames it encodes
+#[derive(Debug, Default)]
+pub struct FrameEncoder {
+    /// Sequence number of the next frame
+    seq: u8,
+}
+
+impl FrameEncoder {
+    /// Create a new frame encoder
+    pub fn new() -> Self {
+        FrameEncoder { seq: 0 }
+    }
+
+    /// Encode `payload` into `buf` and return the encoded frame
+    ///
+    /// A frame is `[SOF, len, seq, payload.., crc_hi, crc_lo]` where the CRC
+    /// covers the length, the sequence number and the payload.
+    pub fn encode<'a>(&mut self, payload: &[u8], buf: &'a mut [u8; MAX_FRAME_SIZE]) -> &'a [u8] {
+        let len = payload.len().min(MAX_PAYLOAD_SIZE);
+        buf[0] = SOF;
+        buf[1] = len as u8;
+        buf[2] = self.seq;
+        buf[HEADER_SIZE..HEADER_SIZE + len].copy_from_slice(&payload[..len]);
+        let crc = crc16(&buf[1..HEADER_SIZE + len]);
+        buf[HEADER_SIZE + len..HEADER_SIZE + len + CRC_SIZE].copy_from_slice(&crc.to_be_bytes());
+        self.seq = self.seq.wrapping_add(1);
+        &buf[..HEADER_SIZE + len + CRC_SIZE]
+    }
+}
+
+/// Frame decoder, able to resynchronise on the start of frame marker
+/// after a corrupted or truncated frame
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    /// Bytes of the frame being decoded, starting with `SOF`
+    buf: Vec<u8, MAX_FRAME_SIZE>,
+    /// Sequence number expected for the next frame, if any frame was received
+    expected_seq: Option<u8>,
+}
+
+impl FrameDecoder {
+    /// Create a new frame decoder
+    pub fn new() -> Self {
+        FrameDecoder {
+            buf: Vec::new(),
+            expected_seq: None,
+        }
+    }
+
+    /// Drop the frame being decoded, after bytes were lost on the serial line
+    ///
+    /// The expected sequence number is kept so that the frames lost are
+    /// still reported by the next decoded frame.
+    pub fn reset(&mut self) {
+        self.buf.clear();
+    }
+
+    /// Push a byte received from the serial line
+    ///
+    /// `poll()` must be called until it returns `None` after each byte.
+    pub fn push(&mut self, byte: u8) {
+        if self.buf.is_empty() && byte != SOF {
+            // Not synchronised on a frame, skip the byte
+            return;
+        }
+        if self.buf.is_full() {
+            self.resync();
+        }
+        // Cannot fail since the buffer was just made room for
+        let _ = self.buf.push(byte);
+    }
+
+    /// Get the next decoded frame or the next decoding error, if any
+    pub fn poll(&mut self) -> Option<Result<Frame, FrameError>> {
+        if self.buf.len() < 2 {
+            return None;
+        }
+        let len = self.buf[1] as usize;
+        if len > MAX_PAYLOAD_SIZE {
+            self.resync();
+            return Some(Err(FrameError::Length));
+        }
+        let frame_len = HEADER_SIZE + len + CRC_SIZE;
+        if self.buf.len() < frame_len {
+            return None;
+        }
+        let crc = u16::from_be_bytes([self.buf[frame_len - 2], self.buf[frame_len - 1]]);
+        if crc != crc16(&self.buf[1..HEADER_SIZE + len]) {
+            self.resync();
+            return Some(Err(FrameError::Crc));
+        }
+        let seq = self.buf[2];
+        let lost = match self.expected_seq {
+            Some(expected) => seq.wrapping_sub(expected),
+            None => 0,
+        };
+        self.expected_seq = Some(seq.wrapping_add(1));
+        let mut payload = Vec::new();
+        // Cannot fail since `len <= MAX_PAYLOAD_SIZE`
+        let _ = payload.extend_from_slice(&self.buf[HEADER_SIZE..HEADER_SIZE + len]);
+        self.consume(frame_len);
+        Some(Ok(Frame { seq, lost, payload }))
+    }
+
+    /// Drop the first `n` bytes of the buffer, and any following byte that
+    /// is not a start of frame marker
+    fn consume(&mut self, n: usize) {
+        let start = self.buf[n..]
+            .iter()
+            .position(|&b| b == SOF)
+            .map(|p| p + n)
+            .unwrap_or(self.buf.len());
+        let remaining = self.buf.len() - start;
+        self.buf.copy_within(start.., 0);
+        self.buf.truncate(remaining);
+    }
+
+    /// Drop the current start of frame marker and look for the next one
+    /// in the bytes already received
+    fn resync(&mut self) {
+        self.consume(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Push the bytes of `data` into `decoder`, collecting the results
+    fn decode(decoder: &mut FrameDecoder, data: &[u8]) -> Vec<Result<Frame, FrameError>, 8> {
+        let mut results = Vec::new();
+        for &byte in data {
+            decoder.push(byte);
+            while let Some(result) = decoder.poll() {
+                results.push(result).unwrap();
+            }
+        }
+        results
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut encoder = FrameEncoder::new();
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let results = decode(&mut decoder, encoder.encode(&[1, 2, 3], &mut buf));
+        assert_eq!(results.len(), 1);
+        let frame = results[0].as_ref().unwrap();
+        assert_eq!((frame.seq, frame.lost), (0, 0));
+        assert_eq!(frame.payload, [1, 2, 3]);
+    }
+
+    #[test]
+    fn payload_with_sof() {
+        let mut encoder = FrameEncoder::new();
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let results = decode(&mut decoder, encoder.encode(&[SOF, 0, SOF], &mut buf));
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].as_ref().unwrap().payload, [SOF, 0, SOF]);
+    }
+
+    #[test]
+    fn bit_flip() {
+        let mut encoder = FrameEncoder::new();
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; MAX_FRAME_SIZE];
+        assert_eq!(
+            decode(&mut decoder, encoder.encode(&[0], &mut buf)).len(),
+            1
+        );
+        let mut frame: Vec<u8, MAX_FRAME_SIZE> =
+            Vec::from_slice(encoder.encode(&[1, 2, 3], &mut buf)).unwrap();
+        frame[4] ^= 0x10;
+        assert_eq!(
+            decode(&mut decoder, &frame).as_slice(),
+            [Err(FrameError::Crc)]
+        );
+        // The next frame is decoded, the corrupted one being reported as lost
+        let results = decode(&mut decoder, encoder.encode(&[4], &mut buf));
+        assert_eq!(results.len(), 1);
+        let frame = results[0].as_ref().unwrap();
+        assert_eq!(
+            (frame.seq, frame.lost, frame.payload.as_slice()),
+            (2, 1, [4].as_slice())
+        );
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut encoder = FrameEncoder::new();
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; MAX_FRAME_SIZE];
+        let first = encoder.encode(&[1, 2, 3, 4], &mut buf);
+        let truncated: Vec<u8, MAX_FRAME_SIZE> = Vec::from_slice(&first[..4]).unwrap();
+        let mut data: Vec<u8, 64> = Vec::from_slice(&truncated).unwrap();
+        data.extend_from_slice(encoder.encode(&[5, 6], &mut buf))
+            .unwrap();
+        data.extend_from_slice(encoder.encode(&[7], &mut buf))
+            .unwrap();
+        let results = decode(&mut decoder, &data);
+        // The truncated frame swallows bytes of the next one and fails its
+        // CRC, the decoder then resyncs on the following start of frame
+        let frames: Vec<&Frame, 8> = results.iter().filter_map(|r| r.as_ref().ok()).collect();
+        assert!(results.iter().any(|r| r.is_err()));
+        let last = frames.last().unwrap();
+        assert_eq!((last.seq, last.payload.as_slice()), (2, [7].as_slice()));
+    }
+
+    #[test]
+    fn lost_frames() {
+        let mut encoder = FrameEncoder::new();
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0; MAX_FRAME_SIZE];
+        decode(&mut decoder, encoder.encode(&[1], &mut buf));
+        encoder.encode(&[2], &mut buf);
+        encoder.encode(&[3], &mut buf);
+        let results = decode(&mut decoder, encoder.encode(&[4], &mut buf));
+        assert_eq!(results[0].as_ref().unwrap().lost, 2);
+    }
+
+    #[test]
+    fn length_too_large() {
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(
+            decode(&mut decoder, &[SOF, MAX_PAYLOAD_SIZE as u8 + 1]).as_slice(),
+            [Err(FrameError::Length)]
+        );
+    }
+}
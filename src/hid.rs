@@ -1,26 +1,396 @@
 use crate::layout::LAYOUT_CHANNEL;
-use crate::side::is_host;
+use crate::side::update_host_state;
+use core::sync::atomic::{AtomicBool, Ordering};
 use defmt::*;
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
+#[cfg(not(test))]
 use embassy_stm32::peripherals::USB_OTG_FS;
+#[cfg(not(test))]
 use embassy_stm32::usb::Driver;
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_usb::class::hid::{ReportId, RequestHandler};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+use embassy_time::Instant;
+use embassy_usb::class::hid::{HidProtocolMode, ReportId, RequestHandler};
 use embassy_usb::control::OutResponse;
-use usbd_hid::descriptor::{KeyboardReport, MouseReport};
+use keyberon::key_code::KeyCode;
+use usbd_hid::descriptor::MouseReport;
 
 /// Only one report is sent at a time
 const NB_REPORTS: usize = 64;
 /// Channel to send HID keyboard reports to the HID writer
-pub static HID_KB_CHANNEL: Channel<CriticalSectionRawMutex, KeyboardReport, NB_REPORTS> =
-    Channel::new();
+pub static HID_KB_CHANNEL: Channel<CriticalSectionRawMutex, KbReport, NB_REPORTS> = Channel::new();
 /// Channel to send HID mouse reports to the HID writer
 pub static HID_MOUSE_CHANNEL: Channel<CriticalSectionRawMutex, MouseReport, NB_REPORTS> =
     Channel::new();
+/// Channel to send HID system control reports to the HID writer: the usage
+/// pressed, if any
+pub static HID_SYSTEM_CHANNEL: Channel<CriticalSectionRawMutex, Option<SystemControl>, NB_REPORTS> =
+    Channel::new();
+/// Signaled to wake up the host, only heeded while the USB bus is suspended
+pub static REMOTE_WAKEUP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Channel to send HID consumer control reports to the HID writer
+pub static HID_CONSUMER_CHANNEL: Channel<CriticalSectionRawMutex, ConsumerReport, NB_REPORTS> =
+    Channel::new();
 
 /// HID writer type
+#[cfg(not(test))]
 pub type HidWriter<'a, 'b> = embassy_usb::class::hid::HidWriter<'a, Driver<'b, USB_OTG_FS>, 64>;
 
+/// Number of keycodes covered by the NKRO bitmap
+const NKRO_KEYCODES: usize = 0xA8;
+/// Size of the NKRO bitmap
+const NKRO_BITMAP_SIZE: usize = NKRO_KEYCODES / 8;
+/// Size of the boot keyboard report: modifiers, reserved byte, 6 keycodes
+const BOOT_REPORT_SIZE: usize = 8;
+/// Size of the keyboard report in report protocol: the boot report followed
+/// by the NKRO bitmap
+pub const KB_REPORT_SIZE: usize = BOOT_REPORT_SIZE + NKRO_BITMAP_SIZE;
+
+/// Report descriptor of the keyboard
+///
+/// The report starts like the boot keyboard report, with an array of 6
+/// keycodes, followed by a bitmap of the keys pressed.  Only one of them is
+/// filled, depending on whether NKRO is enabled.
+#[rustfmt::skip]
+pub const KB_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    // Modifiers
+    0x05, 0x07, //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0, //   Usage Minimum (Left Control)
+    0x29, 0xE7, //   Usage Maximum (Right GUI)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    // Reserved byte
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x01, //   Input (Constant)
+    // Leds
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (Num Lock)
+    0x29, 0x05, //   Usage Maximum (Kana)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x05, //   Report Count (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0x75, 0x03, //   Report Size (3)
+    0x95, 0x01, //   Report Count (1)
+    0x91, 0x01, //   Output (Constant)
+    // Array of 6 keycodes
+    0x05, 0x07, //   Usage Page (Keyboard/Keypad)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x2A, 0xFF, 0x00, // Usage Maximum (255)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, // Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x06, //   Report Count (6)
+    0x81, 0x00, //   Input (Data, Array)
+    // Bitmap of keycodes
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, (NKRO_KEYCODES - 1) as u8, // Usage Maximum
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, NKRO_KEYCODES as u8, // Report Count
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0xC0,       // End Collection
+];
+
+/// Keys pressed on the keyboard, turned into a report in the format
+/// expected by the host
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KbReport {
+    /// Modifiers pressed
+    modifier: u8,
+    /// Bitmap of the other keycodes pressed
+    keys: [u8; 32],
+}
+
+impl KbReport {
+    /// Add a pressed keycode
+    pub fn press(&mut self, kc: KeyCode) {
+        if kc == KeyCode::No {
+        } else if kc.is_modifier() {
+            self.modifier |= kc.as_modifier_bit();
+        } else {
+            let kc = kc as u8;
+            self.keys[kc as usize / 8] |= 1 << (kc % 8);
+        }
+    }
+
+    /// Iterate over the keycodes pressed, except the modifiers
+    fn keycodes(&self) -> impl Iterator<Item = u8> + '_ {
+        (1..=u8::MAX).filter(|&kc| self.keys[kc as usize / 8] & 1 << (kc % 8) != 0)
+    }
+
+    /// Remove a pressed keycode
+    pub fn release(&mut self, kc: KeyCode) {
+        if kc == KeyCode::No {
+        } else if kc.is_modifier() {
+            self.modifier &= !kc.as_modifier_bit();
+        } else {
+            let kc = kc as u8;
+            self.keys[kc as usize / 8] &= !(1 << (kc % 8));
+        }
+    }
+
+    /// Add the keycodes pressed in another report
+    pub fn merge(&mut self, other: &KbReport) {
+        self.modifier |= other.modifier;
+        for (keys, other_keys) in self.keys.iter_mut().zip(other.keys) {
+            *keys |= other_keys;
+        }
+    }
+
+    /// Report made of a keycode pressed since the `previous` report, if any,
+    /// with the modifiers pressed
+    pub fn new_key(&self, previous: &KbReport) -> Option<KbReport> {
+        let kc = self
+            .keycodes()
+            .find(|&kc| previous.keys[kc as usize / 8] & 1 << (kc % 8) == 0)?;
+        let mut report = KbReport {
+            modifier: self.modifier,
+            ..KbReport::default()
+        };
+        report.keys[kc as usize / 8] |= 1 << (kc % 8);
+        Some(report)
+    }
+
+    /// Report made of the modifiers pressed only
+    pub fn modifiers(&self) -> KbReport {
+        KbReport {
+            modifier: self.modifier,
+            ..KbReport::default()
+        }
+    }
+
+    /// Whether a keycode is pressed
+    pub fn is_pressed(&self, kc: KeyCode) -> bool {
+        if kc.is_modifier() {
+            return self.modifier & kc.as_modifier_bit() != 0;
+        }
+        let kc = kc as u8;
+        self.keys[kc as usize / 8] & 1 << (kc % 8) != 0
+    }
+
+    /// Boot report, with up to 6 keycodes
+    fn boot_report(&self) -> [u8; BOOT_REPORT_SIZE] {
+        use KeyCode::{ErrorRollOver, ErrorUndefined, PostFail};
+        let mut report = [0; BOOT_REPORT_SIZE];
+        report[0] = self.modifier;
+        let error = [ErrorRollOver, PostFail, ErrorUndefined]
+            .into_iter()
+            .find(|&kc| self.is_pressed(kc))
+            .or((self.keycodes().count() > 6).then_some(ErrorRollOver));
+        if let Some(kc) = error {
+            // All the slots are set to the error, without any modifier
+            report[0] = 0;
+            report[2..].fill(kc as u8);
+            error!("Error: {:?}", Debug2Format(&kc));
+        } else {
+            for (slot, kc) in report[2..].iter_mut().zip(self.keycodes()) {
+                *slot = kc;
+            }
+        }
+        report
+    }
+
+    /// Report in report protocol, with up to 6 keycodes
+    fn six_kro_report(&self) -> [u8; KB_REPORT_SIZE] {
+        let mut report = [0; KB_REPORT_SIZE];
+        report[..BOOT_REPORT_SIZE].copy_from_slice(&self.boot_report());
+        report
+    }
+
+    /// Report in report protocol, with all the keycodes
+    fn nkro_report(&self) -> [u8; KB_REPORT_SIZE] {
+        let mut report = [0; KB_REPORT_SIZE];
+        report[0] = self.modifier;
+        report[BOOT_REPORT_SIZE..].copy_from_slice(&self.keys[..NKRO_BITMAP_SIZE]);
+        report
+    }
+}
+
+/// Report ID of the consumer control report
+const CONSUMER_REPORT_ID: u8 = 1;
+/// Report ID of the system control report
+const SYSTEM_REPORT_ID: u8 = 2;
+/// Number of consumer control usages reported at the same time
+const NB_CONSUMER_USAGES: usize = 4;
+/// Size of the consumer control report
+const CONSUMER_REPORT_SIZE: usize = 1 + 2 * NB_CONSUMER_USAGES;
+
+/// Report descriptor of the extra keys, not handled by the keyboard report
+#[rustfmt::skip]
+pub const EXTRA_KEYS_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x0C, // Usage Page (Consumer)
+    0x09, 0x01, // Usage (Consumer Control)
+    0xA1, 0x01, // Collection (Application)
+    0x85, CONSUMER_REPORT_ID, // Report ID
+    0x19, 0x00, //   Usage Minimum (0)
+    0x2A, 0xFF, 0x03, // Usage Maximum (0x3FF)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x03, // Logical Maximum (0x3FF)
+    0x75, 0x10, //   Report Size (16)
+    0x95, NB_CONSUMER_USAGES as u8, // Report Count
+    0x81, 0x00, //   Input (Data, Array)
+    0xC0,       // End Collection
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x80, // Usage (System Control)
+    0xA1, 0x01, // Collection (Application)
+    0x85, SYSTEM_REPORT_ID, // Report ID
+    0x19, SystemControl::PowerDown as u8, // Usage Minimum
+    0x29, SystemControl::WakeUp as u8, // Usage Maximum
+    0x15, SystemControl::PowerDown as u8, // Logical Minimum
+    0x25, SystemControl::WakeUp as u8, // Logical Maximum
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x01, //   Report Count (1)
+    0x81, 0x00, //   Input (Data, Array)
+    0xC0,       // End Collection
+];
+
+/// Consumer control usage of a keycode, if it is sent in the consumer
+/// control report instead of the keyboard report
+pub fn consumer_usage(kc: KeyCode) -> Option<u16> {
+    use KeyCode::*;
+    match kc {
+        Mute | MediaMute => Some(0xE2),
+        VolUp | MediaVolUp => Some(0xE9),
+        VolDown | MediaVolDown => Some(0xEA),
+        MediaPlayPause => Some(0xCD),
+        MediaStopCD | MediaStop => Some(0xB7),
+        MediaPreviousSong => Some(0xB6),
+        MediaNextSong => Some(0xB5),
+        MediaEjectCD => Some(0xB8),
+        MediaWWW => Some(0x196),
+        MediaBack => Some(0x224),
+        MediaForward => Some(0x225),
+        MediaFind => Some(0x221),
+        MediaEdit => Some(0x185),
+        MediaCoffee => Some(0x19E),
+        MediaRefresh => Some(0x227),
+        MediaCalc => Some(0x192),
+        _ => None,
+    }
+}
+
+/// Consumer control usages pressed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConsumerReport {
+    /// Usages pressed, 0 for an empty slot
+    usages: [u16; NB_CONSUMER_USAGES],
+}
+
+impl ConsumerReport {
+    /// Add a pressed usage, dropped when all the slots are taken
+    pub fn press(&mut self, usage: u16) {
+        if self.usages.contains(&usage) {
+            return;
+        }
+        match self.usages.iter_mut().find(|u| **u == 0) {
+            Some(slot) => *slot = usage,
+            None => warn!("Too many consumer keys pressed, dropping {:#x}", usage),
+        }
+    }
+
+    /// Report as sent over USB
+    fn to_bytes(self) -> [u8; CONSUMER_REPORT_SIZE] {
+        let mut report = [0; CONSUMER_REPORT_SIZE];
+        report[0] = CONSUMER_REPORT_ID;
+        for (bytes, usage) in report[1..].chunks_exact_mut(2).zip(self.usages) {
+            bytes.copy_from_slice(&usage.to_le_bytes());
+        }
+        report
+    }
+}
+
+/// Generic Desktop system control usages
+///
+/// Wake Up also signals a remote wakeup when the USB bus is suspended, the
+/// host not listening to the keyboard anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum SystemControl {
+    /// System Power Down
+    PowerDown = 0x81,
+    /// System Sleep
+    Sleep = 0x82,
+    /// System Wake Up
+    WakeUp = 0x83,
+}
+
+/// System control report, with the usage pressed if any
+fn system_report(usage: Option<SystemControl>) -> [u8; 2] {
+    [SYSTEM_REPORT_ID, usage.map_or(0, |u| u as u8)]
+}
+
+/// Format of the keyboard reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+enum KbReportFormat {
+    /// Boot protocol, selected by the host
+    Boot,
+    /// Report protocol, with up to 6 keycodes
+    SixKro,
+    /// Report protocol, with all the keycodes
+    Nkro,
+}
+
+/// Whether the host selected the boot protocol
+static BOOT_PROTOCOL: AtomicBool = AtomicBool::new(false);
+/// Whether all the keycodes are reported in report protocol
+static NKRO: AtomicBool = AtomicBool::new(true);
+/// Signaled when the format of the keyboard reports changes
+static KB_REPORT_FORMAT_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Current format of the keyboard reports
+fn kb_report_format() -> KbReportFormat {
+    if BOOT_PROTOCOL.load(Ordering::Relaxed) {
+        KbReportFormat::Boot
+    } else if NKRO.load(Ordering::Relaxed) {
+        KbReportFormat::Nkro
+    } else {
+        KbReportFormat::SixKro
+    }
+}
+
+/// Switch between NKRO and 6KRO in report protocol
+pub fn toggle_nkro() {
+    NKRO.fetch_xor(true, Ordering::Relaxed);
+    KB_REPORT_FORMAT_CHANGED.signal(());
+}
+
+/// Handler of the protocol requests of the keyboard interface
+pub struct KbProtocolHandler {}
+
+impl KbProtocolHandler {
+    /// Create a new protocol handler
+    pub fn new() -> Self {
+        KbProtocolHandler {}
+    }
+}
+
+impl RequestHandler for KbProtocolHandler {
+    fn get_protocol(&self) -> HidProtocolMode {
+        if BOOT_PROTOCOL.load(Ordering::Relaxed) {
+            HidProtocolMode::Boot
+        } else {
+            HidProtocolMode::Report
+        }
+    }
+
+    fn set_protocol(&mut self, protocol: HidProtocolMode) -> OutResponse {
+        info!("Set protocol to {:?}", protocol);
+        BOOT_PROTOCOL.store(protocol == HidProtocolMode::Boot, Ordering::Relaxed);
+        KB_REPORT_FORMAT_CHANGED.signal(());
+        OutResponse::Accepted
+    }
+}
+
 /// HID handler
 pub struct HidRequestHandler<'a> {
     /// Spawner
@@ -71,10 +441,10 @@ async fn caps_lock_change() {
     // send a key press and release event for the CapsLock key so that
     // the keymap can do something with it, like changing the default layer
     LAYOUT_CHANNEL
-        .send(keyberon::layout::Event::Press(3, 0))
+        .send((keyberon::layout::Event::Press(3, 0), Instant::now()))
         .await;
     LAYOUT_CHANNEL
-        .send(keyberon::layout::Event::Release(3, 0))
+        .send((keyberon::layout::Event::Release(3, 0), Instant::now()))
         .await;
 }
 #[embassy_executor::task]
@@ -82,10 +452,10 @@ async fn num_lock_change() {
     // send a key press and release event for the NumLock key so that
     // the keymap can do something with it, like changing the default layer
     LAYOUT_CHANNEL
-        .send(keyberon::layout::Event::Press(3, 1))
+        .send((keyberon::layout::Event::Press(3, 1), Instant::now()))
         .await;
     LAYOUT_CHANNEL
-        .send(keyberon::layout::Event::Release(3, 1))
+        .send((keyberon::layout::Event::Release(3, 1), Instant::now()))
         .await;
 }
 
@@ -94,6 +464,7 @@ impl HidRequestHandler<'_> {
     fn caps_lock(&mut self, caps_lock: bool) {
         if self.caps_lock != caps_lock {
             self.caps_lock = caps_lock;
+            update_host_state(|s| s.caps_lock = caps_lock);
             self.spawner.spawn(caps_lock_change().unwrap());
         }
     }
@@ -101,33 +472,128 @@ impl HidRequestHandler<'_> {
     fn num_lock(&mut self, num_lock: bool) {
         if self.num_lock != num_lock {
             self.num_lock = num_lock;
+            update_host_state(|s| s.num_lock = num_lock);
             self.spawner.spawn(num_lock_change().unwrap());
         }
     }
 }
 
-/// Loop to read HID KeyboardReport reports from the channel and send them over USB
+/// Loop to read keyboard reports from the channel and send them over USB,
+/// in the format expected by the host
+#[cfg(not(test))]
 pub async fn hid_kb_writer_handler<'a>(mut writer: HidWriter<'a, 'a>) {
+    let mut report = KbReport::default();
     loop {
-        let hid_report = HID_KB_CHANNEL.receive().await;
-        if is_host() {
-            match writer.write_serialize(&hid_report).await {
-                Ok(()) => {}
-                Err(e) => warn!("Failed to send report: {:?}", e),
-            }
+        match select(HID_KB_CHANNEL.receive(), KB_REPORT_FORMAT_CHANGED.wait()).await {
+            Either::First(r) => report = r,
+            // Send the current report again, in the new format
+            Either::Second(()) => info!("Keyboard report format: {:?}", kb_report_format()),
+        }
+        let res = match kb_report_format() {
+            KbReportFormat::Boot => writer.write(&report.boot_report()).await,
+            KbReportFormat::SixKro => writer.write(&report.six_kro_report()).await,
+            KbReportFormat::Nkro => writer.write(&report.nkro_report()).await,
+        };
+        match res {
+            Ok(()) => {}
+            Err(e) => warn!("Failed to send report: {:?}", e),
         }
     }
 }
 
 /// Loop to read HID MouseReport reports from the channel and send them over USB
+#[cfg(not(test))]
 pub async fn hid_mouse_writer_handler<'a>(mut writer: HidWriter<'a, 'a>) {
     loop {
         let hid_report = HID_MOUSE_CHANNEL.receive().await;
-        if is_host() {
-            match writer.write_serialize(&hid_report).await {
-                Ok(()) => {}
-                Err(e) => warn!("Failed to send report: {:?}", e),
-            }
+        match writer.write_serialize(&hid_report).await {
+            Ok(()) => {}
+            Err(e) => warn!("Failed to send report: {:?}", e),
         }
     }
 }
+
+/// Loop to read consumer and system control reports from the channels and
+/// send them over USB
+#[cfg(not(test))]
+pub async fn hid_extra_keys_writer_handler<'a>(mut writer: HidWriter<'a, 'a>) {
+    loop {
+        let res = match select(HID_CONSUMER_CHANNEL.receive(), HID_SYSTEM_CHANNEL.receive()).await {
+            Either::First(hid_report) => writer.write(&hid_report.to_bytes()).await,
+            Either::Second(usage) => writer.write(&system_report(usage)).await,
+        };
+        match res {
+            Ok(()) => {}
+            Err(e) => warn!("Failed to send report: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{assert, assert_eq};
+
+    #[test]
+    fn keyboard_keycodes() {
+        for kc in [
+            KeyCode::A,
+            KeyCode::Kb1,
+            KeyCode::Enter,
+            KeyCode::LShift,
+            KeyCode::F12,
+        ] {
+            assert_eq!(consumer_usage(kc), None);
+        }
+    }
+
+    #[test]
+    fn consumer_keycodes() {
+        // Both the keyboard page keycodes and their media aliases
+        assert_eq!(consumer_usage(KeyCode::Mute), Some(0xE2));
+        assert_eq!(consumer_usage(KeyCode::MediaMute), Some(0xE2));
+        assert_eq!(consumer_usage(KeyCode::VolUp), Some(0xE9));
+        assert_eq!(consumer_usage(KeyCode::MediaVolUp), Some(0xE9));
+        assert_eq!(consumer_usage(KeyCode::VolDown), Some(0xEA));
+        assert_eq!(consumer_usage(KeyCode::MediaVolDown), Some(0xEA));
+        assert_eq!(consumer_usage(KeyCode::MediaPlayPause), Some(0xCD));
+        assert_eq!(consumer_usage(KeyCode::MediaStop), Some(0xB7));
+        assert_eq!(consumer_usage(KeyCode::MediaNextSong), Some(0xB5));
+        assert_eq!(consumer_usage(KeyCode::MediaPreviousSong), Some(0xB6));
+        assert_eq!(consumer_usage(KeyCode::MediaCalc), Some(0x192));
+    }
+
+    #[test]
+    fn consumer_report() {
+        let mut report = ConsumerReport::default();
+        report.press(0xE9);
+        report.press(0xE9);
+        report.press(0x192);
+        assert_eq!(
+            report.to_bytes(),
+            [CONSUMER_REPORT_ID, 0xE9, 0, 0x92, 0x01, 0, 0, 0, 0]
+        );
+        // Dropped once all the slots are taken
+        for usage in [0xB5, 0xB6, 0xB7] {
+            report.press(usage);
+        }
+        assert!(!report.usages.contains(&0xB7));
+    }
+
+    #[test]
+    fn system_reports() {
+        assert_eq!(system_report(None), [SYSTEM_REPORT_ID, 0]);
+        assert_eq!(
+            system_report(Some(SystemControl::PowerDown)),
+            [SYSTEM_REPORT_ID, 0x81]
+        );
+        assert_eq!(
+            system_report(Some(SystemControl::Sleep)),
+            [SYSTEM_REPORT_ID, 0x82]
+        );
+        assert_eq!(
+            system_report(Some(SystemControl::WakeUp)),
+            [SYSTEM_REPORT_ID, 0x83]
+        );
+    }
+}
@@ -0,0 +1,654 @@
+use crate::layout::{
+    CustomEvent, COLS, HOLD_ROW, MATRIX_ROWS, RIGHT_FIRST_COL, TAP_ROW, THUMB_ROW,
+};
+use defmt::{error, warn};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use keyberon::action::Action;
+use keyberon::layout::Event;
+
+/// Maximum number of events held back while a hold tap is undecided
+const MAX_BUFFERED: usize = 8;
+/// Maximum number of hold taps held at the same time
+const MAX_HELD: usize = 4;
+/// Minimum time another key has to overlap a balanced hold tap for it to be
+/// held
+const BALANCED_OVERLAP: Duration = Duration::from_millis(80);
+
+/// Coordinates of a key, as (row, column)
+type Key = (u8, u8);
+
+/// Virtual key of the hold tap at `index`, in the hold or tap rows starting
+/// at `row`
+fn virtual_key(row: u8, index: u8) -> Key {
+    (row + index / COLS as u8, index % COLS as u8)
+}
+
+/// Press of the virtual key of the hold tap at `index`, in the hold or tap
+/// rows starting at `row`
+fn press(row: u8, index: u8) -> Event {
+    let (i, j) = virtual_key(row, index);
+    Event::Press(i, j)
+}
+
+/// Release of the virtual key of the hold tap at `index`, in the hold or tap
+/// rows starting at `row`
+fn release(row: u8, index: u8) -> Event {
+    let (i, j) = virtual_key(row, index);
+    Event::Release(i, j)
+}
+
+/// Whether `other` is on the same half as `key`
+///
+/// The thumb keys and the virtual keys are on neither half, so they always
+/// let a bilateral hold tap be held.
+fn same_half(key: Key, other: Key) -> bool {
+    (other.0 as usize) < MATRIX_ROWS
+        && other.0 != THUMB_ROW
+        && (key.1 < RIGHT_FIRST_COL) == (other.1 < RIGHT_FIRST_COL)
+}
+
+/// How a hold tap is decided when another key is pressed before its timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoldTapMode {
+    /// Held as soon as another key is pressed
+    HoldOnOtherKeyPress,
+    /// Held when another key is pressed and released before it
+    PermissiveHold,
+    /// Held when another key is pressed and released before it, or pressed
+    /// long enough before its release, unlike a fast roll
+    Balanced,
+}
+
+impl HoldTapMode {
+    /// Whether it is held as soon as another key is pressed
+    fn holds_on_press(self) -> bool {
+        self == HoldTapMode::HoldOnOtherKeyPress
+    }
+
+    /// Whether it is held when another key is pressed and released before
+    /// it
+    fn holds_on_tap(self) -> bool {
+        self == HoldTapMode::PermissiveHold || self == HoldTapMode::Balanced
+    }
+}
+
+/// Action depending on whether its key is tapped or held, decided by the
+/// layout engines from the time of the key events
+///
+/// To be declared as a `static` listed in the hold taps of the keymap, and
+/// used in the layout with `Action::Custom(CustomEvent::HoldTap(&HT))`.
+#[derive(Debug)]
+pub struct HoldTap {
+    /// Time after which the key is held, in ms
+    pub timeout: u16,
+    /// Action when held
+    pub hold: Action<CustomEvent>,
+    /// Action when tapped
+    pub tap: Action<CustomEvent>,
+    /// How it is decided when another key is pressed
+    pub mode: HoldTapMode,
+    /// Whether it is tapped when released after its timeout, no other key
+    /// being pressed meanwhile
+    pub retro_tap: bool,
+    /// Whether it is tapped as soon as a key of the same half is pressed
+    /// before it is decided, to be held only with a key of the other half
+    pub bilateral: bool,
+}
+
+/// Hold tap not decided yet
+struct Pending {
+    /// Coordinates of its key
+    key: Key,
+    /// Hold tap
+    hold_tap: &'static HoldTap,
+    /// Index in the hold taps of the keymap, locating its virtual keys
+    index: u8,
+    /// Time its key was pressed at
+    pressed_at: Instant,
+    /// Events held back, with their action and the time they happened at
+    events: Vec<(Event, &'static Action<CustomEvent>, Instant), MAX_BUFFERED>,
+}
+
+impl Pending {
+    /// Whether a key pressed after the hold tap was released
+    fn has_tap(&self) -> bool {
+        self.events.iter().any(|(e, ..)| match e {
+            Event::Release(i, j) => self.events.iter().any(|(p, ..)| *p == Event::Press(*i, *j)),
+            Event::Press(..) => false,
+        })
+    }
+
+    /// Time after which the hold tap is held
+    fn deadline(&self) -> Instant {
+        self.pressed_at + Duration::from_millis(self.hold_tap.timeout.into())
+    }
+
+    /// Time between the first key pressed after the hold tap, if any, and
+    /// `time`
+    fn overlap(&self, time: Instant) -> Option<Duration> {
+        self.events.iter().find_map(|(e, _, t)| match e {
+            Event::Press(..) => Some(time.saturating_duration_since(*t)),
+            Event::Release(..) => None,
+        })
+    }
+}
+
+/// Hold tap resolved while its key is held
+struct Held {
+    /// Coordinates of its key
+    key: Key,
+    /// First row of the virtual key pressed, hold or tap
+    row: u8,
+    /// Index in the hold taps of the keymap, locating its virtual keys
+    index: u8,
+    /// Whether it is tapped when released
+    retro_tap: bool,
+}
+
+/// Hold tap engine, between the key events and the layout
+///
+/// The events following the press of a hold tap are held back until it is
+/// decided, then its hold or tap action is pressed, from the virtual key of
+/// its index in the hold or tap rows.
+pub struct HoldTaps {
+    /// Hold taps of the keymap
+    hold_taps: &'static [&'static HoldTap],
+    /// Hold tap not decided yet
+    pending: Option<Pending>,
+    /// Hold taps resolved as held
+    held: Vec<Held, MAX_HELD>,
+}
+
+impl HoldTaps {
+    /// Create a new hold tap engine
+    pub fn new(hold_taps: &'static [&'static HoldTap]) -> Self {
+        HoldTaps {
+            hold_taps,
+            pending: None,
+            held: Vec::new(),
+        }
+    }
+
+    /// Press the hold or tap virtual key, at `row`, of the pending hold tap
+    /// until its key is released, then send the events held back
+    fn resolve(&mut self, row: u8, emit: &mut dyn FnMut(Event)) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        emit(press(row, pending.index));
+        let held = Held {
+            key: pending.key,
+            row,
+            index: pending.index,
+            retro_tap: row == HOLD_ROW && pending.hold_tap.retro_tap && pending.events.is_empty(),
+        };
+        if let Err(held) = self.held.push(held) {
+            warn!("Too many hold taps held");
+            emit(release(row, held.index));
+        }
+        self.flush(pending.events, emit);
+    }
+
+    /// Hold the pending hold tap, then send the events held back
+    fn hold(&mut self, emit: &mut dyn FnMut(Event)) {
+        self.resolve(HOLD_ROW, emit);
+    }
+
+    /// Tap the pending hold tap, its key being released, with the events
+    /// held back sent in between
+    fn tap(&mut self, emit: &mut dyn FnMut(Event)) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        emit(press(TAP_ROW, pending.index));
+        self.flush(pending.events, emit);
+        emit(release(TAP_ROW, pending.index));
+    }
+
+    /// Process again the events held back
+    fn flush(
+        &mut self,
+        events: Vec<(Event, &'static Action<CustomEvent>, Instant), MAX_BUFFERED>,
+        emit: &mut dyn FnMut(Event),
+    ) {
+        for (event, action, time) in events {
+            self.process(event, time, action, emit);
+        }
+    }
+
+    /// Process a key event, that happened at `time`, with `action`
+    fn process(
+        &mut self,
+        event: Event,
+        time: Instant,
+        action: &'static Action<CustomEvent>,
+        emit: &mut dyn FnMut(Event),
+    ) {
+        if let Some(pending) = &mut self.pending {
+            if time >= pending.deadline() {
+                // Held before the event happened, even if it was received
+                // before the timeout was ticked
+                self.hold(emit);
+                return self.process(event, time, action, emit);
+            }
+            let released = event == Event::Release(pending.key.0, pending.key.1);
+            if !released && pending.events.push((event, action, time)).is_err() {
+                warn!("Too many events while a hold tap is undecided");
+                self.hold(emit);
+                return self.process(event, time, action, emit);
+            }
+            let bilateral = match event {
+                Event::Press(i, j) => pending.hold_tap.bilateral && same_half(pending.key, (i, j)),
+                Event::Release(..) => false,
+            };
+            match pending.hold_tap.mode {
+                _ if bilateral => self.resolve(TAP_ROW, emit),
+                _ if released => {
+                    let overlap = pending.overlap(time).unwrap_or_default();
+                    if pending.hold_tap.mode == HoldTapMode::Balanced && overlap >= BALANCED_OVERLAP
+                    {
+                        self.hold(emit);
+                        self.process(event, time, action, emit);
+                    } else {
+                        self.tap(emit);
+                    }
+                }
+                mode if mode.holds_on_press() && event.is_press() => self.hold(emit),
+                mode if mode.holds_on_tap() && pending.has_tap() => self.hold(emit),
+                _ => {}
+            }
+            return;
+        }
+        match (event, action) {
+            (Event::Press(i, j), Action::Custom(CustomEvent::HoldTap(ht))) => {
+                let Some(index) = self.hold_taps.iter().position(|h| core::ptr::eq(*h, *ht)) else {
+                    error!("Hold tap missing from the keymap");
+                    return;
+                };
+                self.pending = Some(Pending {
+                    key: (i, j),
+                    hold_tap: ht,
+                    index: index as u8,
+                    pressed_at: time,
+                    events: Vec::new(),
+                });
+            }
+            (Event::Press(..), _) => {
+                for held in self.held.iter_mut() {
+                    held.retro_tap = false;
+                }
+                emit(event);
+            }
+            (Event::Release(i, j), _) => match self.held.iter().position(|h| h.key == (i, j)) {
+                Some(index) => {
+                    let held = self.held.swap_remove(index);
+                    emit(release(held.row, held.index));
+                    if held.retro_tap {
+                        emit(press(TAP_ROW, held.index));
+                        emit(release(TAP_ROW, held.index));
+                    }
+                }
+                None => emit(event),
+            },
+        }
+    }
+
+    /// Process a key event, that happened at `time`, with `action`, sending
+    /// the resulting events to the layout through `emit`
+    ///
+    /// The hold taps are decided from the times of the events, taken when
+    /// the keys are scanned or received from the other half, not from the
+    /// time they are processed at.
+    pub fn event(
+        &mut self,
+        event: Event,
+        time: Instant,
+        action: &'static Action<CustomEvent>,
+        mut emit: impl FnMut(Event),
+    ) {
+        self.process(event, time, action, &mut emit);
+    }
+
+    /// Hold the pending hold tap if its timeout expired at `now`
+    pub fn tick(&mut self, now: Instant, mut emit: impl FnMut(Event)) {
+        if self.pending.as_ref().is_some_and(|p| now >= p.deadline()) {
+            self.hold(&mut emit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::COMBO_ROW;
+    use keyberon::action::k;
+    use keyberon::key_code::KeyCode;
+    use std::vec;
+
+    /// Hold tap of the tests in `mode`
+    const fn hold_tap(mode: HoldTapMode, retro_tap: bool) -> HoldTap {
+        HoldTap {
+            timeout: 200,
+            hold: k(KeyCode::LCtrl),
+            tap: k(KeyCode::A),
+            mode,
+            retro_tap,
+            bilateral: false,
+        }
+    }
+
+    /// Held on another key press
+    static HOLD_ON_PRESS: HoldTap = hold_tap(HoldTapMode::HoldOnOtherKeyPress, false);
+    /// Permissive hold
+    static PERMISSIVE: HoldTap = hold_tap(HoldTapMode::PermissiveHold, false);
+    /// Balanced
+    static BALANCED: HoldTap = hold_tap(HoldTapMode::Balanced, false);
+    /// Permissive hold, tapped when held alone
+    static RETRO: HoldTap = hold_tap(HoldTapMode::PermissiveHold, true);
+    /// Balanced, held only with a key of the other half, like the home
+    /// row mods
+    static BILATERAL: HoldTap = HoldTap {
+        bilateral: true,
+        ..hold_tap(HoldTapMode::Balanced, true)
+    };
+    /// Hold taps of the tests, at the columns of their virtual keys
+    static HOLD_TAPS: [&HoldTap; 5] = [&HOLD_ON_PRESS, &PERMISSIVE, &BALANCED, &RETRO, &BILATERAL];
+    /// Actions of the keys of the hold taps
+    static ACTIONS: [Action<CustomEvent>; 5] = [
+        Action::Custom(CustomEvent::HoldTap(&HOLD_ON_PRESS)),
+        Action::Custom(CustomEvent::HoldTap(&PERMISSIVE)),
+        Action::Custom(CustomEvent::HoldTap(&BALANCED)),
+        Action::Custom(CustomEvent::HoldTap(&RETRO)),
+        Action::Custom(CustomEvent::HoldTap(&BILATERAL)),
+    ];
+    /// Action of the other keys
+    static KEY: Action<CustomEvent> = k(KeyCode::B);
+
+    /// Process `event` of a key with `action`, that happened at `ms`,
+    /// returning the events emitted
+    fn event(
+        hold_taps: &mut HoldTaps,
+        event: Event,
+        ms: u64,
+        action: &'static Action<CustomEvent>,
+    ) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        hold_taps.event(event, Instant::from_millis(ms), action, |e| events.push(e));
+        events
+    }
+
+    /// Tick the hold taps at `ms`, returning the events emitted
+    fn tick(hold_taps: &mut HoldTaps, ms: u64) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        hold_taps.tick(Instant::from_millis(ms), |e| events.push(e));
+        events
+    }
+
+    #[test]
+    fn tapped() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        assert!(event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[0]).is_empty());
+        assert!(tick(&mut hold_taps, 199).is_empty());
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 199, &ACTIONS[0]),
+            [Event::Press(TAP_ROW, 0), Event::Release(TAP_ROW, 0)]
+        );
+    }
+
+    #[test]
+    fn second_rows() {
+        // Hold on press at index 11, past the first rows of virtual keys
+        static MANY: [&HoldTap; 12] = [
+            &PERMISSIVE,
+            &PERMISSIVE,
+            &PERMISSIVE,
+            &PERMISSIVE,
+            &PERMISSIVE,
+            &PERMISSIVE,
+            &PERMISSIVE,
+            &PERMISSIVE,
+            &PERMISSIVE,
+            &PERMISSIVE,
+            &PERMISSIVE,
+            &HOLD_ON_PRESS,
+        ];
+        let mut hold_taps = HoldTaps::new(&MANY);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[0]);
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 10, &ACTIONS[0]),
+            [Event::Press(TAP_ROW + 1, 1), Event::Release(TAP_ROW + 1, 1)]
+        );
+        event(&mut hold_taps, Event::Press(1, 0), 20, &ACTIONS[0]);
+        assert_eq!(tick(&mut hold_taps, 220), [Event::Press(HOLD_ROW + 1, 1)]);
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 230, &ACTIONS[0]),
+            [Event::Release(HOLD_ROW + 1, 1)]
+        );
+    }
+
+    #[test]
+    fn held_on_timeout() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[1]);
+        assert_eq!(tick(&mut hold_taps, 200), [Event::Press(HOLD_ROW, 1)]);
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 300, &ACTIONS[1]),
+            [Event::Release(HOLD_ROW, 1)]
+        );
+    }
+
+    #[test]
+    fn held_before_a_late_event() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[1]);
+        // Released after the timeout, processed before it was ticked
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 250, &ACTIONS[1]),
+            [Event::Press(HOLD_ROW, 1), Event::Release(HOLD_ROW, 1)]
+        );
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[0]);
+        assert_eq!(
+            event(&mut hold_taps, Event::Press(1, 1), 10, &KEY),
+            [Event::Press(HOLD_ROW, 0), Event::Press(1, 1)]
+        );
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 20, &ACTIONS[0]),
+            [Event::Release(HOLD_ROW, 0)]
+        );
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[1]);
+        assert!(event(&mut hold_taps, Event::Press(1, 1), 10, &KEY).is_empty());
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 1), 20, &KEY),
+            [
+                Event::Press(HOLD_ROW, 1),
+                Event::Press(1, 1),
+                Event::Release(1, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn permissive_hold_rolled() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[1]);
+        event(&mut hold_taps, Event::Press(1, 1), 10, &KEY);
+        // Released first, the hold tap is tapped before the other key
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 20, &ACTIONS[1]),
+            [
+                Event::Press(TAP_ROW, 1),
+                Event::Press(1, 1),
+                Event::Release(TAP_ROW, 1)
+            ]
+        );
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 1), 30, &KEY),
+            [Event::Release(1, 1)]
+        );
+    }
+
+    #[test]
+    fn balanced() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        let overlap = BALANCED_OVERLAP.as_millis();
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[2]);
+        event(&mut hold_taps, Event::Press(1, 1), 10, &KEY);
+        assert_eq!(
+            event(
+                &mut hold_taps,
+                Event::Release(1, 0),
+                10 + overlap - 1,
+                &ACTIONS[2]
+            ),
+            [
+                Event::Press(TAP_ROW, 2),
+                Event::Press(1, 1),
+                Event::Release(TAP_ROW, 2)
+            ]
+        );
+        event(&mut hold_taps, Event::Release(1, 1), 100, &KEY);
+        // Overlapping long enough, it is held
+        event(&mut hold_taps, Event::Press(1, 0), 200, &ACTIONS[2]);
+        event(&mut hold_taps, Event::Press(1, 1), 210, &KEY);
+        assert_eq!(
+            event(
+                &mut hold_taps,
+                Event::Release(1, 0),
+                210 + overlap,
+                &ACTIONS[2]
+            ),
+            [
+                Event::Press(HOLD_ROW, 2),
+                Event::Press(1, 1),
+                Event::Release(HOLD_ROW, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn balanced_received_late() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[2]);
+        tick(&mut hold_taps, 100);
+        // A fast roll received late from the other half is still a roll
+        event(&mut hold_taps, Event::Press(1, 9), 20, &KEY);
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 40, &ACTIONS[2])[0],
+            Event::Press(TAP_ROW, 2)
+        );
+    }
+
+    #[test]
+    fn retro_tap() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[3]);
+        tick(&mut hold_taps, 200);
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 300, &ACTIONS[3]),
+            [
+                Event::Release(HOLD_ROW, 3),
+                Event::Press(TAP_ROW, 3),
+                Event::Release(TAP_ROW, 3)
+            ]
+        );
+        // Not tapped once another key was pressed
+        event(&mut hold_taps, Event::Press(1, 0), 400, &ACTIONS[3]);
+        tick(&mut hold_taps, 600);
+        event(&mut hold_taps, Event::Press(1, 1), 650, &KEY);
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 700, &ACTIONS[3]),
+            [Event::Release(HOLD_ROW, 3)]
+        );
+    }
+
+    #[test]
+    fn nested() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[1]);
+        assert!(event(&mut hold_taps, Event::Press(1, 1), 10, &ACTIONS[3]).is_empty());
+        assert!(event(&mut hold_taps, Event::Press(1, 2), 20, &KEY).is_empty());
+        // Both hold taps are held by the key tapped
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 2), 30, &KEY),
+            [
+                Event::Press(HOLD_ROW, 1),
+                Event::Press(HOLD_ROW, 3),
+                Event::Press(1, 2),
+                Event::Release(1, 2)
+            ]
+        );
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 1), 40, &ACTIONS[3]),
+            [Event::Release(HOLD_ROW, 3)]
+        );
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 50, &ACTIONS[1]),
+            [Event::Release(HOLD_ROW, 1)]
+        );
+    }
+
+    #[test]
+    fn bilateral_same_hand_roll() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        // A then S, on the home row of the left half
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[4]);
+        assert_eq!(
+            event(&mut hold_taps, Event::Press(1, 2), 10, &KEY),
+            [Event::Press(TAP_ROW, 4), Event::Press(1, 2)]
+        );
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 0), 20, &ACTIONS[4]),
+            [Event::Release(TAP_ROW, 4)]
+        );
+    }
+
+    #[test]
+    fn bilateral_cross_hand_roll() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[4]);
+        assert!(event(&mut hold_taps, Event::Press(1, 7), 10, &KEY).is_empty());
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(1, 7), 20, &KEY),
+            [
+                Event::Press(HOLD_ROW, 4),
+                Event::Press(1, 7),
+                Event::Release(1, 7)
+            ]
+        );
+    }
+
+    #[test]
+    fn bilateral_thumb_key() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[4]);
+        // A thumb key of the same half is on neither half, it is held
+        assert!(event(&mut hold_taps, Event::Press(THUMB_ROW, 2), 10, &KEY).is_empty());
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(THUMB_ROW, 2), 20, &KEY)[0],
+            Event::Press(HOLD_ROW, 4)
+        );
+    }
+
+    #[test]
+    fn bilateral_virtual_key() {
+        let mut hold_taps = HoldTaps::new(&HOLD_TAPS);
+        event(&mut hold_taps, Event::Press(1, 0), 0, &ACTIONS[4]);
+        // A combo is on no half, nor is it a same-hand roll
+        assert!(event(&mut hold_taps, Event::Press(COMBO_ROW, 0), 10, &KEY).is_empty());
+        assert_eq!(
+            event(&mut hold_taps, Event::Release(COMBO_ROW, 0), 20, &KEY)[0],
+            Event::Press(HOLD_ROW, 4)
+        );
+    }
+}
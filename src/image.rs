@@ -0,0 +1,82 @@
+//! Layout of the firmware images in flash, shared with the bootloader
+//!
+//! The flash of the STM32F401CD is split into:
+//!
+//! - the bootloader, in sectors 0 and 1
+//! - the active firmware, in sectors 2 to 5
+//! - the DFU partition, in sector 6, where an update is written before the
+//!   bootloader copies it over the active firmware
+//!
+//! An update in the DFU partition starts with a header, written only once the
+//! whole image is received and checked.
+
+// Each of the firmware and the bootloader only uses a part of this module
+#![allow(dead_code)]
+
+/// Offset of the active firmware from the start of the flash
+pub const ACTIVE_OFFSET: u32 = 0x0000_8000;
+/// Size of the active firmware partition
+pub const ACTIVE_SIZE: u32 = 0x0003_8000;
+/// Offset of the DFU partition from the start of the flash
+pub const DFU_OFFSET: u32 = 0x0004_0000;
+/// Size of the DFU partition
+pub const DFU_SIZE: u32 = 0x0002_0000;
+/// Size of the header at the start of the DFU partition
+pub const HEADER_SIZE: u32 = 32;
+/// Offset of the image in the DFU partition
+pub const IMAGE_OFFSET: u32 = DFU_OFFSET + HEADER_SIZE;
+/// Maximum size of a firmware image
+pub const MAX_IMAGE_SIZE: u32 = DFU_SIZE - HEADER_SIZE;
+/// Marker of a valid header
+const MAGIC: u32 = 0xC36D_F00D;
+
+/// Compute the CRC-32 (IEEE 802.3) of `data`, continuing from `crc`
+///
+/// Start with `crc = 0`.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xEDB8_8320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
+}
+
+/// Header of an image waiting in the DFU partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// Size of the image
+    pub size: u32,
+    /// CRC-32 of the image
+    pub crc: u32,
+}
+
+impl ImageHeader {
+    /// Encode the header as stored in flash
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE as usize] {
+        let mut bytes = [0xff; HEADER_SIZE as usize];
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.size.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a header read from flash, if any is there
+    pub fn from_bytes(bytes: &[u8; HEADER_SIZE as usize]) -> Option<Self> {
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        if word(0) != MAGIC || word(4) > MAX_IMAGE_SIZE {
+            return None;
+        }
+        Some(ImageHeader {
+            size: word(4),
+            crc: word(8),
+        })
+    }
+}
@@ -1,31 +1,44 @@
-use crate::layout::CustomEvent;
+use crate::autoshift::AutoShiftConfig;
+use crate::combos::Combo;
+use crate::holdtap::{HoldTap, HoldTapMode};
+use crate::keyoverride::KeyOverride;
 use crate::layout::CustomEvent::*;
+use crate::layout::{with_virtual_keys, CustomEvent, LAYOUT_ROWS};
+use crate::leader::LeaderSequence;
+use crate::repeat::AltRepeat;
+use crate::tapdance::TapDance;
+use crate::trilayer::TriLayer;
 use core::fmt::Debug;
 use keyberon::action::{
     d, k, l, m, Action, HoldTapAction, HoldTapConfig,
     SequenceEvent::{self, Filter, Press, Release, Restore, Tap},
 };
-use keyberon::key_code::KeyCode::*;
+use keyberon::key_code::KeyCode::{self, *};
 use keyberon::layout::Layout;
 
 /// Keyboard Layout type to mask the number of layers
-pub type KBLayout = Layout<10, 4, 9, CustomEvent>;
+pub type KBLayout = Layout<10, LAYOUT_ROWS, 10, CustomEvent>;
 
 /// Timeout to consider a key as held
 const TIMEOUT: u16 = 200;
 /// Disable tap_hold_interval
 const TAP_HOLD_INTERVAL: u16 = 0;
+/// Maximum time between the presses of the keys of a combo
+const COMBO_TIMEOUT: u16 = 30;
 
-/// Helper to create a HoldTapAction
-macro_rules! ht {
-    ($h:expr, $t:expr) => {
-        Action::HoldTap(&HoldTapAction {
+/// Helper to create a HoldTap decided by the layout engines, in `$mode`,
+/// tapped when held alone if `$retro`, and held only with a key of the other
+/// half if `$bilateral`
+macro_rules! htm {
+    ($mode:ident, $retro:expr, $bilateral:expr, $h:expr, $t:expr) => {
+        HoldTap {
             timeout: TIMEOUT,
-            tap_hold_interval: TAP_HOLD_INTERVAL,
-            config: HoldTapConfig::Default,
             hold: $h,
             tap: $t,
-        })
+            mode: HoldTapMode::$mode,
+            retro_tap: $retro,
+            bilateral: $bilateral,
+        }
     };
 }
 
@@ -47,60 +60,108 @@ const L_GAMING: usize = 6;
 const L_CAPS: usize = 7;
 /// QWERTY layer
 const L_QWERTY: usize = 8;
+/// ADJUST layer, while LOWER and RAISE are held
+const L_ADJUST: usize = 9;
 
 /// Win when held, or W
-const HT_W_W: Action<CustomEvent> = ht!(k(LGui), k(W));
+static MOD_W_W: HoldTap = htm!(PermissiveHold, false, true, k(LGui), k(W));
 /// Win when held, or O
-const HT_W_O: Action<CustomEvent> = ht!(k(RGui), k(O));
+static MOD_W_O: HoldTap = htm!(PermissiveHold, false, true, k(RGui), k(O));
 /// Win when held, or Y
-const HT_W_Y: Action<CustomEvent> = ht!(k(RGui), k(Y));
+static MOD_W_Y: HoldTap = htm!(PermissiveHold, false, true, k(RGui), k(Y));
 /// Left Control when held, or A
-const HT_C_A: Action<CustomEvent> = ht!(k(LCtrl), k(A));
+static MOD_C_A: HoldTap = htm!(Balanced, true, true, k(LCtrl), k(A));
 /// Right Control when held, or SemiColon
-const HT_C_SC: Action<CustomEvent> = ht!(k(RCtrl), k(SColon));
+static MOD_C_SC: HoldTap = htm!(Balanced, true, true, k(RCtrl), k(SColon));
 /// Right Control when held, or O
-const HT_C_O: Action<CustomEvent> = ht!(k(RCtrl), k(O));
+static MOD_C_O: HoldTap = htm!(Balanced, true, true, k(RCtrl), k(O));
 /// Left Shift when held, or Z
-const HT_S_Z: Action<CustomEvent> = ht!(k(LShift), k(Z));
+static MOD_S_Z: HoldTap = htm!(PermissiveHold, true, true, k(LShift), k(Z));
 /// Right Shift when held, or Slash
-const HT_S_SL: Action<CustomEvent> = ht!(k(RShift), k(Slash));
+static MOD_S_SL: HoldTap = htm!(PermissiveHold, true, true, k(RShift), k(Slash));
 /// Left Alt when held, or X
-const HT_A_X: Action<CustomEvent> = ht!(k(LAlt), k(X));
+static MOD_A_X: HoldTap = htm!(Balanced, false, true, k(LAlt), k(X));
 /// Left Alt when held, or .
-const HT_A_DOT: Action<CustomEvent> = ht!(k(LAlt), k(Dot));
+static MOD_A_DOT: HoldTap = htm!(Balanced, false, true, k(LAlt), k(Dot));
 
-/// Layer 1 (lower) when held, or Space
-const HT_1_SP: Action<CustomEvent> = ht!(l(L_LOWER), k(Space));
+/// Hold tap of `MOD_W_W`
+const HT_W_W: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&MOD_W_W));
+/// Hold tap of `MOD_W_O`
+const HT_W_O: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&MOD_W_O));
+/// Hold tap of `MOD_W_Y`
+const HT_W_Y: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&MOD_W_Y));
+/// Hold tap of `MOD_C_A`
+const HT_C_A: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&MOD_C_A));
+/// Hold tap of `MOD_C_SC`
+const HT_C_SC: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&MOD_C_SC));
+/// Hold tap of `MOD_C_O`
+const HT_C_O: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&MOD_C_O));
+/// Hold tap of `MOD_S_Z`
+const HT_S_Z: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&MOD_S_Z));
+/// Hold tap of `MOD_S_SL`
+const HT_S_SL: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&MOD_S_SL));
+/// Hold tap of `MOD_A_X`
+const HT_A_X: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&MOD_A_X));
+/// Hold tap of `MOD_A_DOT`
+const HT_A_DOT: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&MOD_A_DOT));
 
+/// Layer 1 (lower) when held, or Space
+static LAYER_1_SP: HoldTap = htm!(PermissiveHold, false, false, l(L_LOWER), k(Space));
 /// Layer 2 (raise) when held, or BackSpace
-const HT_2_BS: Action<CustomEvent> = ht!(l(L_RAISE), k(BSpace));
-
+static LAYER_2_BS: HoldTap = htm!(PermissiveHold, false, false, l(L_RAISE), k(BSpace));
 /// Layer 3 (numbers/Fx) when held, or B
-const HT_3_B: Action<CustomEvent> = ht!(l(L_NUM), k(B));
+static LAYER_3_B: HoldTap = htm!(PermissiveHold, false, false, l(L_NUM), k(B));
 /// Layer 3 (numbers/Fx) when held, or N
-const HT_3_N: Action<CustomEvent> = ht!(l(L_NUM), k(N));
+static LAYER_3_N: HoldTap = htm!(PermissiveHold, false, false, l(L_NUM), k(N));
 /// Layer 3 (numbers/Fx) when held, or V
-const HT_3_V: Action<CustomEvent> = ht!(l(L_NUM), k(V));
+static LAYER_3_V: HoldTap = htm!(PermissiveHold, false, false, l(L_NUM), k(V));
 /// Layer 3 (numbers/Fx) when held, or J
-const HT_3_J: Action<CustomEvent> = ht!(l(L_NUM), k(J));
+static LAYER_3_J: HoldTap = htm!(PermissiveHold, false, false, l(L_NUM), k(J));
 /// Layer 3 (numbers/Fx) when held, or RAlt
-const HT_3_RA: Action<CustomEvent> = ht!(l(L_NUM), k(RAlt));
+static LAYER_3_RA: HoldTap = htm!(PermissiveHold, false, false, l(L_NUM), k(RAlt));
 /// Layer 3 (numbers/Fx) when held, or Escape
-const HT_3_ESC: Action<CustomEvent> = ht!(l(L_NUM), k(Escape));
-
+static LAYER_3_ESC: HoldTap = htm!(PermissiveHold, false, false, l(L_NUM), k(Escape));
 /// Layer 4 (misc) when held, or T
-const HT_4_T: Action<CustomEvent> = ht!(l(L_MISC), k(T));
+static LAYER_4_T: HoldTap = htm!(PermissiveHold, false, false, l(L_MISC), k(T));
 /// Layer 4 (misc) when held, or Y
-const HT_4_Y: Action<CustomEvent> = ht!(l(L_MISC), k(Y));
+static LAYER_4_Y: HoldTap = htm!(PermissiveHold, false, false, l(L_MISC), k(Y));
 /// Layer 4 (misc) when held, or B
-const HT_4_B: Action<CustomEvent> = ht!(l(L_MISC), k(B));
+static LAYER_4_B: HoldTap = htm!(PermissiveHold, false, false, l(L_MISC), k(B));
 /// Layer 4 (misc) when held, or K
-const HT_4_K: Action<CustomEvent> = ht!(l(L_MISC), k(K));
-
+static LAYER_4_K: HoldTap = htm!(PermissiveHold, false, false, l(L_MISC), k(K));
 /// Layer 5 (tmux) when held, or F
-const HT_5_F: Action<CustomEvent> = ht!(l(L_TMUX), k(F));
+static LAYER_5_F: HoldTap = htm!(PermissiveHold, false, false, l(L_TMUX), k(F));
 /// Layer 5 (tmux) when held, or T
-const HT_5_T: Action<CustomEvent> = ht!(l(L_TMUX), k(T));
+static LAYER_5_T: HoldTap = htm!(PermissiveHold, false, false, l(L_TMUX), k(T));
+
+/// Hold tap of `LAYER_1_SP`
+const HT_1_SP: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_1_SP));
+/// Hold tap of `LAYER_2_BS`
+const HT_2_BS: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_2_BS));
+/// Hold tap of `LAYER_3_B`
+const HT_3_B: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_3_B));
+/// Hold tap of `LAYER_3_N`
+const HT_3_N: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_3_N));
+/// Hold tap of `LAYER_3_V`
+const HT_3_V: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_3_V));
+/// Hold tap of `LAYER_3_J`
+const HT_3_J: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_3_J));
+/// Hold tap of `LAYER_3_RA`
+const HT_3_RA: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_3_RA));
+/// Hold tap of `LAYER_3_ESC`
+const HT_3_ESC: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_3_ESC));
+/// Hold tap of `LAYER_4_T`
+const HT_4_T: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_4_T));
+/// Hold tap of `LAYER_4_Y`
+const HT_4_Y: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_4_Y));
+/// Hold tap of `LAYER_4_B`
+const HT_4_B: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_4_B));
+/// Hold tap of `LAYER_4_K`
+const HT_4_K: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_4_K));
+/// Hold tap of `LAYER_5_F`
+const HT_5_F: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_5_F));
+/// Hold tap of `LAYER_5_T`
+const HT_5_T: Action<CustomEvent> = Action::Custom(CustomEvent::HoldTap(&LAYER_5_T));
 
 /// Shift-Insert
 const S_INS: Action<CustomEvent> = m(&[LShift, Insert].as_slice());
@@ -325,39 +386,223 @@ const MMC: Action<CustomEvent> = Action::Custom(MouseMiddleClick);
 const MSU: Action<CustomEvent> = Action::Custom(MouseScrollUp);
 /// Mouse scroll down
 const MSD: Action<CustomEvent> = Action::Custom(MouseScrollDown);
+/// Log the statistics of the split link
+const STATS: Action<CustomEvent> = Action::Custom(LinkStats);
+/// Switch between NKRO and 6KRO
+const NKRO: Action<CustomEvent> = Action::Custom(ToggleNkro);
+/// Power down the system
+const PWR: Action<CustomEvent> = Action::Custom(SystemPowerDown);
+/// Put the system to sleep
+const SLEEP: Action<CustomEvent> = Action::Custom(SystemSleep);
+/// Wake the system up
+const WAKE: Action<CustomEvent> = Action::Custom(SystemWakeUp);
+/// Shift for the next key only
+const OS_SFT: Action<CustomEvent> = Action::Custom(OneShotMod(LShift));
+/// Ctrl for the next key only
+const OS_CTL: Action<CustomEvent> = Action::Custom(OneShotMod(LCtrl));
+/// NUMBERS layer for the next key only
+const OS_NUM: Action<CustomEvent> = Action::Custom(OneShotLayer(L_NUM));
+/// Start a leader sequence
+const LEAD: Action<CustomEvent> = Action::Custom(Leader);
+/// Toggle Caps Word
+const CW: Action<CustomEvent> = Action::Custom(CapsWord);
+/// Switch auto-shift on or off
+const ASFT: Action<CustomEvent> = Action::Custom(ToggleAutoShift);
+/// Record macro 1
+const REC1: Action<CustomEvent> = Action::Custom(DynMacroRecord(0));
+/// Record macro 2
+const REC2: Action<CustomEvent> = Action::Custom(DynMacroRecord(1));
+/// Stop recording a macro
+const RSTOP: Action<CustomEvent> = Action::Custom(DynMacroStop);
+/// Play macro 1
+const PLAY1: Action<CustomEvent> = Action::Custom(DynMacroPlay(0));
+/// Play macro 2
+const PLAY2: Action<CustomEvent> = Action::Custom(DynMacroPlay(1));
+/// Repeat the last key
+const REP: Action<CustomEvent> = Action::Custom(Repeat);
+/// Alternate repeat of the last key
+const AREP: Action<CustomEvent> = Action::Custom(CustomEvent::AltRepeat);
+/// Swap the hands while held
+const SWAP: Action<CustomEvent> = Action::Custom(SwapHands);
+/// Swap the hands until toggled again
+const SWAPT: Action<CustomEvent> = Action::Custom(SwapHandsToggle);
+/// Swap the hands for the next key only
+const SWAP1: Action<CustomEvent> = Action::Custom(SwapHandsOneShot);
+/// Swap the hands when held, or Tab, for the left hand to type the keys of
+/// the right one, held as soon as another key is pressed, as the swap-hands
+/// mode decides it
+const HT_SW_TAB: Action<CustomEvent> = Action::HoldTap(&HoldTapAction {
+    timeout: TIMEOUT,
+    tap_hold_interval: TAP_HOLD_INTERVAL,
+    config: HoldTapConfig::HoldOnOtherKeyPress,
+    hold: SWAP,
+    tap: k(Tab),
+});
+
+/// `;` on tap, `:` on double tap, or layer 5 (tmux) when held
+static TD_SCLN: TapDance = TapDance {
+    timeout: TIMEOUT,
+    taps: &[k(SColon), m(&[LShift, SColon].as_slice())],
+    holds: &[l(L_TMUX), l(L_TMUX)],
+};
+/// Tap dance on `;`
+const SCLN: Action<CustomEvent> = Action::Custom(CustomEvent::TapDance(&TD_SCLN));
+
+/// Combos
+pub const COMBOS: &[Combo] = &[
+    // A and R together for Escape
+    Combo {
+        keys: &[(1, 0), (1, 1)],
+        action: k(Escape),
+        timeout: COMBO_TIMEOUT,
+        layers: &[L_COLEMAN, L_CAPS, L_QWERTY],
+    },
+];
+
+/// Home row mods, and the other modifiers on letters: Control and Alt
+/// balanced not to fire on rolls, Shift and Win permissive, Control and
+/// Shift tapped when held alone, all of them held only with a key of the
+/// other half or a thumb key
+pub const HOLD_TAPS: &[&HoldTap] = &[
+    &MOD_W_W,
+    &MOD_W_O,
+    &MOD_W_Y,
+    &MOD_C_A,
+    &MOD_C_SC,
+    &MOD_C_O,
+    &MOD_S_Z,
+    &MOD_S_SL,
+    &MOD_A_X,
+    &MOD_A_DOT,
+    &LAYER_1_SP,
+    &LAYER_2_BS,
+    &LAYER_3_B,
+    &LAYER_3_N,
+    &LAYER_3_V,
+    &LAYER_3_J,
+    &LAYER_3_RA,
+    &LAYER_3_ESC,
+    &LAYER_4_T,
+    &LAYER_4_Y,
+    &LAYER_4_B,
+    &LAYER_4_K,
+    &LAYER_5_F,
+    &LAYER_5_T,
+];
+
+/// Tap dances
+pub const TAP_DANCES: &[&TapDance] = &[&TD_SCLN];
+
+/// Leader sequences, for the commands too rarely used to take a key
+pub const LEADER_SEQUENCES: &[LeaderSequence] = &[
+    LeaderSequence {
+        keys: &[R],
+        action: T_RNM,
+    },
+    LeaderSequence {
+        keys: &[M],
+        action: T_MOV,
+    },
+    LeaderSequence {
+        keys: &[S, T],
+        action: STATS,
+    },
+    LeaderSequence {
+        keys: &[S, L],
+        action: SLEEP,
+    },
+];
+
+/// Key codes shifted by Caps Word in addition to the letters, `-` typing `_`
+pub const CAPS_WORD_SHIFTED: &[KeyCode] = &[Minus];
+
+/// Auto-shift, off until toggled from the MISC layer, leaving alone the keys
+/// held to repeat them, to draw lines or type ellipses
+pub const AUTO_SHIFT: AutoShiftConfig = AutoShiftConfig {
+    timeout: 170,
+    excluded: &[Minus, Equal, Dot],
+};
+
+/// Key overrides
+pub const KEY_OVERRIDES: &[KeyOverride] = &[
+    // Shift-BackSpace for Delete
+    KeyOverride {
+        mods: &[LShift, RShift],
+        key: BSpace,
+        replacement: Delete,
+        suppress_mods: true,
+    },
+    // Shift-, for ;
+    KeyOverride {
+        mods: &[LShift, RShift],
+        key: Comma,
+        replacement: SColon,
+        suppress_mods: true,
+    },
+];
+
+/// Alternate repeats: the opposite page, or the other tmux window
+pub const ALT_REPEATS: &[AltRepeat] = &[
+    AltRepeat {
+        key: PgUp,
+        layers: &[],
+        action: k(PgDown),
+    },
+    AltRepeat {
+        key: PgDown,
+        layers: &[],
+        action: k(PgUp),
+    },
+    AltRepeat {
+        key: N,
+        layers: &[L_TMUX],
+        action: T_PRV,
+    },
+    AltRepeat {
+        key: P,
+        layers: &[L_TMUX],
+        action: T_NXT,
+    },
+];
+
+/// Tri-layer rules
+pub const TRI_LAYERS: &[TriLayer] = &[TriLayer {
+    layers: [L_LOWER, L_RAISE],
+    layer: L_ADJUST,
+}];
 
 #[rustfmt::skip]
-/// Layout
-pub static LAYERS: keyberon::layout::Layers<10, 4, 9, CustomEvent> = keyberon::layout::layout! {
+/// Keys of the layout
+const KEYS: keyberon::layout::Layers<10, 4, 10, CustomEvent> = keyberon::layout::layout! {
    { /* 0: Coleman-DH */
-[  Q         {HT_W_W}   F          P         {HT_4_B}    {HT_4_K}   L         U        {HT_W_Y}     ;        ],
+[  Q         {HT_W_W}   F          P         {HT_4_B}    {HT_4_K}   L         U        {HT_W_Y}    {SCLN}    ],
 [ {HT_C_A}    R         S         {HT_5_T}    G           M        {HT_3_N}   E         I          {HT_C_O}  ],
 [ {HT_S_Z}   {HT_A_X}   C          D         {HT_3_V}    {HT_3_J}   H         ,        {HT_A_DOT}  {HT_S_SL} ],
-[ {VCAPS}    {VNUM}    {HT_3_ESC} {HT_1_SP}   Tab         Enter    {HT_2_BS} {HT_3_RA}  n           n        ],
+[ {VCAPS}    {VNUM}    {HT_3_ESC} {HT_1_SP}  {HT_SW_TAB}  Enter    {HT_2_BS} {HT_3_RA}  n           n        ],
     } { /* 1: LOWER */
         [ !  #  $    '(' ')'      ^       &      {S_INS}    *      ~   ],
         [ =  -  '`'  '{' '}'      n       n       PgUp    PgDown  '\\' ],
         [ @  &  %    '[' ']'      n       n         n      '\''    '"' ],
-        [ t  t  n     n   n       Enter  Space   Delete     t      t   ],
+        [ t  t {OS_SFT} {OS_NUM} {OS_CTL} Enter   t     Delete     t      t   ],
     } { /* 2: RAISE */
-        [ {QWERTY}  n    {E_ACU}  {E_CIR}  {E_GRV}      PgUp   {U_GRV}  {I_CIR}  {O_CIR}  Home  ],
+        [ {QWERTY} {CW}  {E_ACU}  {E_CIR}  {E_GRV}      PgUp   {U_GRV}  {I_CIR}  {O_CIR}  Home  ],
         [ {A_GRV}  '_'    +        &        |           RAlt    Left     Up       Down    Right ],
         [ {EURO}   {OE}  {C_CED}  {CAPS}   {NUMLCK}     PgDown  Menu    PScreen  {DOTS}   End   ],
-        [ t         t    Escape   BSpace    Tab         n       n        n        t       t     ],
+        [ t         t    Escape    t        Tab        {REP}    n      {AREP}     t       t     ],
     } { /* 3: NUMBERS Fx */
         [ .  4  5   6         =         /    F1         F2   F3   F4   ],
         [ 0  1  2   3         -         *    F5         F6   F7   F8   ],
         [ ,  7  8   9         +         +    F9         F10  F11  F12  ],
         [ t {VUNNUM} {UNNUM}  {HT_1_SP} Tab     Enter  {HT_2_BS}    n    t    t   ],
     } { /* 4: MISC and Mouse */
-        [ Pause  {GAME}           {COLEMAN}    {QWERTY}      n      {MSU}  n      n     n     n   ],
-        [ n      VolDown          Mute         VolUp         n       n    {ML}  {MD}   {MU}  {MR} ],
-        [ n MediaPreviousSong  MediaPlayPause MediaNextSong  n      {MSD}  n      n     n     n   ],
+        [ Pause  {GAME}           {COLEMAN}    {QWERTY}  {STATS}  {MSU} {REC1} {REC2} {RSTOP} {NKRO}],
+        [ {PWR}  VolDown          Mute         VolUp      {SLEEP}  n      {ML}  {MD}   {MU}  {MR} ],
+        [ {ASFT} MediaPreviousSong MediaPlayPause MediaNextSong {WAKE} {MSD} {PLAY1} {PLAY2} n n ],
         [ t      t                {MLC}        {MMC}      {MRC}     {MLC} {MMC}  {MRC}  t     t   ],
     } { /* 5: TMUX */
         [ {T_6}   {T_7} {T_8}   {T_9}   {T_0}      {T_1}   {T_2}  {T_3}   {T_4}   {T_5}   ],
         [ {T_LST}  n     n       n       n          n     {T_PRV} {T_UP}  {T_DWN} {T_NXT} ],
-        [  n       n    {T_NEW} {T_CPY} {T_PST}     n       n     {T_RNM} {T_MOV} {T_PST} ],
+        [  n       n    {T_NEW} {T_CPY} {T_PST}     n       n     {LEAD}   n      {T_PST} ],
         [  t       t     n       n       n         {T_CMD}  n      n       t       t      ],
     } { /* 6: Gaming */
         [ Q  W  E   R         T              {HT_4_Y} U          I  {HT_W_O}     P       ],
@@ -373,6 +618,22 @@ pub static LAYERS: keyberon::layout::Layers<10, 4, 9, CustomEvent> = keyberon::l
 [  Q         {HT_W_W}   E       R         {HT_4_T}       {HT_4_Y}   U         I    {HT_W_O}     P        ],
 [ {HT_C_A}    S         D      {HT_5_F}    G              H         J         K     L          {HT_C_SC} ],
 [ {HT_S_Z}   {HT_A_X}   C       V         {HT_3_B}       {HT_3_N}   M         ,    {HT_A_DOT}  {HT_S_SL} ],
-[  t          t        Escape  {HT_1_SP}   Tab           Enter    {HT_2_BS}  RAlt   t           t        ],
+[  t          t        Escape  {HT_1_SP}  {HT_SW_TAB}    Enter    {HT_2_BS}  RAlt   t           t        ],
+    } { /* 9: ADJUST */
+        [ {QWERTY} {GAME} {COLEMAN}  n      {STATS}    n       {REC1}  {REC2}  {RSTOP} {NKRO} ],
+        [ {PWR}     n     {CW}      {ASFT}  {SLEEP}    n       {PLAY1} {PLAY2}  n       n     ],
+        [ {SWAPT} {SWAP1}  n         n      {WAKE}     n        n       n       n       n     ],
+        [  t        t      n         t       n         n        t       n       t       t     ],
     }
 };
+
+/// Layout, with the virtual keys of the layout engines
+pub static LAYERS: keyberon::layout::Layers<10, LAYOUT_ROWS, 10, CustomEvent> = with_virtual_keys(
+    &KEYS,
+    COMBOS,
+    HOLD_TAPS,
+    TAP_DANCES,
+    LEADER_SEQUENCES,
+    ALT_REPEATS,
+    TRI_LAYERS,
+);
@@ -1,14 +1,22 @@
-use crate::layout::CustomEvent;
+use crate::autoshift::AutoShiftConfig;
+use crate::combos::Combo;
+use crate::holdtap::HoldTap;
+use crate::keyoverride::KeyOverride;
+use crate::layout::{with_virtual_keys, CustomEvent, LAYOUT_ROWS};
+use crate::leader::LeaderSequence;
+use crate::repeat::AltRepeat;
+use crate::tapdance::TapDance;
+use crate::trilayer::TriLayer;
 use core::fmt::Debug;
 use keyberon::action::{
     Action,
     SequenceEvent::{self, *},
 };
-use keyberon::key_code::KeyCode::*;
+use keyberon::key_code::KeyCode::{self, *};
 use keyberon::layout::Layout;
 
 /// Keyboard Layout type to mask the number of layers
-pub type KBLayout = Layout<10, 4, 2, CustomEvent>;
+pub type KBLayout = Layout<10, LAYOUT_ROWS, 2, CustomEvent>;
 
 /// A shortcut to create a `Action::Sequence`, useful to
 /// create compact layout.
@@ -25,9 +33,39 @@ const QQ: Action<CustomEvent> = seq(&[Tap(Q), Tap(W), Tap(E)].as_slice());
 /// write `aze`
 const AA: Action<CustomEvent> = seq(&[Tap(A), Tap(Z), Tap(E)].as_slice());
 
+/// Combos
+pub const COMBOS: &[Combo] = &[];
+
+/// Hold taps decided by the layout engines
+pub const HOLD_TAPS: &[&HoldTap] = &[];
+
+/// Tap dances
+pub const TAP_DANCES: &[&TapDance] = &[];
+
+/// Leader sequences
+pub const LEADER_SEQUENCES: &[LeaderSequence] = &[];
+
+/// Key codes shifted by Caps Word in addition to the letters
+pub const CAPS_WORD_SHIFTED: &[KeyCode] = &[];
+
+/// Auto-shift, off until toggled
+pub const AUTO_SHIFT: AutoShiftConfig = AutoShiftConfig {
+    timeout: 170,
+    excluded: &[],
+};
+
+/// Key overrides
+pub const KEY_OVERRIDES: &[KeyOverride] = &[];
+
+/// Alternate repeats
+pub const ALT_REPEATS: &[AltRepeat] = &[];
+
+/// Tri-layer rules
+pub const TRI_LAYERS: &[TriLayer] = &[];
+
 #[rustfmt::skip]
-/// Layout
-pub static LAYERS: keyberon::layout::Layers<10, 4, 2, CustomEvent> = keyberon::layout::layout! {
+/// Keys of the layout
+const KEYS: keyberon::layout::Layers<10, 4, 2, CustomEvent> = keyberon::layout::layout! {
     { // 0: Base Layer
         [ {QQ}  W   E   R  T      Y  U  I  O  P ],
         [  A   S   D   F  G      H  J  K  L  ; ],
@@ -40,3 +78,14 @@ pub static LAYERS: keyberon::layout::Layers<10, 4, 2, CustomEvent> = keyberon::l
         [  n   n  n     n  RAlt   Escape  Delete  n       n      n   ],
     }
 };
+
+/// Layout, with the virtual keys of the layout engines
+pub static LAYERS: keyberon::layout::Layers<10, LAYOUT_ROWS, 2, CustomEvent> = with_virtual_keys(
+    &KEYS,
+    COMBOS,
+    HOLD_TAPS,
+    TAP_DANCES,
+    LEADER_SEQUENCES,
+    ALT_REPEATS,
+    TRI_LAYERS,
+);
@@ -0,0 +1,185 @@
+use crate::hid::KbReport;
+use keyberon::key_code::KeyCode;
+
+/// Key sending another keycode while some modifiers are held
+#[derive(Debug, Clone, Copy)]
+pub struct KeyOverride {
+    /// Modifiers triggering the override, any of them
+    pub mods: &'static [KeyCode],
+    /// Key overridden
+    pub key: KeyCode,
+    /// Keycode sent instead of the key
+    pub replacement: KeyCode,
+    /// Whether the modifiers are released while the replacement is sent
+    pub suppress_mods: bool,
+}
+
+impl KeyOverride {
+    /// Whether any of the modifiers of the override is held in `report`
+    fn mods_held(&self, report: &KbReport) -> bool {
+        self.mods.iter().any(|&m| report.is_pressed(m))
+    }
+}
+
+/// State of the override of the key held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Latch {
+    /// The override at this index is sent until its key is released
+    Active(usize),
+    /// The key is sent as is until released, another key being pressed
+    /// while it was overridden
+    Cancelled(KeyCode),
+}
+
+/// Key overrides, latched when their key is pressed until it is released
+///
+/// Only one override is active at a time, and it is cancelled when another
+/// key is pressed, so that the modifiers it suppresses apply to that key.
+pub struct KeyOverrides {
+    /// Overrides of the keymap, the first one matching a key wins
+    overrides: &'static [KeyOverride],
+    /// Override of the key held, if any
+    latch: Option<Latch>,
+    /// Keyboard report of the previous tick, before the overrides
+    previous: KbReport,
+}
+
+impl KeyOverrides {
+    /// Create a new key override engine
+    pub fn new(overrides: &'static [KeyOverride]) -> Self {
+        KeyOverrides {
+            overrides,
+            latch: None,
+            previous: KbReport::default(),
+        }
+    }
+
+    /// Apply the key overrides to a keyboard report
+    pub fn apply(&mut self, report: &mut KbReport) {
+        let keys = *report;
+        let previous = core::mem::replace(&mut self.previous, keys);
+        let new_key = keys.new_key(&previous).is_some();
+        self.latch = match self.latch {
+            Some(Latch::Active(index)) => {
+                let key = self.overrides[index].key;
+                if !keys.is_pressed(key) {
+                    None
+                } else if new_key {
+                    Some(Latch::Cancelled(key))
+                } else {
+                    Some(Latch::Active(index))
+                }
+            }
+            Some(Latch::Cancelled(key)) if keys.is_pressed(key) => Some(Latch::Cancelled(key)),
+            _ => None,
+        };
+        if self.latch.is_none() && new_key {
+            self.latch = self
+                .overrides
+                .iter()
+                .position(|o| {
+                    keys.is_pressed(o.key) && !previous.is_pressed(o.key) && o.mods_held(&keys)
+                })
+                .map(Latch::Active);
+        }
+        let Some(Latch::Active(index)) = self.latch else {
+            return;
+        };
+        let o = &self.overrides[index];
+        report.release(o.key);
+        report.press(o.replacement);
+        if o.suppress_mods {
+            for &m in o.mods {
+                report.release(m);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shift-BackSpace for Delete
+    static OVERRIDES: [KeyOverride; 1] = [KeyOverride {
+        mods: &[KeyCode::LShift, KeyCode::RShift],
+        key: KeyCode::BSpace,
+        replacement: KeyCode::Delete,
+        suppress_mods: true,
+    }];
+
+    /// Apply the overrides to the report made of `keycodes`
+    fn apply(key_overrides: &mut KeyOverrides, keycodes: &[KeyCode]) -> KbReport {
+        let mut keys = report(keycodes);
+        key_overrides.apply(&mut keys);
+        keys
+    }
+
+    /// Report made of `keycodes`
+    fn report(keycodes: &[KeyCode]) -> KbReport {
+        let mut report = KbReport::default();
+        for &kc in keycodes {
+            report.press(kc);
+        }
+        report
+    }
+
+    #[test]
+    fn overridden() {
+        let mut key_overrides = KeyOverrides::new(&OVERRIDES);
+        apply(&mut key_overrides, &[KeyCode::LShift]);
+        assert_eq!(
+            apply(&mut key_overrides, &[KeyCode::LShift, KeyCode::BSpace]),
+            report(&[KeyCode::Delete])
+        );
+    }
+
+    #[test]
+    fn latched_until_released() {
+        let mut key_overrides = KeyOverrides::new(&OVERRIDES);
+        apply(&mut key_overrides, &[KeyCode::LShift, KeyCode::BSpace]);
+        // Shift released before the key
+        assert_eq!(
+            apply(&mut key_overrides, &[KeyCode::BSpace]),
+            report(&[KeyCode::Delete])
+        );
+        apply(&mut key_overrides, &[]);
+        assert_eq!(
+            apply(&mut key_overrides, &[KeyCode::BSpace]),
+            report(&[KeyCode::BSpace])
+        );
+    }
+
+    #[test]
+    fn mods_pressed_after_the_key() {
+        let mut key_overrides = KeyOverrides::new(&OVERRIDES);
+        apply(&mut key_overrides, &[KeyCode::BSpace]);
+        assert_eq!(
+            apply(&mut key_overrides, &[KeyCode::LShift, KeyCode::BSpace]),
+            report(&[KeyCode::LShift, KeyCode::BSpace])
+        );
+    }
+
+    #[test]
+    fn other_key_pressed() {
+        let mut key_overrides = KeyOverrides::new(&OVERRIDES);
+        apply(&mut key_overrides, &[KeyCode::LShift, KeyCode::BSpace]);
+        // Shift applies to the other key, cancelling the override
+        let keys = [KeyCode::LShift, KeyCode::BSpace, KeyCode::A];
+        assert_eq!(apply(&mut key_overrides, &keys), report(&keys));
+        assert_eq!(apply(&mut key_overrides, &keys), report(&keys));
+    }
+
+    #[test]
+    fn other_key_held() {
+        let mut key_overrides = KeyOverrides::new(&OVERRIDES);
+        apply(&mut key_overrides, &[KeyCode::LShift, KeyCode::A]);
+        assert_eq!(
+            apply(
+                &mut key_overrides,
+                &[KeyCode::LShift, KeyCode::A, KeyCode::BSpace]
+            ),
+            report(&[KeyCode::A, KeyCode::Delete])
+        );
+    }
+}
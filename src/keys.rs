@@ -1,7 +1,7 @@
 use crate::layout::LAYOUT_CHANNEL;
-use crate::side::{is_host, SIDE_CHANNEL};
+use crate::side::{role, Role, SIDE_CHANNEL};
 use embassy_stm32::gpio::Input;
-use embassy_time::{Duration, Ticker};
+use embassy_time::{Duration, Instant, Ticker};
 use keyberon::debounce::Debouncer;
 use keyberon::layout::Event;
 
@@ -59,16 +59,18 @@ pub async fn matrix_scanner(matrix: Matrix<'_>) {
     let mut debouncer = Debouncer::new(matrix_state_new(), matrix_state_new(), NB_BOUNCE);
 
     loop {
-        let is_host = is_host();
+        let role = role();
         for event in debouncer
             .events(scan_matrix(&matrix))
             .map(transform_keypress_coordinates)
         {
-            if is_host {
-                LAYOUT_CHANNEL.send(event).await;
-            } else {
-                SIDE_CHANNEL.send(event).await;
-            };
+            match role {
+                Role::Master => LAYOUT_CHANNEL.send((event, Instant::now())).await,
+                Role::Secondary => SIDE_CHANNEL.send(event).await,
+                // No half is connected to USB, the event would be stale
+                // when replayed
+                Role::Undecided => (),
+            }
         }
 
         ticker.next().await;
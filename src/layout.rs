@@ -1,31 +1,208 @@
-use crate::hid::{HID_KB_CHANNEL, HID_MOUSE_CHANNEL};
+use crate::autoshift::AutoShift;
+use crate::capsword::CapsWord;
+use crate::combos::{Combo, Combos};
+use crate::dynmacro::DynMacros;
+use crate::hid::{
+    consumer_usage, toggle_nkro, ConsumerReport, KbReport, SystemControl, HID_CONSUMER_CHANNEL,
+    HID_KB_CHANNEL, HID_MOUSE_CHANNEL, HID_SYSTEM_CHANNEL, REMOTE_WAKEUP,
+};
+use crate::holdtap::{HoldTap, HoldTaps};
+use crate::keyoverride::KeyOverrides;
+use crate::leader::{Leader, LeaderSequence, MAX_LEADER_KEYS};
 use crate::mouse::MouseHandler;
+use crate::oneshot::OneShots;
+use crate::repeat::{AltRepeat, Repeat};
+use crate::side::{is_host, update_host_state};
+use crate::stats::link_stats;
+use crate::swaphands::SwapHands;
+use crate::tapdance::{TapDance, TapDances};
+use crate::trilayer::{TriLayer, TriLayers};
 use embassy_futures::select::{select, Either};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::{Duration, Ticker};
-use keyberon::layout::{Event, Layout};
-use usbd_hid::descriptor::KeyboardReport;
+use embassy_time::{Duration, Instant, Ticker};
+use heapless::Vec;
+use keyberon::action::{k, l, Action};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{CustomEvent as KbCustomEvent, Event, Layers, Layout};
+use usbd_hid::descriptor::MouseReport;
 
 /// Basic layout for the keyboard
 #[cfg(feature = "keymap_basic")]
-use crate::keymap_basic::{KBLayout, LAYERS};
+use crate::keymap_basic::{
+    KBLayout, ALT_REPEATS, AUTO_SHIFT, CAPS_WORD_SHIFTED, COMBOS, HOLD_TAPS, KEY_OVERRIDES, LAYERS,
+    LEADER_SEQUENCES, TAP_DANCES, TRI_LAYERS,
+};
 
 /// Keymap by Boris Faure
 #[cfg(feature = "keymap_borisfaure")]
-use crate::keymap_borisfaure::{KBLayout, LAYERS};
+use crate::keymap_borisfaure::{
+    KBLayout, ALT_REPEATS, AUTO_SHIFT, CAPS_WORD_SHIFTED, COMBOS, HOLD_TAPS, KEY_OVERRIDES, LAYERS,
+    LEADER_SEQUENCES, TAP_DANCES, TRI_LAYERS,
+};
 
 /// Test layout for the keyboard
 #[cfg(feature = "keymap_test")]
-use crate::keymap_test::{KBLayout, LAYERS};
+use crate::keymap_test::{
+    KBLayout, ALT_REPEATS, AUTO_SHIFT, CAPS_WORD_SHIFTED, COMBOS, HOLD_TAPS, KEY_OVERRIDES, LAYERS,
+    LEADER_SEQUENCES, TAP_DANCES, TRI_LAYERS,
+};
+
+/// Number of columns of the layout
+pub const COLS: usize = 10;
+/// Number of rows of the keyboard matrix
+pub const MATRIX_ROWS: usize = 4;
+/// First column of the right half, the events of the right half being
+/// mirrored
+pub const RIGHT_FIRST_COL: u8 = 5;
+/// Row of the thumb keys
+pub const THUMB_ROW: u8 = MATRIX_ROWS as u8 - 1;
+/// Number of rows of the layers: the keyboard matrix followed by the rows of
+/// virtual keys, pressed by the layout engines
+pub const LAYOUT_ROWS: usize = TAP_ROW as usize + HOLD_TAP_ROWS as usize;
+/// Row of the virtual keys pressed by the combos
+pub const COMBO_ROW: u8 = 4;
+/// Row of the virtual keys of the one-shot modifiers, from `LCtrl` to `RGui`
+pub const ONESHOT_MOD_ROW: u8 = 5;
+/// Row of the virtual keys of the one-shot layers, one per layer
+pub const ONESHOT_LAYER_ROW: u8 = 6;
+/// Row of the virtual keys of the actions of the tap dances
+pub const TAP_DANCE_ROW: u8 = 7;
+/// Row of the virtual keys pressed by the leader sequences
+pub const LEADER_ROW: u8 = 8;
+/// Row of the virtual key of Shift pressed by the auto-shift, at column 0
+pub const AUTO_SHIFT_ROW: u8 = 9;
+/// Row of the virtual keys pressed by the Alt-Repeat key
+pub const ALT_REPEAT_ROW: u8 = 10;
+/// Row of the virtual keys pressed by the tri-layer rules
+pub const TRI_LAYER_ROW: u8 = 11;
+/// Number of rows of the virtual keys of the hold actions of the hold taps,
+/// and likewise of their tap actions
+pub const HOLD_TAP_ROWS: u8 = 3;
+/// First row of the virtual keys of the hold actions of the hold taps
+pub const HOLD_ROW: u8 = 12;
+/// First row of the virtual keys of the tap actions of the hold taps
+pub const TAP_ROW: u8 = HOLD_ROW + HOLD_TAP_ROWS;
+
+/// Add the rows of virtual keys to the layers of a keymap
+///
+/// The combo at index `i` presses the key at column `i` of the combo row.
+/// The tap dances have their taps then their holds one after the other in
+/// the tap dance row. The leader sequence at index `i` presses the key at
+/// column `i` of the leader row, and likewise for the alternate repeats and
+/// the tri-layer rules. The hold tap at index `i` has its hold and tap
+/// actions at column `i % COLS` of the hold and tap rows numbered `i / COLS`
+/// from the first ones.
+pub const fn with_virtual_keys<const L: usize>(
+    keys: &Layers<COLS, MATRIX_ROWS, L, CustomEvent>,
+    combos: &[Combo],
+    hold_taps: &[&HoldTap],
+    tap_dances: &[&TapDance],
+    leader_sequences: &[LeaderSequence],
+    alt_repeats: &[AltRepeat],
+    tri_layers: &[TriLayer],
+) -> Layers<COLS, LAYOUT_ROWS, L, CustomEvent> {
+    assert!(combos.len() <= COLS, "Too many combos");
+    assert!(
+        hold_taps.len() <= HOLD_TAP_ROWS as usize * COLS,
+        "Too many hold taps"
+    );
+    assert!(leader_sequences.len() <= COLS, "Too many leader sequences");
+    assert!(alt_repeats.len() <= COLS, "Too many alternate repeats");
+    assert!(tri_layers.len() <= COLS, "Too many tri-layer rules");
+    assert!(L <= COLS, "Too many layers for the one-shot layers");
+    let mut virtual_keys = [[Action::NoOp; COLS]; LAYOUT_ROWS];
+    let mut i = 0;
+    while i < combos.len() {
+        virtual_keys[COMBO_ROW as usize][i] = combos[i].action;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < hold_taps.len() {
+        virtual_keys[HOLD_ROW as usize + i / COLS][i % COLS] = hold_taps[i].hold;
+        virtual_keys[TAP_ROW as usize + i / COLS][i % COLS] = hold_taps[i].tap;
+        i += 1;
+    }
+    let mut column = 0;
+    let mut i = 0;
+    while i < tap_dances.len() {
+        let actions = [tap_dances[i].taps, tap_dances[i].holds];
+        let mut a = 0;
+        while a < actions.len() {
+            let mut n = 0;
+            while n < actions[a].len() {
+                assert!(column < COLS, "Too many tap dance actions");
+                virtual_keys[TAP_DANCE_ROW as usize][column] = actions[a][n];
+                column += 1;
+                n += 1;
+            }
+            a += 1;
+        }
+        i += 1;
+    }
+    let mut i = 0;
+    while i < leader_sequences.len() {
+        assert!(
+            leader_sequences[i].keys.len() <= MAX_LEADER_KEYS,
+            "Leader sequence too long"
+        );
+        virtual_keys[LEADER_ROW as usize][i] = leader_sequences[i].action;
+        i += 1;
+    }
+    let mods = [
+        KeyCode::LCtrl,
+        KeyCode::LShift,
+        KeyCode::LAlt,
+        KeyCode::LGui,
+        KeyCode::RCtrl,
+        KeyCode::RShift,
+        KeyCode::RAlt,
+        KeyCode::RGui,
+    ];
+    let mut i = 0;
+    while i < mods.len() {
+        virtual_keys[ONESHOT_MOD_ROW as usize][i] = k(mods[i]);
+        i += 1;
+    }
+    let mut i = 0;
+    while i < L {
+        virtual_keys[ONESHOT_LAYER_ROW as usize][i] = l(i);
+        i += 1;
+    }
+    let mut i = 0;
+    while i < alt_repeats.len() {
+        virtual_keys[ALT_REPEAT_ROW as usize][i] = alt_repeats[i].action;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < tri_layers.len() {
+        assert!(tri_layers[i].layer < L, "Tri-layer missing from the layers");
+        virtual_keys[TRI_LAYER_ROW as usize][i] = l(tri_layers[i].offset());
+        i += 1;
+    }
+    virtual_keys[AUTO_SHIFT_ROW as usize][0] = k(KeyCode::LShift);
+    let mut layers = [virtual_keys; L];
+    let mut i = 0;
+    while i < L {
+        let mut r = 0;
+        while r < MATRIX_ROWS {
+            layers[i][r] = keys[i][r];
+            r += 1;
+        }
+        i += 1;
+    }
+    layers
+}
 
 /// Layout refresh rate, in ms
 const REFRESH_RATE_MS: u64 = 1;
 /// Number of events in the layout channel
 const NB_EVENTS: usize = 64;
-/// Channel to send `keyberon::layout::event` events to the layout handler
-pub static LAYOUT_CHANNEL: Channel<CriticalSectionRawMutex, Event, NB_EVENTS> = Channel::new();
+/// Channel to send `keyberon::layout::event` events to the layout handler,
+/// with the time they happened at
+pub static LAYOUT_CHANNEL: Channel<CriticalSectionRawMutex, (Event, Instant), NB_EVENTS> =
+    Channel::new();
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 /// Custom events for the layout, mostly mouse events
 #[allow(clippy::enum_variant_names)]
 #[allow(dead_code)]
@@ -48,63 +225,470 @@ pub enum CustomEvent {
     MouseScrollUp,
     /// Mouse scroll down
     MouseScrollDown,
+    /// Log the statistics of the split link
+    LinkStats,
+    /// Switch between NKRO and 6KRO
+    ToggleNkro,
+    /// System power down
+    SystemPowerDown,
+    /// System sleep
+    SystemSleep,
+    /// System wake up
+    SystemWakeUp,
+    /// Apply a modifier to the next key only
+    OneShotMod(KeyCode),
+    /// Activate a layer for the next key only
+    OneShotLayer(usize),
+    /// Action depending on whether its key is tapped or held
+    HoldTap(&'static HoldTap),
+    /// Action depending on the number of taps
+    TapDance(&'static TapDance),
+    /// Start a leader sequence
+    Leader,
+    /// Toggle Caps Word
+    CapsWord,
+    /// Switch auto-shift on or off
+    ToggleAutoShift,
+    /// Record a macro in a slot
+    DynMacroRecord(usize),
+    /// Stop recording a macro
+    DynMacroStop,
+    /// Play the macro of a slot
+    DynMacroPlay(usize),
+    /// Repeat the last key
+    Repeat,
+    /// Send the alternate repeat of the last key
+    AltRepeat,
+    /// Swap the hands while held
+    SwapHands,
+    /// Swap the hands until toggled again
+    SwapHandsToggle,
+    /// Swap the hands for the next key only
+    SwapHandsOneShot,
 }
 
-/// Set a report as an error based on keycode `kc`
-fn keyboard_report_set_error(report: &mut KeyboardReport, kc: keyberon::key_code::KeyCode) {
-    report.modifier = 0;
-    report.keycodes = [kc as u8; 6];
-    defmt::error!("Error: {:?}", defmt::Debug2Format(&kc));
+impl CustomEvent {
+    /// System control usage sent for the event, if any
+    fn system_control(&self) -> Option<SystemControl> {
+        match self {
+            CustomEvent::SystemPowerDown => Some(SystemControl::PowerDown),
+            CustomEvent::SystemSleep => Some(SystemControl::Sleep),
+            CustomEvent::SystemWakeUp => Some(SystemControl::WakeUp),
+            _ => None,
+        }
+    }
 }
 
-/// Generate a HID report from the current layout
-fn generate_hid_kb_report(layout: &mut KBLayout) -> KeyboardReport {
-    let mut report = KeyboardReport::default();
+/// Generate the keyboard and consumer control HID reports from the current
+/// layout, with the key overrides, Caps Word and the Repeat key applied
+fn generate_hid_kb_reports(
+    layout: &mut KBLayout,
+    key_overrides: &mut KeyOverrides,
+    caps_word: &mut CapsWord,
+    repeat: &mut Repeat,
+) -> (KbReport, ConsumerReport) {
+    let mut kb_report = KbReport::default();
+    let mut consumer_report = ConsumerReport::default();
     for kc in layout.keycodes() {
-        use keyberon::key_code::KeyCode::*;
-        match kc {
-            No => (),
-            ErrorRollOver | PostFail | ErrorUndefined => keyboard_report_set_error(&mut report, kc),
-            kc if kc.is_modifier() => report.modifier |= kc.as_modifier_bit(),
-            _ => report.keycodes[..]
-                .iter_mut()
-                .find(|c| **c == 0)
-                .map(|c| *c = kc as u8)
-                .unwrap_or_else(|| keyboard_report_set_error(&mut report, ErrorRollOver)),
+        match consumer_usage(kc) {
+            Some(usage) => consumer_report.press(usage),
+            None => kb_report.press(kc),
+        }
+    }
+    key_overrides.apply(&mut kb_report);
+    let keycodes = layout.keycodes().filter(|&kc| consumer_usage(kc).is_none());
+    caps_word.apply(keycodes, &mut kb_report);
+    repeat.apply(&mut kb_report);
+    (kb_report, consumer_report)
+}
+
+/// Layers the actions of the keys are read from
+#[derive(Debug, Clone, Copy)]
+struct ActiveLayers {
+    /// Current layer
+    current: usize,
+    /// Default layer, for the transparent keys
+    default: usize,
+}
+
+impl ActiveLayers {
+    /// Layers of the layout
+    fn of(layout: &KBLayout) -> Self {
+        ActiveLayers {
+            current: layout.current_layer(),
+            default: layout.default_layer,
+        }
+    }
+}
+
+/// Action of the key of an event, on the current layer
+///
+/// Like in the layout, a transparent key has the action of the default
+/// layer, and none if transparent there too.
+fn action_at(layers: ActiveLayers, event: Event) -> &'static Action<CustomEvent> {
+    let (i, j) = event.coord();
+    match &LAYERS[layers.current][i as usize][j as usize] {
+        Action::Trans if layers.current != layers.default => action_at(
+            ActiveLayers {
+                current: layers.default,
+                ..layers
+            },
+            event,
+        ),
+        Action::Trans => &Action::NoOp,
+        action => action,
+    }
+}
+
+/// Maximum number of events output at once by a layout engine
+const MAX_ENGINE_EVENTS: usize = 16;
+/// Events output by a layout engine, to be sent to the next one
+type Events = Vec<Event, MAX_ENGINE_EVENTS>;
+
+/// Collect the events emitted by a layout engine
+fn collect(f: impl FnOnce(&mut dyn FnMut(Event))) -> Events {
+    let mut events = Events::new();
+    f(&mut |e| {
+        if events.push(e).is_err() {
+            defmt::error!("Too many events output by a layout engine");
+        }
+    });
+    events
+}
+
+/// Send an event to the layout through the tri-layer rules, which may hold
+/// it back
+fn to_layout(
+    event: Event,
+    layers: ActiveLayers,
+    tri_layers: &mut TriLayers,
+    repeat: &mut Repeat,
+    layout: &mut KBLayout,
+) {
+    tri_layers.event(event, action_at(layers, event), |e| {
+        into_layout(e, layers, repeat, layout)
+    });
+}
+
+/// Send an event to the layout, tracking its action for the Repeat key
+fn into_layout(event: Event, layers: ActiveLayers, repeat: &mut Repeat, layout: &mut KBLayout) {
+    repeat.event(event, action_at(layers, event), layers.current);
+    layout.event(event);
+}
+
+/// Layout engines, between the key events and the layout
+///
+/// The events go through the swap-hands mode, the combos, the hold taps, the
+/// tap dances, the leader key, the auto-shift and the one-shot actions, in
+/// that order.
+/// The tri-layer rules are checked once the layout has processed them, and
+/// the actions sent to the layout are tracked for the Repeat key.
+struct Engines {
+    /// Swap-hands mode
+    swap_hands: SwapHands,
+    /// Combos
+    combos: Combos,
+    /// Hold taps
+    hold_taps: HoldTaps,
+    /// Tap dances
+    tap_dances: TapDances,
+    /// Leader key
+    leader: Leader,
+    /// Auto-shift
+    auto_shift: AutoShift,
+    /// One-shot actions
+    oneshots: OneShots,
+    /// Tri-layer rules
+    tri_layers: TriLayers,
+    /// Repeat and Alt-Repeat keys
+    repeat: Repeat,
+}
+
+impl Engines {
+    /// Create the layout engines
+    fn new() -> Self {
+        Engines {
+            swap_hands: SwapHands::new(),
+            combos: Combos::new(COMBOS),
+            hold_taps: HoldTaps::new(HOLD_TAPS),
+            tap_dances: TapDances::new(TAP_DANCES),
+            leader: Leader::new(LEADER_SEQUENCES),
+            auto_shift: AutoShift::new(AUTO_SHIFT),
+            oneshots: OneShots::new(),
+            tri_layers: TriLayers::new(TRI_LAYERS),
+            repeat: Repeat::new(ALT_REPEATS),
+        }
+    }
+
+    /// Process a key event, that happened at `time`, sending the resulting
+    /// events to the layout
+    fn event(&mut self, event: Event, time: Instant, layout: &mut KBLayout) {
+        let layers = ActiveLayers::of(layout);
+        let event = self.swap_hands.event(event, |e| action_at(layers, e));
+        let events = collect(|emit| self.combos.event(event, layers.current, emit));
+        self.after_combos(events, time, layers, layout);
+    }
+
+    /// Send the events output by the combos, at `time`, to the hold taps
+    fn after_combos(
+        &mut self,
+        events: Events,
+        time: Instant,
+        layers: ActiveLayers,
+        layout: &mut KBLayout,
+    ) {
+        for event in events {
+            let action = action_at(layers, event);
+            let events = collect(|emit| self.hold_taps.event(event, time, action, emit));
+            self.after_hold_taps(events, layers, layout);
+        }
+    }
+
+    /// Send the events output by the hold taps to the tap dances
+    fn after_hold_taps(&mut self, events: Events, layers: ActiveLayers, layout: &mut KBLayout) {
+        for event in events {
+            let events =
+                collect(|emit| self.tap_dances.event(event, action_at(layers, event), emit));
+            self.after_tap_dances(events, layers, layout);
+        }
+    }
+
+    /// Send the events output by the tap dances to the leader key
+    fn after_tap_dances(&mut self, events: Events, layers: ActiveLayers, layout: &mut KBLayout) {
+        for event in events {
+            let events = collect(|emit| self.leader.event(event, action_at(layers, event), emit));
+            self.after_leader(events, layers, layout);
+        }
+    }
+
+    /// Send the events output by the leader key to the auto-shift
+    fn after_leader(&mut self, events: Events, layers: ActiveLayers, layout: &mut KBLayout) {
+        for event in events {
+            let events =
+                collect(|emit| self.auto_shift.event(event, action_at(layers, event), emit));
+            self.after_auto_shift(events, layers, layout);
+        }
+    }
+
+    /// Send the events output by the auto-shift to the one-shot actions,
+    /// then to the layout
+    fn after_auto_shift(&mut self, events: Events, layers: ActiveLayers, layout: &mut KBLayout) {
+        for event in events {
+            let (tri_layers, repeat) = (&mut self.tri_layers, &mut self.repeat);
+            self.oneshots.event(event, action_at(layers, event), |e| {
+                to_layout(e, layers, tri_layers, repeat, layout)
+            });
         }
     }
-    report
+
+    /// Tick the engines and the layout at `now`, returning the custom event
+    /// of the layout
+    fn tick(&mut self, now: Instant, layout: &mut KBLayout) -> KbCustomEvent<CustomEvent> {
+        let layers = ActiveLayers::of(layout);
+        let events = collect(|emit| self.combos.tick(emit));
+        self.after_combos(events, now, layers, layout);
+        let events = collect(|emit| self.hold_taps.tick(now, emit));
+        self.after_hold_taps(events, layers, layout);
+        let events = collect(|emit| self.tap_dances.tick(emit));
+        self.after_tap_dances(events, layers, layout);
+        let events = collect(|emit| self.leader.tick(emit));
+        self.after_leader(events, layers, layout);
+        let events = collect(|emit| self.auto_shift.tick(emit));
+        self.after_auto_shift(events, layers, layout);
+        let (tri_layers, repeat) = (&mut self.tri_layers, &mut self.repeat);
+        self.oneshots
+            .tick(|e| to_layout(e, layers, tri_layers, repeat, layout));
+        self.repeat.tick();
+        let custom_event = layout.tick();
+        let layers = ActiveLayers::of(layout);
+        let repeat = &mut self.repeat;
+        self.tri_layers
+            .check(layers.current, |e| into_layout(e, layers, repeat, layout));
+        let events = collect(|emit| self.tap_dances.custom_event(&custom_event, emit));
+        self.after_tap_dances(events, layers, layout);
+        self.leader.custom_event(&custom_event);
+        self.swap_hands.custom_event(&custom_event);
+        let (tri_layers, repeat) = (&mut self.tri_layers, &mut self.repeat);
+        self.oneshots.custom_event(&custom_event, |e| {
+            to_layout(e, layers, tri_layers, repeat, layout)
+        });
+        let events = collect(|emit| self.oneshots.report(layout.keycodes(), emit));
+        for event in events {
+            to_layout(
+                event,
+                layers,
+                &mut self.tri_layers,
+                &mut self.repeat,
+                layout,
+            );
+        }
+        let (tri_layers, repeat) = (&mut self.tri_layers, &mut self.repeat);
+        let events = collect(|emit| repeat.custom_event(&custom_event, emit));
+        for event in events {
+            to_layout(event, layers, tri_layers, repeat, layout);
+        }
+        custom_event
+    }
 }
 
 /// Keyboard layout handler
 /// Handles layout events into the keymap and sends HID reports to the HID handler
 pub async fn layout_handler() {
     let mut layout = Layout::new(&LAYERS);
+    let mut engines = Engines::new();
+    let mut key_overrides = KeyOverrides::new(KEY_OVERRIDES);
+    let mut caps_word = CapsWord::new(CAPS_WORD_SHIFTED);
+    // Kept when the role changes, not to lose the macros recorded
+    let mut dyn_macros = DynMacros::new();
     let mut mouse = MouseHandler::new();
-    let mut old_kb_report = KeyboardReport::default();
+    let mut old_kb_report = KbReport::default();
+    let mut old_consumer_report = ConsumerReport::default();
+    let mut was_host = false;
     let mut ticker = Ticker::every(Duration::from_millis(REFRESH_RATE_MS));
     loop {
         match select(ticker.next(), LAYOUT_CHANNEL.receive()).await {
             Either::First(_) => {
+                let is_host = is_host();
+                if is_host != was_host {
+                    // Events received with the previous role were discarded,
+                    // start from a clean state
+                    layout = Layout::new(&LAYERS);
+                    engines = Engines::new();
+                    key_overrides = KeyOverrides::new(KEY_OVERRIDES);
+                    caps_word = CapsWord::new(CAPS_WORD_SHIFTED);
+                    mouse = MouseHandler::new();
+                    if !is_host {
+                        // Release the keys still pressed on the host
+                        old_kb_report = KbReport::default();
+                        old_consumer_report = ConsumerReport::default();
+                        HID_KB_CHANNEL.send(old_kb_report).await;
+                        HID_CONSUMER_CHANNEL.send(old_consumer_report).await;
+                        HID_SYSTEM_CHANNEL.send(None).await;
+                        let mouse_report = MouseReport {
+                            x: 0,
+                            y: 0,
+                            buttons: 0,
+                            wheel: 0,
+                            pan: 0,
+                        };
+                        HID_MOUSE_CHANNEL.send(mouse_report).await;
+                    }
+                }
+                was_host = is_host;
+                if !is_host {
+                    // The keys are handled by the other half
+                    continue;
+                }
                 // Process all events in the channel if any
-                while let Ok(event) = LAYOUT_CHANNEL.try_receive() {
-                    layout.event(event);
+                while let Ok((event, time)) = LAYOUT_CHANNEL.try_receive() {
+                    engines.event(event, time, &mut layout);
+                }
+                let custom_event = engines.tick(Instant::now(), &mut layout);
+                caps_word.tick();
+                caps_word.custom_event(&custom_event);
+                dyn_macros.custom_event(&custom_event);
+                let (mut kb_report, consumer_report) = generate_hid_kb_reports(
+                    &mut layout,
+                    &mut key_overrides,
+                    &mut caps_word,
+                    &mut engines.repeat,
+                );
+                dyn_macros.record(&kb_report);
+                if let Some(report) = dyn_macros.play() {
+                    kb_report = report;
                 }
-                let custom_event = layout.tick();
-                let kb_report = generate_hid_kb_report(&mut layout);
                 if kb_report != old_kb_report {
                     HID_KB_CHANNEL.send(kb_report).await;
                     old_kb_report = kb_report;
                 }
+                if consumer_report != old_consumer_report {
+                    HID_CONSUMER_CHANNEL.send(consumer_report).await;
+                    old_consumer_report = consumer_report;
+                }
+                match custom_event {
+                    KbCustomEvent::Press(CustomEvent::LinkStats) => {
+                        defmt::info!("Split link statistics: {:?}", link_stats());
+                    }
+                    KbCustomEvent::Press(CustomEvent::ToggleNkro) => toggle_nkro(),
+                    KbCustomEvent::Press(CustomEvent::ToggleAutoShift) => {
+                        engines.auto_shift.toggle()
+                    }
+                    KbCustomEvent::Press(e) if e.system_control().is_some() => {
+                        if e.system_control() == Some(SystemControl::WakeUp) {
+                            REMOTE_WAKEUP.signal(());
+                        }
+                        HID_SYSTEM_CHANNEL.send(e.system_control()).await;
+                    }
+                    KbCustomEvent::Release(e) if e.system_control().is_some() => {
+                        HID_SYSTEM_CHANNEL.send(None).await;
+                    }
+                    _ => {}
+                }
                 mouse.process_event(custom_event);
                 if let Some(mouse_report) = mouse.tick() {
                     defmt::info!("Mouse Report: {:?}", defmt::Debug2Format(&mouse_report));
                     HID_MOUSE_CHANNEL.send(mouse_report).await;
                 }
+                update_host_state(|s| {
+                    s.layer = layout.current_layer() as u8;
+                    s.default_layer = layout.default_layer as u8;
+                    s.mouse = mouse.is_active();
+                    s.leader = engines.leader.is_active();
+                    s.leader_unambiguous = engines.leader.is_unambiguous();
+                    s.caps_word = caps_word.is_active();
+                });
             }
-            Either::Second(event) => {
-                layout.event(event);
-            }
+            Either::Second((event, time)) if was_host => engines.event(event, time, &mut layout),
+            Either::Second(_) => {}
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_control() {
+        assert_eq!(
+            CustomEvent::SystemPowerDown.system_control(),
+            Some(SystemControl::PowerDown)
+        );
+        assert_eq!(
+            CustomEvent::SystemSleep.system_control(),
+            Some(SystemControl::Sleep)
+        );
+        assert_eq!(
+            CustomEvent::SystemWakeUp.system_control(),
+            Some(SystemControl::WakeUp)
+        );
+        assert_eq!(CustomEvent::ToggleNkro.system_control(), None);
+    }
+
+    #[test]
+    fn transparent_keys() {
+        for (layer, rows) in LAYERS.iter().enumerate() {
+            for (i, row) in rows.iter().enumerate().take(MATRIX_ROWS) {
+                for (j, action) in row.iter().enumerate() {
+                    if !matches!(action, Action::Trans) || layer == 0 {
+                        continue;
+                    }
+                    let layers = ActiveLayers {
+                        current: layer,
+                        default: 0,
+                    };
+                    let default = action_at(
+                        ActiveLayers {
+                            current: 0,
+                            default: 0,
+                        },
+                        Event::Press(i as u8, j as u8),
+                    );
+                    let action = action_at(layers, Event::Press(i as u8, j as u8));
+                    assert!(core::ptr::eq(action, default));
+                    assert!(!matches!(action, Action::Trans));
+                }
+            }
+        }
+    }
+}
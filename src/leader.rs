@@ -0,0 +1,170 @@
+use crate::layout::{CustomEvent, LEADER_ROW};
+use defmt::{info, warn};
+use heapless::Vec;
+use keyberon::action::Action;
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{CustomEvent as KbCustomEvent, Event};
+
+/// Maximum number of keys in a leader sequence
+pub const MAX_LEADER_KEYS: usize = 4;
+/// Maximum number of keys typed after the leader key held at the same time
+const MAX_HELD: usize = 8;
+/// Time after which the leader sequence ends, since the last key, in ms
+const LEADER_TIMEOUT: u16 = 1000;
+
+/// Coordinates of a key, as (row, column)
+type Key = (u8, u8);
+
+/// Keys typed after the leader key to trigger an action
+#[derive(Debug, Clone, Copy)]
+pub struct LeaderSequence {
+    /// Keys of the sequence, as the key codes they type
+    pub keys: &'static [KeyCode],
+    /// Action triggered by the sequence
+    pub action: Action<CustomEvent>,
+}
+
+/// Key code typed by a key with `action`, the tap of a hold tap action
+fn keycode(action: &Action<CustomEvent>) -> Option<KeyCode> {
+    match action {
+        Action::KeyCode(kc) if !kc.is_modifier() => Some(*kc),
+        Action::HoldTap(ht) => keycode(&ht.tap),
+        _ => None,
+    }
+}
+
+/// Leader key engine, between the key events and the layout
+///
+/// After the leader key, the keys typing a key code are not sent to the
+/// layout but matched against the leader sequences. The action of the
+/// sequence is the virtual key at the column of its index in the leader row.
+pub struct Leader {
+    /// Leader sequences of the keymap
+    sequences: &'static [LeaderSequence],
+    /// Whether a leader sequence is being typed
+    active: bool,
+    /// Key codes typed after the leader key
+    typed: Vec<KeyCode, MAX_LEADER_KEYS>,
+    /// Keys typed after the leader key still held, their release is not
+    /// sent to the layout
+    held: Vec<Key, MAX_HELD>,
+    /// Time since the leader key or the last key typed, in ms
+    elapsed: u16,
+}
+
+impl Leader {
+    /// Create a new leader key engine
+    pub fn new(sequences: &'static [LeaderSequence]) -> Self {
+        Leader {
+            sequences,
+            active: false,
+            typed: Vec::new(),
+            held: Vec::new(),
+            elapsed: 0,
+        }
+    }
+
+    /// Whether a leader sequence is being typed
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Whether the keys typed so far match a single leader sequence
+    pub fn is_unambiguous(&self) -> bool {
+        self.active && self.candidates().count() == 1
+    }
+
+    /// Leader sequences starting with the keys typed so far
+    fn candidates(&self) -> impl Iterator<Item = &LeaderSequence> {
+        self.sequences
+            .iter()
+            .filter(|s| s.keys.starts_with(&self.typed))
+    }
+
+    /// Index of the leader sequence made of exactly the keys typed so far
+    fn matched(&self) -> Option<usize> {
+        self.sequences
+            .iter()
+            .position(|s| s.keys == self.typed.as_slice())
+    }
+
+    /// End the leader sequence, triggering the action of the sequence at
+    /// `index`, if any
+    fn end(&mut self, index: Option<usize>, emit: &mut impl FnMut(Event)) {
+        self.active = false;
+        self.typed.clear();
+        match index {
+            Some(index) => {
+                emit(Event::Press(LEADER_ROW, index as u8));
+                emit(Event::Release(LEADER_ROW, index as u8));
+            }
+            None => warn!("No leader sequence matches"),
+        }
+    }
+
+    /// Match the keys typed so far against the leader sequences
+    fn check(&mut self, emit: &mut impl FnMut(Event)) {
+        match self.candidates().count() {
+            0 => self.end(None, emit),
+            1 => match self.matched() {
+                Some(index) => self.end(Some(index), emit),
+                None => info!("Leader sequence unambiguous"),
+            },
+            _ => {}
+        }
+    }
+
+    /// Process a custom event of the layout, starting a leader sequence on
+    /// the leader key
+    pub fn custom_event(&mut self, event: &KbCustomEvent<CustomEvent>) {
+        if let KbCustomEvent::Press(CustomEvent::Leader) = event {
+            self.active = true;
+            self.typed.clear();
+            self.elapsed = 0;
+        }
+    }
+
+    /// Process a key event with `action`, sending it to the layout through
+    /// `emit` unless it is part of a leader sequence
+    pub fn event(
+        &mut self,
+        event: Event,
+        action: &Action<CustomEvent>,
+        mut emit: impl FnMut(Event),
+    ) {
+        match event {
+            Event::Press(i, j) if self.active => match keycode(action) {
+                Some(kc) => {
+                    if self.held.push((i, j)).is_err() {
+                        warn!("Too many keys held after the leader key");
+                    }
+                    self.elapsed = 0;
+                    match self.typed.push(kc) {
+                        Ok(()) => self.check(&mut emit),
+                        Err(_) => self.end(None, &mut emit),
+                    }
+                }
+                None => emit(event),
+            },
+            Event::Release(i, j) => match self.held.iter().position(|k| *k == (i, j)) {
+                Some(index) => {
+                    self.held.swap_remove(index);
+                }
+                None => emit(event),
+            },
+            _ => emit(event),
+        }
+    }
+
+    /// Count the time elapsed, ending the leader sequence on timeout
+    pub fn tick(&mut self, mut emit: impl FnMut(Event)) {
+        if !self.active {
+            return;
+        }
+        self.elapsed = self.elapsed.saturating_add(1);
+        if self.elapsed >= LEADER_TIMEOUT {
+            let index = self.matched();
+            self.end(index, &mut emit);
+        }
+    }
+}
@@ -1,37 +1,98 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![warn(missing_docs)]
 #![warn(clippy::missing_docs_in_private_items)]
+// The tests run on the host, without the hardware driven by the rest
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 //! Firmware for the [Cantor36 keyboard](https://github.com/borisfaure/cantor36)
 
+#[cfg(not(test))]
 use defmt::*;
+#[cfg(not(test))]
 use defmt_rtt as _;
+#[cfg(not(test))]
 use embassy_executor::Spawner;
+#[cfg(not(test))]
+use embassy_futures::select::{select, Either};
+#[cfg(not(test))]
 use embassy_stm32::bind_interrupts;
-use embassy_stm32::gpio::{Input, Pull};
+#[cfg(all(not(test), feature = "bootloader"))]
+use embassy_stm32::flash::Flash;
+#[cfg(not(test))]
+use embassy_stm32::gpio::{Input, Level, Output, Pull, Speed};
+#[cfg(not(test))]
 use embassy_stm32::usart;
+#[cfg(not(test))]
 use embassy_usb::class::hid::{HidBootProtocol, HidReaderWriter, HidSubclass, HidWriter, State};
+#[cfg(not(test))]
 use embassy_usb::Builder;
-use usbd_hid::descriptor::{KeyboardReport, MouseReport, SerializedDescriptor};
+#[cfg(not(test))]
+use usbd_hid::descriptor::{MouseReport, SerializedDescriptor};
 
-use crate::hid::{hid_kb_writer_handler, hid_mouse_writer_handler};
+#[cfg(not(test))]
+use crate::hid::{hid_extra_keys_writer_handler, hid_kb_writer_handler, hid_mouse_writer_handler};
+#[cfg(not(test))]
 use crate::side::{SERIAL_BUF_SIZE, USART_BAUDRATE};
+#[cfg(all(not(test), feature = "half_duplex"))]
+use crate::transport::HalfDuplex;
+#[cfg(not(test))]
+use crate::transport::SplitTransport;
+#[cfg(not(test))]
 use futures::future;
+#[cfg(not(test))]
 use panic_probe as _;
 
+/// Shift the keys held long enough
+mod autoshift;
+/// Shift the letters until the end of the word
+mod capsword;
+/// Keys pressed together to trigger an action
+mod combos;
 /// Configuration
+#[cfg(not(test))]
 mod config;
+/// Macros recorded at runtime
+mod dynmacro;
+/// Framing of the messages exchanged with the other half
+mod frame;
 /// USB HID configuration
 mod hid;
+/// Actions depending on whether their key is tapped or held
+mod holdtap;
+/// Layout of the firmware images in flash
+mod image;
+/// Keys sending another keycode while some modifiers are held
+mod keyoverride;
 /// Key handling
+#[cfg(not(test))]
 mod keys;
 /// Layout events processing
 mod layout;
+/// Leader key sequences
+mod leader;
 /// Act as a mouse
 mod mouse;
+/// One-shot modifiers and layers
+mod oneshot;
+/// Repeat and Alt-Repeat keys
+mod repeat;
 /// Handling the other half of the keyboard
 mod side;
+/// Statistics of the split link
+mod stats;
+/// Mirror the keys to the other half
+mod swaphands;
+/// Actions depending on the number of taps
+mod tapdance;
+/// Transports linking both halves of the keyboard
+mod transport;
+/// Layers activated while two other layers are held
+mod trilayer;
+/// Firmware update of the other half
+mod update;
+/// Identity of the firmware, checked against the other half
+mod version;
 
 /// Basic layout for the keyboard
 #[cfg(feature = "keymap_basic")]
@@ -57,11 +118,13 @@ compile_error!(
     "Either feature \"keymap_basic\" or \"keymap_borisfaure\" or \"keymap_test\" must be enabled."
 );
 
+#[cfg(not(test))]
 bind_interrupts!(struct Irqs {
     OTG_FS => embassy_stm32::usb::InterruptHandler<embassy_stm32::peripherals::USB_OTG_FS>;
     USART1 => usart::BufferedInterruptHandler<embassy_stm32::peripherals::USART1>;
 });
 
+#[cfg(not(test))]
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = config::init_device();
@@ -89,9 +152,12 @@ async fn main(spawner: Spawner) {
     let mut control_buf = [0; 64];
 
     let mut device_handler = side::DeviceHandler::new();
+    let mut update_handler = update::UpdateHandler::new();
+    let mut kb_protocol_handler = hid::KbProtocolHandler::new();
 
     let mut state_kb = State::new();
     let mut state_mouse = State::new();
+    let mut state_extra = State::new();
 
     let mut builder = Builder::new(
         driver,
@@ -103,13 +169,14 @@ async fn main(spawner: Spawner) {
     );
 
     builder.handler(&mut device_handler);
+    builder.handler(&mut update_handler);
 
     // Create classes on the builder.
     let hidkb_config = embassy_usb::class::hid::Config {
-        report_descriptor: KeyboardReport::desc(),
-        request_handler: None,
+        report_descriptor: hid::KB_REPORT_DESCRIPTOR,
+        request_handler: Some(&mut kb_protocol_handler),
         poll_ms: 60,
-        max_packet_size: 8,
+        max_packet_size: 32,
         hid_subclass: HidSubclass::Boot,
         hid_boot_protocol: HidBootProtocol::Keyboard,
     };
@@ -125,11 +192,32 @@ async fn main(spawner: Spawner) {
     };
     let hidm = HidWriter::<_, 64>::new(&mut builder, &mut state_mouse, hidm_config);
 
+    let hidx_config = embassy_usb::class::hid::Config {
+        report_descriptor: hid::EXTRA_KEYS_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 60,
+        max_packet_size: 16,
+        hid_subclass: HidSubclass::No,
+        hid_boot_protocol: HidBootProtocol::None,
+    };
+    let hidx = HidWriter::<_, 64>::new(&mut builder, &mut state_extra, hidx_config);
+
     // Build the builder.
     let mut usb = builder.build();
 
-    // Run the USB device.
-    let usb_fut = usb.run();
+    // Run the USB device, waking up the host when asked to while the bus is
+    // suspended.
+    let usb_fut = async {
+        loop {
+            usb.run_until_suspend().await;
+            hid::REMOTE_WAKEUP.reset();
+            if let Either::Second(()) = select(usb.wait_resume(), hid::REMOTE_WAKEUP.wait()).await {
+                if let Err(e) = usb.remote_wakeup().await {
+                    warn!("Remote wakeup failed: {:?}", e);
+                }
+            }
+        }
+    };
 
     let mut request_handler = hid::HidRequestHandler::new(&spawner);
     let (hid_kb_reader, hid_kb_writer) = hidkb.split();
@@ -138,6 +226,7 @@ async fn main(spawner: Spawner) {
     };
     let hid_kb_writer_fut = hid_kb_writer_handler(hid_kb_writer);
     let hid_mouse_writer_fut = hid_mouse_writer_handler(hidm);
+    let hid_extra_keys_writer_fut = hid_extra_keys_writer_handler(hidx);
 
     let matrix = [
         [
@@ -177,7 +266,8 @@ async fn main(spawner: Spawner) {
     let mut rx_buf = [0u8; SERIAL_BUF_SIZE];
     let mut usart_config = usart::Config::default();
     usart_config.baudrate = USART_BAUDRATE;
-    let buf_usart = usart::BufferedUart::new(
+    #[cfg(not(feature = "half_duplex"))]
+    let link = usart::BufferedUart::new(
         p.USART1,
         p.PB7,
         p.PB6,
@@ -187,17 +277,46 @@ async fn main(spawner: Spawner) {
         usart_config,
     )
     .unwrap();
-    let (usart_writer, usart_reader) = buf_usart.split();
+    // Both halves are linked by a single wire, on the TX pin
+    #[cfg(feature = "half_duplex")]
+    let link = HalfDuplex(
+        usart::BufferedUart::new_half_duplex(
+            p.USART1,
+            p.PB6,
+            &mut tx_buf,
+            &mut rx_buf,
+            Irqs,
+            usart_config,
+            usart::HalfDuplexReadback::NoReadback,
+            usart::HalfDuplexConfig::OpenDrainInternal,
+        )
+        .unwrap(),
+    );
+    let (usart_reader, usart_writer) = link.split_link();
     let usart_rx_fut = side::usart_rx(usart_reader);
     let usart_tx_fut = side::usart_tx(usart_writer);
+    let link_fut = side::link_monitor();
+    let stats_fut = stats::stats_reporter();
+    #[cfg(feature = "bootloader")]
+    let update_fut = update::update_handler(Flash::new_blocking(p.FLASH));
+    #[cfg(not(feature = "bootloader"))]
+    let update_fut = update::update_handler();
+
+    let led = Output::new(p.PC13, Level::High, Speed::Low);
+    let state_fut = side::state_handler(led);
 
     // Run everything concurrently.
     // If we had made everything `'static` above instead, we could do this using separate tasks instead.
 
     future::join4(
-        future::join3(usb_fut, usart_rx_fut, usart_tx_fut),
-        future::join3(hid_kb_reader_fut, hid_kb_writer_fut, hid_mouse_writer_fut),
-        matrix_fut,
+        future::join4(usb_fut, usart_rx_fut, usart_tx_fut, link_fut),
+        future::join4(
+            hid_kb_reader_fut,
+            hid_kb_writer_fut,
+            hid_mouse_writer_fut,
+            hid_extra_keys_writer_fut,
+        ),
+        future::join4(matrix_fut, state_fut, stats_fut, update_fut),
         layout_fut,
     )
     .await;
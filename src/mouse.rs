@@ -62,7 +62,6 @@ impl MouseHandler {
             KbCustomEvent::Release(event) => Some((event, false)),
             _ => None,
         } {
-            self.has_changed = true;
             match event {
                 CustomEvent::MouseUp => self.up = is_pressed,
                 CustomEvent::MouseDown => self.down = is_pressed,
@@ -73,12 +72,15 @@ impl MouseHandler {
                 CustomEvent::MouseMiddleClick => self.middle_click = is_pressed,
                 CustomEvent::MouseScrollUp => self.wheel_up = is_pressed,
                 CustomEvent::MouseScrollDown => self.wheel_down = is_pressed,
+                // Not a mouse event
+                _ => return,
             }
+            self.has_changed = true;
         }
     }
 
     /// Check if the mouse is active
-    fn is_active(&self) -> bool {
+    pub fn is_active(&self) -> bool {
         self.up
             || self.down
             || self.left
@@ -0,0 +1,468 @@
+use crate::hid::KbReport;
+use crate::layout::{CustomEvent, ONESHOT_LAYER_ROW, ONESHOT_MOD_ROW};
+use defmt::warn;
+use heapless::Vec;
+use keyberon::action::{Action, HoldTapAction};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{CustomEvent as KbCustomEvent, Event};
+
+/// Time after which a one-shot action not applied to any key is cancelled,
+/// in ms
+const ONESHOT_TIMEOUT: u16 = 1000;
+/// Maximum time between two taps of a one-shot action to lock it, in ms
+const DOUBLE_TAP_TIMEOUT: u16 = 300;
+/// Maximum number of one-shot actions at the same time
+const MAX_ONESHOTS: usize = 4;
+
+/// Coordinates of a key, as (row, column)
+type Key = (u8, u8);
+
+/// State of a one-shot action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Its key is held, acting as a regular modifier or layer key if
+    /// another key is pressed meanwhile
+    Held {
+        /// Whether another key was pressed
+        used: bool,
+    },
+    /// Waiting for the next key
+    Armed,
+    /// Applied to a key, until it is released
+    Applied(Key),
+    /// Its key released, until the layout has sent the keycodes resulting
+    /// from it, like the tap of a hold tap or the keys of a sequence
+    Released,
+    /// Applied to all the keys, until tapped again
+    Locked,
+}
+
+/// One-shot action in progress
+#[derive(Debug, Clone, Copy)]
+struct OneShot {
+    /// Virtual key held by the one-shot action
+    key: Key,
+    /// State of the one-shot action
+    state: State,
+    /// Time since the one-shot action was first pressed, in ms
+    elapsed: u16,
+}
+
+/// Hold tap of the layout whose key is held, not decided yet
+#[derive(Debug, Clone, Copy)]
+struct PendingHoldTap {
+    /// Coordinates of its key
+    key: Key,
+    /// Hold tap
+    hold_tap: &'static HoldTapAction<CustomEvent, KeyCode>,
+    /// Time since its key was pressed, in ms
+    elapsed: u16,
+}
+
+/// Virtual key held by a one-shot action, if `event` is one
+fn virtual_key(event: &CustomEvent) -> Option<Key> {
+    match *event {
+        CustomEvent::OneShotMod(kc) if kc.is_modifier() => {
+            Some((ONESHOT_MOD_ROW, kc as u8 - KeyCode::LCtrl as u8))
+        }
+        CustomEvent::OneShotLayer(layer) => Some((ONESHOT_LAYER_ROW, layer as u8)),
+        _ => None,
+    }
+}
+
+/// Whether pressing a key with `action` applies the one-shot actions
+///
+/// Modifiers and layer keys are combined with the one-shot actions instead.
+fn applies(action: &Action<CustomEvent>) -> bool {
+    match action {
+        Action::KeyCode(kc) => !kc.is_modifier(),
+        Action::MultipleKeyCodes(kcs) => !kcs.iter().all(|kc| kc.is_modifier()),
+        Action::NoOp | Action::Layer(_) | Action::DefaultLayer(_) => false,
+        // The actions of the tap dances are applied instead
+        Action::Custom(CustomEvent::TapDance(_)) => false,
+        // Applied once tapped, as their tap action
+        Action::HoldTap(_) => false,
+        Action::Custom(e) => virtual_key(e).is_none(),
+        _ => true,
+    }
+}
+
+/// One-shot modifiers and layers engine, between the key events and the
+/// layout
+///
+/// A one-shot action presses a virtual key of the layout, holding a modifier
+/// or a layer, until the next key is released and the keycodes of the layout
+/// settle. Tapping it twice quickly locks it until it is tapped again.
+/// Tapping it again later cancels it.
+pub struct OneShots {
+    /// One-shot actions in progress
+    oneshots: Vec<OneShot, MAX_ONESHOTS>,
+    /// Keycodes of the layout on the previous tick
+    report: KbReport,
+    /// Hold tap of the layout held, applying the one-shot actions if tapped
+    hold_tap: Option<PendingHoldTap>,
+}
+
+impl OneShots {
+    /// Create a new one-shot engine
+    pub fn new() -> Self {
+        OneShots {
+            oneshots: Vec::new(),
+            report: KbReport::default(),
+            hold_tap: None,
+        }
+    }
+
+    /// Release the virtual key of the one-shot action at `index`
+    fn release(&mut self, index: usize, emit: &mut impl FnMut(Event)) {
+        let oneshot = self.oneshots.swap_remove(index);
+        emit(Event::Release(oneshot.key.0, oneshot.key.1));
+    }
+
+    /// Process a custom event of the layout, handling the one-shot actions
+    pub fn custom_event(
+        &mut self,
+        event: &KbCustomEvent<CustomEvent>,
+        mut emit: impl FnMut(Event),
+    ) {
+        match *event {
+            KbCustomEvent::Press(e) => {
+                let Some(key) = virtual_key(e) else {
+                    return;
+                };
+                if let Some(index) = self
+                    .oneshots
+                    .iter()
+                    .position(|o| o.key == key && o.state == State::Released)
+                {
+                    // Done with the previous key, start anew
+                    self.release(index, &mut emit);
+                }
+                match self.oneshots.iter().position(|o| o.key == key) {
+                    Some(index) => {
+                        let oneshot = &mut self.oneshots[index];
+                        match oneshot.state {
+                            State::Armed if oneshot.elapsed < DOUBLE_TAP_TIMEOUT => {
+                                oneshot.state = State::Locked;
+                            }
+                            State::Armed | State::Locked => self.release(index, &mut emit),
+                            State::Held { .. } | State::Applied(_) | State::Released => {}
+                        }
+                    }
+                    None => {
+                        let oneshot = OneShot {
+                            key,
+                            state: State::Held { used: false },
+                            elapsed: 0,
+                        };
+                        match self.oneshots.push(oneshot) {
+                            Ok(()) => emit(Event::Press(key.0, key.1)),
+                            Err(_) => warn!("Too many one-shot actions"),
+                        }
+                    }
+                }
+            }
+            KbCustomEvent::Release(e) => {
+                let Some(key) = virtual_key(e) else {
+                    return;
+                };
+                if let Some(index) = self.oneshots.iter().position(|o| o.key == key) {
+                    match self.oneshots[index].state {
+                        State::Held { used: true } => self.release(index, &mut emit),
+                        State::Held { used: false } => self.oneshots[index].state = State::Armed,
+                        _ => {}
+                    }
+                }
+            }
+            KbCustomEvent::NoEvent => {}
+        }
+    }
+
+    /// Process a key event with `action`, sending it to the layout through
+    /// `emit`
+    pub fn event(
+        &mut self,
+        event: Event,
+        action: &Action<CustomEvent>,
+        mut emit: impl FnMut(Event),
+    ) {
+        emit(event);
+        match event {
+            Event::Press(i, j) => {
+                if let Action::HoldTap(hold_tap) = action {
+                    self.hold_tap = Some(PendingHoldTap {
+                        key: (i, j),
+                        hold_tap,
+                        elapsed: 0,
+                    });
+                }
+                if applies(action) {
+                    self.apply((i, j));
+                }
+            }
+            Event::Release(i, j) => {
+                if let Some(pending) = self.hold_tap.filter(|p| p.key == (i, j)) {
+                    self.hold_tap = None;
+                    // Tapped, its tap action is sent on release
+                    if pending.elapsed < pending.hold_tap.timeout && applies(&pending.hold_tap.tap)
+                    {
+                        self.apply((i, j));
+                    }
+                }
+                for oneshot in self.oneshots.iter_mut() {
+                    if oneshot.state == State::Applied((i, j)) {
+                        oneshot.state = State::Released;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Apply the one-shot actions to the key at `key`, pressed
+    fn apply(&mut self, key: Key) {
+        for oneshot in self.oneshots.iter_mut() {
+            match oneshot.state {
+                State::Held { .. } => oneshot.state = State::Held { used: true },
+                State::Armed => oneshot.state = State::Applied(key),
+                _ => {}
+            }
+        }
+    }
+
+    /// Look at the `keycodes` of the layout after its tick, releasing the
+    /// one-shot actions whose key is released once they do not change
+    /// anymore
+    ///
+    /// Releasing them with the key would drop them before the layout sends
+    /// the keycodes of a hold tap tapped on release, or of a sequence.
+    pub fn report(&mut self, keycodes: impl Iterator<Item = KeyCode>, mut emit: impl FnMut(Event)) {
+        let mut report = KbReport::default();
+        for kc in keycodes {
+            report.press(kc);
+        }
+        let settled = report == self.report;
+        self.report = report;
+        if !settled {
+            return;
+        }
+        while let Some(index) = self
+            .oneshots
+            .iter()
+            .position(|o| o.state == State::Released)
+        {
+            self.release(index, &mut emit);
+        }
+    }
+
+    /// Count the time elapsed, cancelling the one-shot actions not applied
+    /// in time
+    pub fn tick(&mut self, mut emit: impl FnMut(Event)) {
+        if let Some(pending) = &mut self.hold_tap {
+            pending.elapsed = pending.elapsed.saturating_add(1);
+        }
+        for oneshot in self.oneshots.iter_mut() {
+            oneshot.elapsed = oneshot.elapsed.saturating_add(1);
+        }
+        while let Some(index) = self
+            .oneshots
+            .iter()
+            .position(|o| o.state == State::Armed && o.elapsed >= ONESHOT_TIMEOUT)
+        {
+            self.release(index, &mut emit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::action::{k, l, HoldTapConfig};
+    use std::vec;
+
+    /// One-shot Shift
+    const OS_SFT: CustomEvent = CustomEvent::OneShotMod(KeyCode::LShift);
+    /// Virtual key of the one-shot Shift
+    const SFT: Key = (ONESHOT_MOD_ROW, 1);
+    /// One-shot NUMBERS layer
+    const OS_NUM: CustomEvent = CustomEvent::OneShotLayer(3);
+    /// Action of a letter key
+    const A: Action<CustomEvent> = k(KeyCode::A);
+    /// Space on tap, layer 1 on hold, decided by the layout
+    static HT_SP: Action<CustomEvent> = Action::HoldTap(&HoldTapAction {
+        timeout: 200,
+        hold: l(1),
+        tap: k(KeyCode::Space),
+        config: HoldTapConfig::Default,
+        tap_hold_interval: 0,
+    });
+
+    /// Process a custom event of the layout, returning the events emitted
+    fn custom(oneshots: &mut OneShots, event: KbCustomEvent<CustomEvent>) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        oneshots.custom_event(&event, |e| events.push(e));
+        events
+    }
+
+    /// Tap the one-shot Shift
+    fn tap(oneshots: &mut OneShots) -> std::vec::Vec<Event> {
+        let mut events = custom(oneshots, KbCustomEvent::Press(&OS_SFT));
+        events.extend(custom(oneshots, KbCustomEvent::Release(&OS_SFT)));
+        events
+    }
+
+    /// Process a key event with `action`, returning the events emitted
+    fn event(
+        oneshots: &mut OneShots,
+        event: Event,
+        action: &Action<CustomEvent>,
+    ) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        oneshots.event(event, action, |e| events.push(e));
+        events
+    }
+
+    /// Look at the keycodes of the layout, returning the events emitted
+    fn report(oneshots: &mut OneShots, keycodes: &[KeyCode]) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        oneshots.report(keycodes.iter().copied(), |e| events.push(e));
+        events
+    }
+
+    #[test]
+    fn applied_to_next_key() {
+        let mut oneshots = OneShots::new();
+        assert_eq!(tap(&mut oneshots), [Event::Press(SFT.0, SFT.1)]);
+        report(&mut oneshots, &[KeyCode::LShift]);
+        event(&mut oneshots, Event::Press(0, 0), &A);
+        report(&mut oneshots, &[KeyCode::LShift, KeyCode::A]);
+        assert_eq!(
+            event(&mut oneshots, Event::Release(0, 0), &A),
+            [Event::Release(0, 0)]
+        );
+        // Kept until the keycodes of the layout settle
+        assert!(report(&mut oneshots, &[KeyCode::LShift]).is_empty());
+        assert_eq!(
+            report(&mut oneshots, &[KeyCode::LShift]),
+            [Event::Release(SFT.0, SFT.1)]
+        );
+        // Not applied to the key after
+        event(&mut oneshots, Event::Press(0, 1), &A);
+        assert!(oneshots.oneshots.is_empty());
+    }
+
+    #[test]
+    fn tap_sent_on_release() {
+        let mut oneshots = OneShots::new();
+        tap(&mut oneshots);
+        // A hold tap typing nothing until released
+        event(&mut oneshots, Event::Press(0, 0), &A);
+        report(&mut oneshots, &[KeyCode::LShift]);
+        event(&mut oneshots, Event::Release(0, 0), &A);
+        assert!(report(&mut oneshots, &[KeyCode::LShift, KeyCode::Z]).is_empty());
+        assert!(report(&mut oneshots, &[KeyCode::LShift]).is_empty());
+        assert_eq!(
+            report(&mut oneshots, &[KeyCode::LShift]),
+            [Event::Release(SFT.0, SFT.1)]
+        );
+    }
+
+    #[test]
+    fn held_as_modifier() {
+        let mut oneshots = OneShots::new();
+        custom(&mut oneshots, KbCustomEvent::Press(&OS_SFT));
+        event(&mut oneshots, Event::Press(0, 0), &A);
+        event(&mut oneshots, Event::Release(0, 0), &A);
+        assert_eq!(
+            custom(&mut oneshots, KbCustomEvent::Release(&OS_SFT)),
+            [Event::Release(SFT.0, SFT.1)]
+        );
+    }
+
+    #[test]
+    fn combined_with_layer_keys() {
+        let mut oneshots = OneShots::new();
+        tap(&mut oneshots);
+        // Layer keys and modifiers do not use the one-shot action
+        event(&mut oneshots, Event::Press(3, 0), &l(1));
+        event(&mut oneshots, Event::Press(3, 1), &k(KeyCode::LCtrl));
+        event(&mut oneshots, Event::Release(3, 0), &l(1));
+        event(&mut oneshots, Event::Release(3, 1), &k(KeyCode::LCtrl));
+        report(&mut oneshots, &[]);
+        assert!(report(&mut oneshots, &[]).is_empty());
+        assert_eq!(oneshots.oneshots[0].state, State::Armed);
+    }
+
+    #[test]
+    fn layer_hold_tap_held() {
+        let mut oneshots = OneShots::new();
+        tap(&mut oneshots);
+        event(&mut oneshots, Event::Press(3, 2), &HT_SP);
+        assert_eq!(oneshots.oneshots[0].state, State::Armed);
+        for _ in 0..200 {
+            oneshots.tick(|_| {});
+        }
+        // Applied to the key of the layer, not to the layer key
+        event(&mut oneshots, Event::Press(0, 0), &A);
+        assert_eq!(oneshots.oneshots[0].state, State::Applied((0, 0)));
+        event(&mut oneshots, Event::Release(3, 2), &HT_SP);
+        assert_eq!(oneshots.oneshots[0].state, State::Applied((0, 0)));
+    }
+
+    #[test]
+    fn layer_hold_tap_tapped() {
+        let mut oneshots = OneShots::new();
+        tap(&mut oneshots);
+        event(&mut oneshots, Event::Press(3, 2), &HT_SP);
+        event(&mut oneshots, Event::Release(3, 2), &HT_SP);
+        // Applied to the Space tapped, until it settles
+        report(&mut oneshots, &[KeyCode::LShift, KeyCode::Space]);
+        assert!(report(&mut oneshots, &[KeyCode::LShift]).is_empty());
+        assert_eq!(
+            report(&mut oneshots, &[KeyCode::LShift]),
+            [Event::Release(SFT.0, SFT.1)]
+        );
+    }
+
+    #[test]
+    fn oneshot_layer() {
+        let mut oneshots = OneShots::new();
+        assert_eq!(
+            custom(&mut oneshots, KbCustomEvent::Press(&OS_NUM)),
+            [Event::Press(ONESHOT_LAYER_ROW, 3)]
+        );
+        custom(&mut oneshots, KbCustomEvent::Release(&OS_NUM));
+        event(&mut oneshots, Event::Press(0, 0), &A);
+        event(&mut oneshots, Event::Release(0, 0), &A);
+        report(&mut oneshots, &[KeyCode::Kb4]);
+        assert!(report(&mut oneshots, &[]).is_empty());
+        assert_eq!(
+            report(&mut oneshots, &[]),
+            [Event::Release(ONESHOT_LAYER_ROW, 3)]
+        );
+    }
+
+    #[test]
+    fn locked() {
+        let mut oneshots = OneShots::new();
+        tap(&mut oneshots);
+        assert!(tap(&mut oneshots).is_empty());
+        for j in 0..3 {
+            event(&mut oneshots, Event::Press(0, j), &A);
+            event(&mut oneshots, Event::Release(0, j), &A);
+        }
+        report(&mut oneshots, &[KeyCode::LShift]);
+        assert!(report(&mut oneshots, &[KeyCode::LShift]).is_empty());
+        assert_eq!(tap(&mut oneshots), [Event::Release(SFT.0, SFT.1)]);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut oneshots = OneShots::new();
+        tap(&mut oneshots);
+        let mut events = vec![];
+        for _ in 0..ONESHOT_TIMEOUT {
+            oneshots.tick(|e| events.push(e));
+        }
+        assert_eq!(events, [Event::Release(SFT.0, SFT.1)]);
+    }
+}
@@ -0,0 +1,461 @@
+use crate::hid::KbReport;
+use crate::layout::{CustomEvent, ALT_REPEAT_ROW};
+use defmt::warn;
+use heapless::Vec;
+use keyberon::action::{Action, HoldTapAction, SequenceEvent};
+use keyberon::key_code::KeyCode;
+use keyberon::layout::{CustomEvent as KbCustomEvent, Event};
+
+/// Maximum number of reports to replay an action
+const MAX_FRAMES: usize = 24;
+
+/// Modifier key codes
+const MODIFIERS: [KeyCode; 8] = [
+    KeyCode::LCtrl,
+    KeyCode::LShift,
+    KeyCode::LAlt,
+    KeyCode::LGui,
+    KeyCode::RCtrl,
+    KeyCode::RShift,
+    KeyCode::RAlt,
+    KeyCode::RGui,
+];
+
+/// Action sent by the Alt-Repeat key after a given key
+#[derive(Debug, Clone, Copy)]
+pub struct AltRepeat {
+    /// Key code typed last by the last action
+    pub key: KeyCode,
+    /// Layers on which the last action has to be pressed, all of them if
+    /// empty
+    pub layers: &'static [usize],
+    /// Action sent instead of repeating the last action
+    pub action: Action<CustomEvent>,
+}
+
+/// Whether `action` types something when replayed
+fn is_replayable(action: &Action<CustomEvent>) -> bool {
+    match action {
+        Action::KeyCode(kc) => !kc.is_modifier(),
+        Action::MultipleKeyCodes(kcs) => !kcs.iter().all(|kc| kc.is_modifier()),
+        Action::Sequence(_) => true,
+        _ => false,
+    }
+}
+
+/// Key code typed last by `action`, if any
+fn last_keycode(action: &Action<CustomEvent>) -> Option<KeyCode> {
+    match action {
+        Action::KeyCode(kc) => Some(*kc),
+        Action::MultipleKeyCodes(kcs) => kcs.iter().rev().find(|kc| !kc.is_modifier()).copied(),
+        Action::Sequence(events) => events.iter().rev().find_map(|e| match e {
+            SequenceEvent::Press(kc) | SequenceEvent::Tap(kc) if !kc.is_modifier() => Some(*kc),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+/// Reports typing `action` with the modifiers `mods` held
+fn frames(action: &Action<CustomEvent>, mods: KbReport) -> Vec<KbReport, MAX_FRAMES> {
+    let mut frames = Vec::new();
+    let mut push = |report: KbReport| {
+        if frames.last() != Some(&report) && frames.push(report).is_err() {
+            warn!("Action too long to be repeated");
+        }
+    };
+    match action {
+        Action::KeyCode(kc) => {
+            let mut report = mods;
+            report.press(*kc);
+            push(report);
+        }
+        Action::MultipleKeyCodes(kcs) => {
+            let mut report = mods;
+            for &kc in kcs.iter() {
+                report.press(kc);
+            }
+            push(report);
+        }
+        Action::Sequence(events) => {
+            let mut held = mods;
+            let mut pressed = KbReport::default();
+            for event in events.iter() {
+                match *event {
+                    SequenceEvent::Press(kc) => pressed.press(kc),
+                    SequenceEvent::Release(kc) => pressed.release(kc),
+                    SequenceEvent::Tap(kc) => {
+                        let mut report = pressed;
+                        report.merge(&held);
+                        report.press(kc);
+                        push(report);
+                        pressed.release(kc);
+                    }
+                    SequenceEvent::Filter(kcs) => {
+                        held = KbReport::default();
+                        for m in MODIFIERS {
+                            if mods.is_pressed(m) && kcs.contains(&m) {
+                                held.press(m);
+                            }
+                        }
+                    }
+                    SequenceEvent::Restore => held = mods,
+                    _ => {}
+                }
+                let mut report = pressed;
+                report.merge(&held);
+                push(report);
+            }
+        }
+        _ => {}
+    }
+    frames
+}
+
+/// Last action pressed
+#[derive(Clone, Copy)]
+struct LastAction {
+    /// Action pressed
+    action: &'static Action<CustomEvent>,
+    /// Layer when it was pressed
+    layer: usize,
+    /// Modifiers held when it was pressed, once known
+    mods: Option<KbReport>,
+}
+
+/// Hold tap of the layout whose key is held, not known as tapped yet
+struct HeldHoldTap {
+    /// Coordinates of its key
+    key: (u8, u8),
+    /// Hold tap
+    hold_tap: &'static HoldTapAction<CustomEvent, KeyCode>,
+    /// Layer when it was pressed
+    layer: usize,
+    /// Time since it was pressed, in ms
+    elapsed: u16,
+}
+
+/// Action being replayed by the Repeat key
+struct Replay {
+    /// Reports to send, one per tick
+    frames: Vec<KbReport, MAX_FRAMES>,
+    /// Index of the report sent
+    index: usize,
+    /// Whether the Repeat key is held
+    held: bool,
+}
+
+/// Repeat and Alt-Repeat keys
+///
+/// The last action pressed is tracked from the events sent to the layout,
+/// including the virtual keys of the layout engines, so that a sequence or
+/// the tap of a hold tap is repeated as a whole. The Alt-Repeat key presses
+/// the virtual key, at the column of the index of the matching alternate
+/// repeat, in the Alt-Repeat row.
+pub struct Repeat {
+    /// Alternate repeats of the keymap
+    alt_repeats: &'static [AltRepeat],
+    /// Keyboard report of the previous tick, before the repeat
+    previous: KbReport,
+    /// Last action pressed
+    last: Option<LastAction>,
+    /// Hold tap of the layout held, repeated if tapped
+    hold_tap: Option<HeldHoldTap>,
+    /// Action being replayed
+    replay: Option<Replay>,
+    /// Virtual key pressed by the Alt-Repeat key
+    alt_pressed: Option<u8>,
+}
+
+impl Repeat {
+    /// Create a new Repeat and Alt-Repeat handler
+    pub fn new(alt_repeats: &'static [AltRepeat]) -> Self {
+        Repeat {
+            alt_repeats,
+            previous: KbReport::default(),
+            last: None,
+            hold_tap: None,
+            replay: None,
+            alt_pressed: None,
+        }
+    }
+
+    /// Record `action` as the last one, pressed on `layer`
+    fn record(&mut self, action: &'static Action<CustomEvent>, layer: usize) {
+        self.last = Some(LastAction {
+            action,
+            layer,
+            mods: None,
+        });
+    }
+
+    /// Track the action of an event sent to the layout, on `layer`
+    pub fn event(&mut self, event: Event, action: &'static Action<CustomEvent>, layer: usize) {
+        match event {
+            // Alternate repeats are not repeated themselves
+            Event::Press(ALT_REPEAT_ROW, _) => {}
+            Event::Press(i, j) => {
+                self.hold_tap = None;
+                match action {
+                    Action::HoldTap(hold_tap) => {
+                        self.hold_tap = Some(HeldHoldTap {
+                            key: (i, j),
+                            hold_tap,
+                            layer,
+                            elapsed: 0,
+                        });
+                    }
+                    _ if is_replayable(action) => self.record(action, layer),
+                    _ => {}
+                }
+            }
+            Event::Release(i, j) => {
+                let Some(held) = self.hold_tap.take_if(|h| h.key == (i, j)) else {
+                    return;
+                };
+                if held.elapsed < held.hold_tap.timeout && is_replayable(&held.hold_tap.tap) {
+                    self.record(&held.hold_tap.tap, held.layer);
+                }
+            }
+        }
+    }
+
+    /// Count the time elapsed, for the hold taps of the layout
+    pub fn tick(&mut self) {
+        if let Some(held) = &mut self.hold_tap {
+            held.elapsed = held.elapsed.saturating_add(1);
+        }
+    }
+
+    /// Index of the alternate repeat of the last action
+    fn alt_repeat(&self) -> Option<usize> {
+        let last = self.last?;
+        let kc = last_keycode(last.action)?;
+        self.alt_repeats
+            .iter()
+            .position(|a| a.key == kc && (a.layers.is_empty() || a.layers.contains(&last.layer)))
+    }
+
+    /// Process a custom event of the layout, handling the Repeat and
+    /// Alt-Repeat keys
+    pub fn custom_event(
+        &mut self,
+        event: &KbCustomEvent<CustomEvent>,
+        mut emit: impl FnMut(Event),
+    ) {
+        match event {
+            KbCustomEvent::Press(CustomEvent::Repeat) => {
+                if let Some(last) = self.last {
+                    let mods = last.mods.unwrap_or_default();
+                    let frames = frames(last.action, mods);
+                    if !frames.is_empty() {
+                        self.replay = Some(Replay {
+                            frames,
+                            index: 0,
+                            held: true,
+                        });
+                    }
+                }
+            }
+            KbCustomEvent::Release(CustomEvent::Repeat) => {
+                // The sequences are replayed until their end
+                if let Some(replay) = &mut self.replay {
+                    replay.held = false;
+                    if replay.index + 1 >= replay.frames.len() {
+                        self.replay = None;
+                    }
+                }
+            }
+            KbCustomEvent::Press(CustomEvent::AltRepeat) => match self.alt_repeat() {
+                Some(index) => {
+                    emit(Event::Press(ALT_REPEAT_ROW, index as u8));
+                    self.alt_pressed = Some(index as u8);
+                }
+                None => warn!("No alternate repeat for the last action"),
+            },
+            KbCustomEvent::Release(CustomEvent::AltRepeat) => {
+                if let Some(column) = self.alt_pressed.take() {
+                    emit(Event::Release(ALT_REPEAT_ROW, column));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Note the modifiers held with the last action, and add the action
+    /// being replayed to the report
+    pub fn apply(&mut self, report: &mut KbReport) {
+        let previous = core::mem::replace(&mut self.previous, *report);
+        if let Some(last) = &mut self.last {
+            last.mods.get_or_insert(previous.modifiers());
+        }
+        let Some(replay) = &mut self.replay else {
+            return;
+        };
+        report.merge(&replay.frames[replay.index]);
+        if replay.index + 1 < replay.frames.len() {
+            replay.index += 1;
+        } else if !replay.held {
+            self.replay = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{assert, assert_eq};
+    use keyberon::action::{k, HoldTapConfig};
+    use std::vec;
+    use KeyCode::*;
+    use SequenceEvent::*;
+
+    /// Acute accent on the following letter, without the Shift held
+    static ACUTE_E: Action<CustomEvent> = Action::Sequence(
+        &[
+            Filter(&[LShift, RShift].as_slice()),
+            Tap(RAlt),
+            Tap(Quote),
+            Restore,
+            Tap(E),
+        ]
+        .as_slice(),
+    );
+    /// Tmux: next window
+    static NEXT: Action<CustomEvent> =
+        Action::Sequence(&[Press(LCtrl), Tap(A), Release(LCtrl), Tap(N)].as_slice());
+    /// Key A
+    static KEY_A: Action<CustomEvent> = k(A);
+    /// B on tap, Ctrl on hold
+    static HOLD_TAP: Action<CustomEvent> = Action::HoldTap(&HoldTapAction {
+        timeout: 200,
+        hold: k(LCtrl),
+        tap: k(B),
+        config: HoldTapConfig::Default,
+        tap_hold_interval: 0,
+    });
+    /// Alternate repeat of N on layer 1
+    static ALT_REPEATS: [AltRepeat; 1] = [AltRepeat {
+        key: N,
+        layers: &[1],
+        action: Action::NoOp,
+    }];
+
+    /// Report made of `keycodes`
+    fn report(keycodes: &[KeyCode]) -> KbReport {
+        let mut report = KbReport::default();
+        for &kc in keycodes {
+            report.press(kc);
+        }
+        report
+    }
+
+    /// Tap the key (0, 0) with `action` on `layer`, while `mods` are held
+    fn tap(
+        repeat: &mut Repeat,
+        action: &'static Action<CustomEvent>,
+        layer: usize,
+        mods: &[KeyCode],
+    ) {
+        // The modifiers are pressed before the key
+        repeat.apply(&mut report(mods));
+        repeat.event(Event::Press(0, 0), action, layer);
+        repeat.apply(&mut report(mods));
+        repeat.event(Event::Release(0, 0), action, layer);
+        repeat.apply(&mut report(mods));
+        repeat.apply(&mut KbReport::default());
+    }
+
+    /// Process a custom event, returning the events emitted
+    fn custom(repeat: &mut Repeat, event: KbCustomEvent<CustomEvent>) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        repeat.custom_event(&event, |e| events.push(e));
+        events
+    }
+
+    /// Reports of the next `n` ticks, without any key pressed
+    fn reports(repeat: &mut Repeat, n: usize) -> std::vec::Vec<KbReport> {
+        (0..n)
+            .map(|_| {
+                let mut report = KbReport::default();
+                repeat.apply(&mut report);
+                report
+            })
+            .collect()
+    }
+
+    #[test]
+    fn key_held() {
+        let mut repeat = Repeat::new(&ALT_REPEATS);
+        tap(&mut repeat, &KEY_A, 0, &[LShift]);
+        custom(&mut repeat, KbCustomEvent::Press(&CustomEvent::Repeat));
+        assert_eq!(reports(&mut repeat, 2), [report(&[LShift, A]); 2]);
+        custom(&mut repeat, KbCustomEvent::Release(&CustomEvent::Repeat));
+        assert_eq!(reports(&mut repeat, 1), [KbReport::default()]);
+    }
+
+    #[test]
+    fn sequence_replayed() {
+        let mut repeat = Repeat::new(&ALT_REPEATS);
+        tap(&mut repeat, &ACUTE_E, 0, &[LCtrl]);
+        // Released before the end, the sequence is replayed as a whole
+        custom(&mut repeat, KbCustomEvent::Press(&CustomEvent::Repeat));
+        custom(&mut repeat, KbCustomEvent::Release(&CustomEvent::Repeat));
+        assert_eq!(
+            reports(&mut repeat, 9),
+            [
+                report(&[]),
+                report(&[RAlt]),
+                report(&[]),
+                report(&[Quote]),
+                report(&[]),
+                report(&[LCtrl]),
+                report(&[LCtrl, E]),
+                report(&[LCtrl]),
+                report(&[]),
+            ]
+        );
+    }
+
+    #[test]
+    fn alt_repeat_on_layer() {
+        let mut repeat = Repeat::new(&ALT_REPEATS);
+        tap(&mut repeat, &NEXT, 0, &[]);
+        assert!(custom(&mut repeat, KbCustomEvent::Press(&CustomEvent::AltRepeat)).is_empty());
+        // The layer is the one of the sequence, whatever the current one
+        tap(&mut repeat, &NEXT, 1, &[]);
+        assert_eq!(
+            custom(&mut repeat, KbCustomEvent::Press(&CustomEvent::AltRepeat)),
+            [Event::Press(ALT_REPEAT_ROW, 0)]
+        );
+        assert_eq!(
+            custom(&mut repeat, KbCustomEvent::Release(&CustomEvent::AltRepeat)),
+            [Event::Release(ALT_REPEAT_ROW, 0)]
+        );
+    }
+
+    #[test]
+    fn hold_tap_tapped() {
+        let mut repeat = Repeat::new(&ALT_REPEATS);
+        tap(&mut repeat, &KEY_A, 0, &[]);
+        repeat.event(Event::Press(0, 1), &HOLD_TAP, 0);
+        repeat.tick();
+        repeat.event(Event::Release(0, 1), &HOLD_TAP, 0);
+        repeat.apply(&mut KbReport::default());
+        custom(&mut repeat, KbCustomEvent::Press(&CustomEvent::Repeat));
+        assert_eq!(reports(&mut repeat, 1), [report(&[B])]);
+    }
+
+    #[test]
+    fn hold_tap_held() {
+        let mut repeat = Repeat::new(&ALT_REPEATS);
+        tap(&mut repeat, &KEY_A, 0, &[]);
+        repeat.event(Event::Press(0, 1), &HOLD_TAP, 0);
+        for _ in 0..200 {
+            repeat.tick();
+        }
+        repeat.event(Event::Release(0, 1), &HOLD_TAP, 0);
+        custom(&mut repeat, KbCustomEvent::Press(&CustomEvent::Repeat));
+        assert_eq!(reports(&mut repeat, 1), [report(&[A])]);
+    }
+}
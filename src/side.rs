@@ -1,10 +1,20 @@
+use crate::frame::{FrameDecoder, FrameEncoder, MAX_FRAME_SIZE, MAX_PAYLOAD_SIZE};
 use crate::layout::LAYOUT_CHANNEL;
-use core::sync::atomic::{AtomicBool, Ordering};
+use crate::stats::LINK_STATS;
+use crate::transport::{LinkError, SplitRx, SplitTx};
+use crate::update::{UpdateMessage, UPDATE_FROM_REMOTE, UPDATE_TO_REMOTE};
+use crate::version::{Compatibility, Identity, MAX_KEYMAP_NAME_LEN};
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use defmt::*;
-use embassy_stm32::usart::{BufferedUartRx, BufferedUartTx};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_futures::select::{select, select6, Either, Either6};
+#[cfg(not(test))]
+use embassy_stm32::gpio::Output;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::{channel::Channel, signal::Signal};
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
 use embassy_usb::Handler;
-use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
 use keyberon::layout::Event;
 
 /// Number of events in the channel to the other half of the keyboard
@@ -12,62 +22,689 @@ const NB_EVENTS: usize = 64;
 /// Channel to send `keyberon::layout::event` events to the layout handler
 pub static SIDE_CHANNEL: Channel<CriticalSectionRawMutex, Event, NB_EVENTS> = Channel::new();
 
-/// Serialized size of a key event
-pub const SERIALIZED_SIZE: usize = 4;
-/// Buffer size for serialized key events
-pub const SERIAL_BUF_SIZE: usize = 8 * SERIALIZED_SIZE;
+/// Buffer size for the serial line
+pub const SERIAL_BUF_SIZE: usize = 4 * MAX_FRAME_SIZE;
 /// USART baudrate
 pub const USART_BAUDRATE: u32 = 38_400;
+/// Number of times a frame is sent again after a write error
+const MAX_RETRANSMITS: usize = 2;
 
-/// Deserialize a key event from the serial line
-fn deserialize(bytes: &[u8; SERIALIZED_SIZE]) -> Result<Event, ()> {
+/// State of the host half, broadcast to the other half
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct HostState {
+    /// Active layer
+    pub layer: u8,
+    /// Default layer
+    pub default_layer: u8,
+    /// Caps Lock led state
+    pub caps_lock: bool,
+    /// Num Lock led state
+    pub num_lock: bool,
+    /// Whether the mouse is being controlled
+    pub mouse: bool,
+    /// Whether the USB bus is suspended
+    pub suspended: bool,
+    /// Whether a leader sequence is being typed
+    pub leader: bool,
+    /// Whether the keys typed after the leader key match a single sequence
+    pub leader_unambiguous: bool,
+    /// Whether Caps Word is on
+    pub caps_word: bool,
+}
+
+impl HostState {
+    /// Create a new host state
+    const fn new() -> Self {
+        HostState {
+            layer: 0,
+            default_layer: 0,
+            caps_lock: false,
+            num_lock: false,
+            mouse: false,
+            suspended: false,
+            leader: false,
+            leader_unambiguous: false,
+            caps_word: false,
+        }
+    }
+
+    /// Pack the boolean states into a byte
+    fn flags(&self) -> u8 {
+        self.caps_lock as u8
+            | (self.num_lock as u8) << 1
+            | (self.mouse as u8) << 2
+            | (self.suspended as u8) << 3
+            | (self.leader as u8) << 4
+            | (self.leader_unambiguous as u8) << 5
+            | (self.caps_word as u8) << 6
+    }
+}
+
+/// State of the host half
+static HOST_STATE: Mutex<CriticalSectionRawMutex, Cell<HostState>> =
+    Mutex::new(Cell::new(HostState::new()));
+/// Signal that the state of the host half has to be sent to the other half
+static HOST_STATE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Signal to the state handler the state of the host half, be it this half
+/// or the other one
+static STATE_SIGNAL: Signal<CriticalSectionRawMutex, HostState> = Signal::new();
+
+/// Update the state of the host half and notify the other half if it changed
+pub fn update_host_state(f: impl FnOnce(&mut HostState)) {
+    HOST_STATE.lock(|cell| {
+        let mut state = cell.get();
+        f(&mut state);
+        if state != cell.get() {
+            cell.set(state);
+            HOST_STATE_CHANGED.signal(());
+            STATE_SIGNAL.signal(state);
+        }
+    });
+}
+
+/// Set of keys, as a bitmap indexed by their coordinates
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KeySet(u64);
+
+impl KeySet {
+    /// Number of columns of the whole keyboard
+    const COLS: u8 = 10;
+    /// Number of rows of the whole keyboard
+    const ROWS: u8 = 4;
+
+    /// Create an empty set of keys
+    pub const fn new() -> Self {
+        KeySet(0)
+    }
+
+    /// Bit of the key at the given coordinates
+    fn bit(i: u8, j: u8) -> u64 {
+        if i < Self::ROWS && j < Self::COLS {
+            1 << (i * Self::COLS + j)
+        } else {
+            0
+        }
+    }
+
+    /// Update the set from a key event, returns whether it changed
+    pub fn update(&mut self, event: Event) -> bool {
+        let old = self.0;
+        match event {
+            Event::Press(i, j) => self.0 |= Self::bit(i, j),
+            Event::Release(i, j) => self.0 &= !Self::bit(i, j),
+        }
+        old != self.0
+    }
+
+    /// Whether the key at the given coordinates is in the set
+    pub fn contains(&self, i: u8, j: u8) -> bool {
+        self.0 & Self::bit(i, j) != 0
+    }
+
+    /// Iterate over the coordinates of the keys in the set
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        (0..Self::ROWS)
+            .flat_map(|i| (0..Self::COLS).map(move |j| (i, j)))
+            .filter(|&(i, j)| self.contains(i, j))
+    }
+
+    /// Events turning this set into `other`
+    fn diff(&self, other: KeySet) -> impl Iterator<Item = Event> + '_ {
+        (0..Self::ROWS)
+            .flat_map(|i| (0..Self::COLS).map(move |j| (i, j)))
+            .filter_map(move |(i, j)| {
+                let bit = Self::bit(i, j);
+                match (self.0 & bit != 0, other.0 & bit != 0) {
+                    (false, true) => Some(Event::Press(i, j)),
+                    (true, false) => Some(Event::Release(i, j)),
+                    _ => None,
+                }
+            })
+    }
+
+    /// Bitmap of the keys of the half whose first column is `first_col`,
+    /// with one bit per key of the half, row by row
+    #[cfg(feature = "matrix_sync")]
+    fn half_bitmap(&self, first_col: u8) -> u32 {
+        self.iter()
+            .filter(|&(_, j)| (first_col..first_col + HALF_COLS).contains(&j))
+            .fold(0, |bitmap, (i, j)| {
+                bitmap | 1 << (i * HALF_COLS + j - first_col)
+            })
+    }
+
+    /// Set of keys from the bitmap of the half whose first column is
+    /// `first_col`
+    fn from_half_bitmap(bitmap: u32, first_col: u8) -> Self {
+        let mut keys = KeySet::new();
+        for i in 0..Self::ROWS {
+            for j in 0..HALF_COLS {
+                if bitmap & 1 << (i * HALF_COLS + j) != 0 {
+                    keys.0 |= Self::bit(i, first_col + j);
+                }
+            }
+        }
+        keys
+    }
+}
+
+/// Number of columns of a half of the keyboard
+const HALF_COLS: u8 = 5;
+/// First column of the keys of this half
+#[cfg(feature = "left")]
+const LOCAL_FIRST_COL: u8 = 0;
+/// First column of the keys of this half
+#[cfg(feature = "right")]
+const LOCAL_FIRST_COL: u8 = HALF_COLS;
+/// First column of the keys of the other half
+const REMOTE_FIRST_COL: u8 = HALF_COLS - LOCAL_FIRST_COL;
+
+/// Period between two heartbeats
+const HEARTBEAT_PERIOD: Duration = Duration::from_millis(100);
+/// The link is considered down when nothing was received for this long
+const LINK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Whether frames are received from the other half
+static LINK_UP: AtomicBool = AtomicBool::new(false);
+/// Signaled when a valid frame is received
+static LINK_ALIVE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Signal to the sender that the link came back up
+static LINK_RESTORED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Keys of the other half that are pressed in the layout
+static REMOTE_KEYS: Mutex<CriticalSectionRawMutex, Cell<KeySet>> =
+    Mutex::new(Cell::new(KeySet::new()));
+
+/// Whether the other half is connected
+pub fn is_link_up() -> bool {
+    LINK_UP.load(Ordering::Relaxed)
+}
+
+/// Whether the link is kept up without heartbeats, during a firmware update
+static LINK_MONITOR_PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Keep the link up while the heartbeats may stop, like when the flash is
+/// erased during a firmware update
+pub fn pause_link_monitor(paused: bool) {
+    LINK_MONITOR_PAUSED.store(paused, Ordering::Relaxed);
+}
+
+/// Role of a half of the keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum Role {
+    /// No half is connected to USB yet, or the other half is not known
+    Undecided = 0,
+    /// The half handling the layout and sending HID reports
+    Master = 1,
+    /// The half sending its key events to the master
+    Secondary = 2,
+}
+
+impl From<u8> for Role {
+    fn from(v: u8) -> Self {
+        match v {
+            1 => Role::Master,
+            2 => Role::Secondary,
+            _ => Role::Undecided,
+        }
+    }
+}
+
+/// When both halves are connected to USB, the left one is the master
+#[cfg(feature = "left")]
+const MASTER_ON_TIE: bool = true;
+/// When both halves are connected to USB, the left one is the master
+#[cfg(feature = "right")]
+const MASTER_ON_TIE: bool = false;
+
+/// Role of this half
+static ROLE: AtomicU8 = AtomicU8::new(Role::Undecided as u8);
+/// Whether the other half is configured by USB
+static REMOTE_USB: AtomicBool = AtomicBool::new(false);
+/// Signal to the sender that the role changed
+static ROLE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Role of this half
+pub fn role() -> Role {
+    ROLE.load(Ordering::Relaxed).into()
+}
+
+/// Whether the device is the host or not
+pub fn is_host() -> bool {
+    role() == Role::Master
+}
+
+/// Compute the role of this half from the USB state of both halves
+///
+/// The half configured by USB is the master.  When both are, the left half
+/// wins.  Events queued for the previous role are discarded when it changes.
+fn negotiate_role() {
+    let local = CONFIGURED.load(Ordering::Relaxed);
+    let remote = is_link_up() && REMOTE_USB.load(Ordering::Relaxed);
+    let role = match (local, remote) {
+        (true, false) => Role::Master,
+        (false, true) => Role::Secondary,
+        (true, true) if MASTER_ON_TIE => Role::Master,
+        (true, true) => Role::Secondary,
+        (false, false) => Role::Undecided,
+    };
+    let old: Role = ROLE.swap(role as u8, Ordering::Relaxed).into();
+    if old != role {
+        info!("Role changed from {:?} to {:?}", old, role);
+        SIDE_CHANNEL.clear();
+        LAYOUT_CHANNEL.clear();
+        REMOTE_KEYS.lock(|cell| cell.set(KeySet::new()));
+        ROLE_CHANGED.signal(());
+        if role == Role::Master {
+            HOST_STATE_CHANGED.signal(());
+        }
+    }
+}
+
+/// Whether the firmware of the other half differs from this one
+static FIRMWARE_MISMATCH: AtomicBool = AtomicBool::new(false);
+/// Whether the other half speaks another version of the protocol
+static PROTOCOL_MISMATCH: AtomicBool = AtomicBool::new(false);
+/// Whether the identity of the other half was received since the link is up
+static HELLO_RECEIVED: AtomicBool = AtomicBool::new(false);
+/// Whether the other half sent its identity again, not having received ours
+static HELLO_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Messages exchanged between both halves
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Message {
+    /// Key event from the other half
+    Event(Event),
+    /// State of the host half
+    State(HostState),
+    /// Sent periodically to show the link is alive and to negotiate the
+    /// role of each half
+    Heartbeat {
+        /// Whether the sender is configured by USB
+        usb: bool,
+        /// Role of the sender
+        role: Role,
+    },
+    /// Identity of the firmware of the sender, sent when the link comes up
+    Hello(Identity),
+    /// Debounced matrix of the secondary half, one bit per key, sent
+    /// periodically to recover from lost key events
+    Matrix(u32),
+    /// Firmware update of the secondary half
+    Update(UpdateMessage),
+}
+
+/// Deserialize a message from the payload of a frame
+fn deserialize(bytes: &[u8]) -> Result<Message, ()> {
     match *bytes {
-        [b'P', i, j, b'\n'] => Ok(Event::Press(i, j)),
-        [b'R', i, j, b'\n'] => Ok(Event::Release(i, j)),
+        [b'P', i, j] => Ok(Message::Event(Event::Press(i, j))),
+        [b'R', i, j] => Ok(Message::Event(Event::Release(i, j))),
+        [b'S', layer, default_layer, flags] => Ok(Message::State(HostState {
+            layer,
+            default_layer,
+            caps_lock: flags & 1 != 0,
+            num_lock: flags & 1 << 1 != 0,
+            mouse: flags & 1 << 2 != 0,
+            suspended: flags & 1 << 3 != 0,
+            leader: flags & 1 << 4 != 0,
+            leader_unambiguous: flags & 1 << 5 != 0,
+            caps_word: flags & 1 << 6 != 0,
+        })),
+        [b'H', usb, role] => Ok(Message::Heartbeat {
+            usb: usb != 0,
+            role: role.into(),
+        }),
+        [b'V', protocol, major, minor, patch, h0, h1, h2, h3, ref name @ ..] => {
+            let name = core::str::from_utf8(name).map_err(|_| ())?;
+            let mut keymap_name = String::<MAX_KEYMAP_NAME_LEN>::new();
+            keymap_name.push_str(name).map_err(|_| ())?;
+            Ok(Message::Hello(Identity {
+                protocol,
+                firmware: [major, minor, patch],
+                keymap_name,
+                keymap_hash: u32::from_be_bytes([h0, h1, h2, h3]),
+            }))
+        }
+        [b'M', b0, b1, b2] => Ok(Message::Matrix(u32::from_le_bytes([b0, b1, b2, 0]))),
+        [b'U', ref msg @ ..] => UpdateMessage::deserialize(msg).map(Message::Update),
         _ => Err(()),
     }
 }
 
-/// Serialize a key event
-fn serialize(e: Event) -> [u8; SERIALIZED_SIZE] {
-    match e {
-        Event::Press(i, j) => [b'P', i, j, b'\n'],
-        Event::Release(i, j) => [b'R', i, j, b'\n'],
+/// Serialize a message into the payload of a frame
+fn serialize(msg: Message) -> Vec<u8, MAX_PAYLOAD_SIZE> {
+    let mut payload = Vec::new();
+    // Cannot fail since all messages fit in a frame
+    let _ = match msg {
+        Message::Event(Event::Press(i, j)) => payload.extend_from_slice(&[b'P', i, j]),
+        Message::Event(Event::Release(i, j)) => payload.extend_from_slice(&[b'R', i, j]),
+        Message::State(s) => {
+            payload.extend_from_slice(&[b'S', s.layer, s.default_layer, s.flags()])
+        }
+        Message::Heartbeat { usb, role } => {
+            payload.extend_from_slice(&[b'H', usb as u8, role as u8])
+        }
+        Message::Hello(id) => payload
+            .extend_from_slice(&[b'V', id.protocol])
+            .and_then(|_| payload.extend_from_slice(&id.firmware))
+            .and_then(|_| payload.extend_from_slice(&id.keymap_hash.to_be_bytes()))
+            .and_then(|_| payload.extend_from_slice(id.keymap_name.as_bytes())),
+        Message::Matrix(bitmap) => {
+            let [b0, b1, b2, _] = bitmap.to_le_bytes();
+            payload.extend_from_slice(&[b'M', b0, b1, b2])
+        }
+        Message::Update(msg) => payload
+            .extend_from_slice(b"U")
+            .and_then(|_| payload.extend_from_slice(&msg.serialize())),
+    };
+    payload
+}
+
+/// Handle a message received from the other half
+async fn handle_message(msg: Message, identity: &Identity) {
+    match msg {
+        Message::Event(event) => {
+            if !is_host() {
+                debug!("Not the master, dropping event from the other half");
+                return;
+            }
+            if PROTOCOL_MISMATCH.load(Ordering::Relaxed) {
+                warn!("Incompatible firmware on the other half, dropping event");
+                return;
+            }
+            // Drop events that do not change the state of the remote keys,
+            // like the presses sent again when the link comes back up or the
+            // releases of keys already released when the link went down
+            if REMOTE_KEYS.lock(|cell| {
+                let mut keys = cell.get();
+                let changed = keys.update(event);
+                cell.set(keys);
+                changed
+            }) {
+                LAYOUT_CHANNEL.send((event, Instant::now())).await;
+            }
+        }
+        Message::State(state) => {
+            if is_host() {
+                warn!("Both halves act as host, ignoring state {:?}", state);
+            } else {
+                STATE_SIGNAL.signal(state);
+            }
+        }
+        Message::Heartbeat { usb, role } => {
+            REMOTE_USB.store(usb, Ordering::Relaxed);
+            negotiate_role();
+            if role == Role::Master && is_host() {
+                warn!("Both halves claim to be the master");
+            }
+        }
+        Message::Hello(remote) => {
+            if HELLO_RECEIVED.swap(true, Ordering::Relaxed) {
+                // The other half sends its identity until it receives ours
+                HELLO_REQUESTED.store(true, Ordering::Relaxed);
+            }
+            let compatibility = identity.compare(&remote);
+            match compatibility {
+                Compatibility::Same => info!("Other half runs the same firmware"),
+                Compatibility::Mismatch => warn!(
+                    "Firmware mismatch! this half: {:?}, other half: {:?}",
+                    identity, remote
+                ),
+                Compatibility::Incompatible => error!(
+                    "Incompatible firmware! this half: {:?}, other half: {:?}",
+                    identity, remote
+                ),
+            }
+            FIRMWARE_MISMATCH.store(compatibility != Compatibility::Same, Ordering::Relaxed);
+            PROTOCOL_MISMATCH.store(
+                compatibility == Compatibility::Incompatible,
+                Ordering::Relaxed,
+            );
+        }
+        Message::Matrix(bitmap) => {
+            if !is_host() || PROTOCOL_MISMATCH.load(Ordering::Relaxed) {
+                return;
+            }
+            // The matrix reflects all the events sent before it, any
+            // difference comes from lost events
+            let keys = KeySet::from_half_bitmap(bitmap, REMOTE_FIRST_COL);
+            let old = REMOTE_KEYS.lock(|cell| cell.replace(keys));
+            for event in old.diff(keys) {
+                warn!("Key event lost, correcting with {:?}", Debug2Format(&event));
+                LINK_STATS.matrix_corrections.incr();
+                LAYOUT_CHANNEL.send((event, Instant::now())).await;
+            }
+        }
+        Message::Update(msg) => {
+            if PROTOCOL_MISMATCH.load(Ordering::Relaxed) {
+                warn!("Incompatible firmware on the other half, dropping update message");
+            } else if UPDATE_FROM_REMOTE.try_send(msg).is_err() {
+                warn!("Update message dropped");
+            }
+        }
+    }
+}
+
+/// Receive messages from the other half of the keyboard
+pub async fn usart_rx(mut rx: impl SplitRx) {
+    let identity = Identity::local();
+    info!("Firmware: {:?}", identity);
+    let mut decoder = FrameDecoder::new();
+    let mut buf: [u8; SERIAL_BUF_SIZE] = [0; SERIAL_BUF_SIZE];
+    loop {
+        let n = match rx.receive(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                if e == LinkError::Overrun {
+                    LINK_STATS.overruns.incr();
+                } else {
+                    LINK_STATS.uart_errors.incr();
+                }
+                warn!("Error reading from the other half: {:?}", e);
+                // Bytes were lost or corrupted, wait for the next frame
+                decoder.reset();
+                continue;
+            }
+        };
+        for &byte in &buf[..n] {
+            decoder.push(byte);
+            while let Some(res) = decoder.poll() {
+                match res {
+                    Ok(frame) => {
+                        LINK_ALIVE.signal(());
+                        if frame.lost > 0 {
+                            LINK_STATS.frames_lost.add(frame.lost as u32);
+                            warn!("{} frame(s) lost before frame {}", frame.lost, frame.seq);
+                        }
+                        match deserialize(&frame.payload) {
+                            Ok(msg) => {
+                                LINK_STATS.frames_received.incr();
+                                handle_message(msg, &identity).await
+                            }
+                            Err(()) => {
+                                LINK_STATS.frames_rejected.incr();
+                                warn!("Invalid message received: {=[u8]}", &frame.payload[..]);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        LINK_STATS.frames_rejected.incr();
+                        warn!("Corrupted frame dropped: {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Frame and send a message to the other half
+async fn send(tx: &mut impl SplitTx, encoder: &mut FrameEncoder, msg: Message) {
+    let mut buf: [u8; MAX_FRAME_SIZE] = [0; MAX_FRAME_SIZE];
+    let frame = encoder.encode(&serialize(msg), &mut buf);
+    for attempt in 0..=MAX_RETRANSMITS {
+        if attempt > 0 {
+            LINK_STATS.retransmits.incr();
+        }
+        match tx.send(frame).await {
+            Ok(()) => {
+                LINK_STATS.frames_sent.incr();
+                return;
+            }
+            Err(e) => warn!("Error writing to the other half: {:?}", e),
+        }
     }
+    error!(
+        "Frame {} dropped after {} retransmits",
+        frame[2], MAX_RETRANSMITS
+    );
 }
 
-/// Receive key events from the other half of the keyboard
-pub async fn usart_rx(mut buf_usart: BufferedUartRx<'_>) {
+/// Send key events, heartbeats or the state of the host half to the other
+/// half of the keyboard
+pub async fn usart_tx(mut tx: impl SplitTx) {
+    let identity = Identity::local();
+    let mut encoder = FrameEncoder::new();
+    let mut ticker = Ticker::every(HEARTBEAT_PERIOD);
+    // Keys of this half that were sent as pressed
+    let mut local_keys = KeySet::new();
     loop {
-        let mut buf: [u8; SERIALIZED_SIZE] = [0; SERIALIZED_SIZE];
-        buf_usart.read_exact(&mut buf).await.unwrap();
-        match deserialize(&buf) {
-            Ok(event) => {
-                LAYOUT_CHANNEL.send(event).await;
+        match select6(
+            SIDE_CHANNEL.receive(),
+            HOST_STATE_CHANGED.wait(),
+            LINK_RESTORED.wait(),
+            ROLE_CHANGED.wait(),
+            ticker.next(),
+            UPDATE_TO_REMOTE.receive(),
+        )
+        .await
+        {
+            Either6::First(event) => {
+                local_keys.update(event);
+                send(&mut tx, &mut encoder, Message::Event(event)).await;
+            }
+            Either6::Second(()) => {
+                if is_host() {
+                    let state = HOST_STATE.lock(|cell| cell.get());
+                    send(&mut tx, &mut encoder, Message::State(state)).await;
+                }
+            }
+            Either6::Third(()) => {
+                let msg = Message::Hello(identity.clone());
+                send(&mut tx, &mut encoder, msg).await;
+                // The other half released our keys when the link went down
+                for (i, j) in local_keys.iter() {
+                    let msg = Message::Event(Event::Press(i, j));
+                    send(&mut tx, &mut encoder, msg).await;
+                }
+            }
+            Either6::Fourth(()) => {
+                // Events sent before the role changed were discarded
+                local_keys = KeySet::new();
+                let msg = Message::Heartbeat {
+                    usb: CONFIGURED.load(Ordering::Relaxed),
+                    role: role(),
+                };
+                send(&mut tx, &mut encoder, msg).await;
+            }
+            Either6::Fifth(()) => {
+                let msg = Message::Heartbeat {
+                    usb: CONFIGURED.load(Ordering::Relaxed),
+                    role: role(),
+                };
+                send(&mut tx, &mut encoder, msg).await;
+                // Hello messages are sent again until both halves know the
+                // identity of the other one, in case they were lost
+                if is_link_up()
+                    && (!HELLO_RECEIVED.load(Ordering::Relaxed)
+                        || HELLO_REQUESTED.swap(false, Ordering::Relaxed))
+                {
+                    let msg = Message::Hello(identity.clone());
+                    send(&mut tx, &mut encoder, msg).await;
+                }
+                #[cfg(feature = "matrix_sync")]
+                if role() == Role::Secondary {
+                    let msg = Message::Matrix(local_keys.half_bitmap(LOCAL_FIRST_COL));
+                    send(&mut tx, &mut encoder, msg).await;
+                }
+            }
+            Either6::Sixth(msg) => {
+                send(&mut tx, &mut encoder, Message::Update(msg)).await;
             }
-            Err(()) => {
-                warn!("Invalid event received: {:?}", buf);
+        }
+    }
+}
+
+/// Monitor the link to the other half
+///
+/// When the link goes down, the keys of the other half that are still
+/// pressed are released.  When it comes back up, the state of the host half
+/// and the keys pressed are sent again.
+pub async fn link_monitor() {
+    loop {
+        let alive = with_timeout(LINK_TIMEOUT, LINK_ALIVE.wait()).await.is_ok();
+        if alive == is_link_up() || (!alive && LINK_MONITOR_PAUSED.load(Ordering::Relaxed)) {
+            continue;
+        }
+        LINK_UP.store(alive, Ordering::Relaxed);
+        if alive {
+            info!("Link to the other half is up");
+            LINK_RESTORED.signal(());
+            HOST_STATE_CHANGED.signal(());
+        } else {
+            warn!("Link to the other half is down");
+            HELLO_RECEIVED.store(false, Ordering::Relaxed);
+            negotiate_role();
+            let keys = REMOTE_KEYS.lock(|cell| cell.replace(KeySet::new()));
+            for (i, j) in keys.iter() {
+                LAYOUT_CHANNEL
+                    .send((Event::Release(i, j), Instant::now()))
+                    .await;
             }
         }
     }
 }
 
-/// Send key events to the other half of the keyboard
-pub async fn usart_tx(mut buf_usart: BufferedUartTx<'_>) {
+/// Period of the blinking of the led when the firmware of both halves differ
+const MISMATCH_BLINK_PERIOD: Duration = Duration::from_millis(250);
+
+/// Drive the led of the Black Pill from the state of the host half
+///
+/// The led is lit when Caps Lock or Caps Word is on and switched off when the USB bus
+/// is suspended.  It blinks when the firmware of the other half differs.
+/// While a leader sequence is typed, it blinks until the keys typed match a
+/// single sequence, then stays lit.
+#[cfg(not(test))]
+pub async fn state_handler(mut led: Output<'_>) {
+    let mut state = HostState::new();
     loop {
-        let event = SIDE_CHANNEL.receive().await;
-        let buf = serialize(event);
-        buf_usart.write_all(&buf).await.unwrap();
-        buf_usart.flush().await.unwrap();
+        if let Either::First(new_state) =
+            select(STATE_SIGNAL.wait(), Timer::after(MISMATCH_BLINK_PERIOD)).await
+        {
+            debug!("Host state: {:?}", new_state);
+            state = new_state;
+        }
+        if FIRMWARE_MISMATCH.load(Ordering::Relaxed) {
+            led.toggle();
+        } else if state.leader_unambiguous {
+            led.set_low();
+        } else if state.leader {
+            led.toggle();
+        } else if (state.caps_lock || state.caps_word) && !state.suspended {
+            // The led is active low
+            led.set_low();
+        } else {
+            led.set_high();
+        }
     }
 }
 
 /// Device configured flag
 static CONFIGURED: AtomicBool = AtomicBool::new(false);
 
-/// Whether the device is the host or not
-pub fn is_host() -> bool {
-    CONFIGURED.load(Ordering::Relaxed)
+/// Set whether the device is configured by USB and negotiate the role again
+fn set_configured(configured: bool) {
+    CONFIGURED.store(configured, Ordering::Relaxed);
+    negotiate_role();
 }
 
 /// Device Handler, used to know when it's configured
@@ -81,8 +718,21 @@ impl DeviceHandler {
 }
 
 impl Handler for DeviceHandler {
+    fn suspended(&mut self, suspended: bool) {
+        if suspended {
+            info!("Device suspended");
+        } else {
+            info!("Device resumed");
+        }
+        update_host_state(|s| s.suspended = suspended);
+    }
+
+    fn remote_wakeup_enabled(&mut self, enabled: bool) {
+        info!("Remote wakeup enabled by the host: {}", enabled);
+    }
+
     fn enabled(&mut self, enabled: bool) {
-        CONFIGURED.store(false, Ordering::Relaxed);
+        set_configured(false);
         if enabled {
             info!("Device enabled");
         } else {
@@ -91,17 +741,17 @@ impl Handler for DeviceHandler {
     }
 
     fn reset(&mut self) {
-        CONFIGURED.store(false, Ordering::Relaxed);
+        set_configured(false);
         info!("Bus reset, the Vbus current limit is 100mA");
     }
 
     fn addressed(&mut self, addr: u8) {
-        CONFIGURED.store(false, Ordering::Relaxed);
+        set_configured(false);
         info!("USB address set to: {}", addr);
     }
 
     fn configured(&mut self, configured: bool) {
-        CONFIGURED.store(configured, Ordering::Relaxed);
+        set_configured(configured);
         if configured {
             info!(
                 "Device configured, it may now draw up to the configured current limit from Vbus."
@@ -111,3 +761,43 @@ impl Handler for DeviceHandler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{Loopback, SplitTransport};
+    use core::{assert, assert_eq};
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select3, Either3};
+
+    #[test]
+    fn two_halves() {
+        let link = Loopback::<CriticalSectionRawMutex, SERIAL_BUF_SIZE>::new();
+        let (secondary, master) = link.ends();
+        let (_, secondary_tx) = secondary.split_link();
+        let (master_rx, _) = master.split_link();
+        // Both simulated halves share the state of this one, the master
+        set_configured(true);
+        assert!(is_host());
+        let key = Event::Press(1, REMOTE_FIRST_COL);
+        SIDE_CHANNEL.try_send(key).unwrap();
+        let received = async {
+            let (event, _) = LAYOUT_CHANNEL.receive().await;
+            // The heartbeat tells whether the other half is configured
+            while !REMOTE_USB.load(Ordering::Relaxed) {
+                Timer::after(HEARTBEAT_PERIOD).await;
+            }
+            event
+        };
+        let res = block_on(select3(
+            usart_tx(secondary_tx),
+            usart_rx(master_rx),
+            with_timeout(4 * HEARTBEAT_PERIOD, received),
+        ));
+        let Either3::Third(Ok(event)) = res else {
+            core::panic!("Nothing received from the other half");
+        };
+        assert_eq!(event, key);
+        assert!(REMOTE_KEYS.lock(|cell| cell.get().contains(1, REMOTE_FIRST_COL)));
+    }
+}
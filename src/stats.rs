@@ -0,0 +1,112 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use defmt::*;
+use embassy_time::{Duration, Ticker};
+
+/// Period between two reports of the statistics
+const REPORT_PERIOD: Duration = Duration::from_secs(60);
+
+/// Counter of the statistics of the split link
+pub struct Counter(AtomicU32);
+
+impl Counter {
+    /// Create a new counter
+    const fn new() -> Self {
+        Counter(AtomicU32::new(0))
+    }
+
+    /// Increment the counter
+    pub fn incr(&self) {
+        self.add(1);
+    }
+
+    /// Add `n` to the counter
+    pub fn add(&self, n: u32) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Get the value of the counter
+    fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Statistics of the split link
+pub struct LinkStats {
+    /// Valid frames received
+    pub frames_received: Counter,
+    /// Frames rejected because corrupted or not understood
+    pub frames_rejected: Counter,
+    /// Frames lost, according to the sequence numbers
+    pub frames_lost: Counter,
+    /// Frames sent
+    pub frames_sent: Counter,
+    /// Frames sent again after a write error
+    pub retransmits: Counter,
+    /// Receive overruns of the UART
+    pub overruns: Counter,
+    /// Other UART errors: framing, noise or parity
+    pub uart_errors: Counter,
+    /// Keys of the other half corrected from its matrix
+    pub matrix_corrections: Counter,
+}
+
+/// Statistics of the split link
+pub static LINK_STATS: LinkStats = LinkStats {
+    frames_received: Counter::new(),
+    frames_rejected: Counter::new(),
+    frames_lost: Counter::new(),
+    frames_sent: Counter::new(),
+    retransmits: Counter::new(),
+    overruns: Counter::new(),
+    uart_errors: Counter::new(),
+    matrix_corrections: Counter::new(),
+};
+
+/// Snapshot of the statistics of the split link
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct LinkStatsSnapshot {
+    /// Valid frames received
+    pub frames_received: u32,
+    /// Frames rejected because corrupted or not understood
+    pub frames_rejected: u32,
+    /// Frames lost, according to the sequence numbers
+    pub frames_lost: u32,
+    /// Frames sent
+    pub frames_sent: u32,
+    /// Frames sent again after a write error
+    pub retransmits: u32,
+    /// Receive overruns of the UART
+    pub overruns: u32,
+    /// Other UART errors: framing, noise or parity
+    pub uart_errors: u32,
+    /// Keys of the other half corrected from its matrix
+    pub matrix_corrections: u32,
+}
+
+/// Get the statistics of the split link
+pub fn link_stats() -> LinkStatsSnapshot {
+    LinkStatsSnapshot {
+        frames_received: LINK_STATS.frames_received.get(),
+        frames_rejected: LINK_STATS.frames_rejected.get(),
+        frames_lost: LINK_STATS.frames_lost.get(),
+        frames_sent: LINK_STATS.frames_sent.get(),
+        retransmits: LINK_STATS.retransmits.get(),
+        overruns: LINK_STATS.overruns.get(),
+        uart_errors: LINK_STATS.uart_errors.get(),
+        matrix_corrections: LINK_STATS.matrix_corrections.get(),
+    }
+}
+
+/// Report periodically the statistics of the split link, when they changed
+pub async fn stats_reporter() {
+    let mut ticker = Ticker::every(REPORT_PERIOD);
+    let mut last = link_stats();
+    loop {
+        ticker.next().await;
+        let stats = link_stats();
+        if stats != last {
+            info!("Split link statistics: {:?}", stats);
+            last = stats;
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use crate::layout::CustomEvent;
+use crate::side::KeySet;
+use keyberon::action::{Action, HoldTapAction};
+use keyberon::layout::{CustomEvent as KbCustomEvent, Event};
+
+/// Number of rows of the keyboard matrix, the other rows being virtual keys
+const MATRIX_ROWS: u8 = 4;
+
+/// Swap-hands mode, mirroring the keys to the other half
+///
+/// A key is released at the position it was pressed at, even if the mode
+/// changed while it was held.
+///
+/// A hold tap swapping the hands when held is held as soon as another key is
+/// pressed, for that key to be swapped already: the layout only sends the
+/// swap once the key reached it.
+pub struct SwapHands {
+    /// Whether a swap-hands key is held
+    held: bool,
+    /// Swap-hands hold tap pressed, held once another key is pressed
+    hold_tap: Option<(u8, u8)>,
+    /// Whether the swap is toggled on
+    toggled: bool,
+    /// Whether the next key is swapped
+    oneshot: bool,
+    /// Keys pressed while the hands were swapped, at their own position
+    swapped: KeySet,
+}
+
+impl SwapHands {
+    /// Create a new swap-hands mode, off
+    pub fn new() -> Self {
+        SwapHands {
+            held: false,
+            hold_tap: None,
+            toggled: false,
+            oneshot: false,
+            swapped: KeySet::new(),
+        }
+    }
+
+    /// Whether the hands are swapped
+    pub fn is_active(&self) -> bool {
+        self.held || self.toggled || self.oneshot
+    }
+
+    /// Process a custom event of the layout, changing the mode
+    pub fn custom_event(&mut self, event: &KbCustomEvent<CustomEvent>) {
+        match event {
+            KbCustomEvent::Press(CustomEvent::SwapHands) => self.held = true,
+            KbCustomEvent::Release(CustomEvent::SwapHands) => self.held = false,
+            KbCustomEvent::Press(CustomEvent::SwapHandsToggle) => {
+                self.toggled = !self.toggled;
+                self.oneshot = false;
+            }
+            KbCustomEvent::Press(CustomEvent::SwapHandsOneShot) => self.oneshot = true,
+            _ => {}
+        }
+    }
+
+    /// Mirror a key event to the other half if the hands are swapped,
+    /// `action` giving the action of the key at the position it is sent at
+    pub fn event(
+        &mut self,
+        event: Event,
+        action: impl Fn(Event) -> &'static Action<CustomEvent>,
+    ) -> Event {
+        let (i, _) = event.coord();
+        if i >= MATRIX_ROWS {
+            return event;
+        }
+        let swap = match event {
+            Event::Press(..) => {
+                if self.hold_tap.is_some() {
+                    self.held = true;
+                }
+                let swap = self.is_active();
+                self.oneshot = false;
+                if swap {
+                    self.swapped.update(event);
+                }
+                swap
+            }
+            Event::Release(..) => self.swapped.update(event),
+        };
+        let event = if swap {
+            // Same mirroring as the keys of the right half
+            event.transform(|i, j| (i, 9 - j))
+        } else {
+            event
+        };
+        match event {
+            Event::Press(i, j) if is_swap_hold_tap(action(event)) => self.hold_tap = Some((i, j)),
+            Event::Release(i, j) if self.hold_tap == Some((i, j)) => {
+                self.hold_tap = None;
+                self.held = false;
+            }
+            _ => {}
+        }
+        event
+    }
+}
+
+/// Whether `action` is a hold tap swapping the hands when held
+fn is_swap_hold_tap(action: &Action<CustomEvent>) -> bool {
+    matches!(
+        action,
+        Action::HoldTap(HoldTapAction {
+            hold: Action::Custom(CustomEvent::SwapHands),
+            ..
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::action::{k, HoldTapConfig};
+    use keyberon::key_code::KeyCode;
+
+    /// Swap the hands when held, or Tab, on the key (3, 3)
+    static SWAP_TAB: Action<CustomEvent> = Action::HoldTap(&HoldTapAction {
+        timeout: 200,
+        tap_hold_interval: 0,
+        config: HoldTapConfig::HoldOnOtherKeyPress,
+        hold: Action::Custom(CustomEvent::SwapHands),
+        tap: k(KeyCode::Tab),
+    });
+
+    /// Action of the key of `event`, the swap-hands hold tap on (3, 3)
+    fn action(event: Event) -> &'static Action<CustomEvent> {
+        match event.coord() {
+            (3, 3) => &SWAP_TAB,
+            _ => &Action::NoOp,
+        }
+    }
+
+    /// Send `event` through `swap_hands`, returning the event sent on
+    fn event(swap_hands: &mut SwapHands, event: Event) -> Event {
+        swap_hands.event(event, action)
+    }
+
+    #[test]
+    fn held() {
+        let mut swap_hands = SwapHands::new();
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(0, 0)),
+            Event::Press(0, 0)
+        );
+        swap_hands.custom_event(&KbCustomEvent::Press(&CustomEvent::SwapHands));
+        // Released where it was pressed
+        assert_eq!(
+            event(&mut swap_hands, Event::Release(0, 0)),
+            Event::Release(0, 0)
+        );
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(1, 2)),
+            Event::Press(1, 7)
+        );
+        swap_hands.custom_event(&KbCustomEvent::Release(&CustomEvent::SwapHands));
+        assert_eq!(
+            event(&mut swap_hands, Event::Release(1, 2)),
+            Event::Release(1, 7)
+        );
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(1, 2)),
+            Event::Press(1, 2)
+        );
+    }
+
+    #[test]
+    fn toggled() {
+        let mut swap_hands = SwapHands::new();
+        event(&mut swap_hands, Event::Press(2, 9));
+        swap_hands.custom_event(&KbCustomEvent::Press(&CustomEvent::SwapHandsToggle));
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(0, 6)),
+            Event::Press(0, 3)
+        );
+        assert_eq!(
+            event(&mut swap_hands, Event::Release(2, 9)),
+            Event::Release(2, 9)
+        );
+        swap_hands.custom_event(&KbCustomEvent::Press(&CustomEvent::SwapHandsToggle));
+        assert_eq!(
+            event(&mut swap_hands, Event::Release(0, 6)),
+            Event::Release(0, 3)
+        );
+    }
+
+    #[test]
+    fn oneshot() {
+        let mut swap_hands = SwapHands::new();
+        swap_hands.custom_event(&KbCustomEvent::Press(&CustomEvent::SwapHandsOneShot));
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(1, 0)),
+            Event::Press(1, 9)
+        );
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(1, 1)),
+            Event::Press(1, 1)
+        );
+        assert_eq!(
+            event(&mut swap_hands, Event::Release(1, 0)),
+            Event::Release(1, 9)
+        );
+        assert_eq!(
+            event(&mut swap_hands, Event::Release(1, 1)),
+            Event::Release(1, 1)
+        );
+    }
+
+    #[test]
+    fn hold_tap_rolled_into_a_key() {
+        let mut swap_hands = SwapHands::new();
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(3, 3)),
+            Event::Press(3, 3)
+        );
+        // Held as soon as the next key is pressed, before the layout sends
+        // the swap
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(1, 2)),
+            Event::Press(1, 7)
+        );
+        swap_hands.custom_event(&KbCustomEvent::Press(&CustomEvent::SwapHands));
+        assert_eq!(
+            event(&mut swap_hands, Event::Release(3, 3)),
+            Event::Release(3, 3)
+        );
+        swap_hands.custom_event(&KbCustomEvent::Release(&CustomEvent::SwapHands));
+        assert_eq!(
+            event(&mut swap_hands, Event::Release(1, 2)),
+            Event::Release(1, 7)
+        );
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(1, 2)),
+            Event::Press(1, 2)
+        );
+    }
+
+    #[test]
+    fn hold_tap_tapped() {
+        let mut swap_hands = SwapHands::new();
+        event(&mut swap_hands, Event::Press(3, 3));
+        event(&mut swap_hands, Event::Release(3, 3));
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(1, 2)),
+            Event::Press(1, 2)
+        );
+        assert!(!swap_hands.is_active());
+    }
+
+    #[test]
+    fn virtual_keys() {
+        let mut swap_hands = SwapHands::new();
+        swap_hands.custom_event(&KbCustomEvent::Press(&CustomEvent::SwapHandsToggle));
+        assert_eq!(
+            event(&mut swap_hands, Event::Press(MATRIX_ROWS, 0)),
+            Event::Press(MATRIX_ROWS, 0)
+        );
+    }
+}
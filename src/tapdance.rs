@@ -0,0 +1,301 @@
+use crate::layout::{CustomEvent, TAP_DANCE_ROW};
+use defmt::{error, warn};
+use heapless::Vec;
+use keyberon::action::Action;
+use keyberon::layout::{CustomEvent as KbCustomEvent, Event};
+
+/// Maximum number of tap dances held at the same time
+const MAX_HELD: usize = 4;
+
+/// Action depending on how many times its key is tapped in quick succession
+///
+/// To be declared as a `static` listed in the tap dances of the keymap, and
+/// used in the layout with `Action::Custom(CustomEvent::TapDance(&TD))`.
+#[derive(Debug)]
+pub struct TapDance {
+    /// Maximum time between two taps, and before a hold, in ms
+    pub timeout: u16,
+    /// Action for each number of taps
+    pub taps: &'static [Action<CustomEvent>],
+    /// Action for each number of taps when the key is held after the last
+    /// tap, the tap action when missing
+    pub holds: &'static [Action<CustomEvent>],
+}
+
+/// Tap dance whose number of taps is not known yet
+struct Pending {
+    /// Tap dance
+    tap_dance: &'static TapDance,
+    /// Number of taps so far
+    taps: usize,
+    /// Whether its key is held
+    held: bool,
+    /// Time since the last press or release of its key, in ms
+    elapsed: u16,
+}
+
+/// Tap dance engine, between the key events and the layout
+///
+/// The actions of the tap dances are the virtual keys of the tap dance row,
+/// the taps then the holds of each tap dance.
+pub struct TapDances {
+    /// Tap dances of the keymap, in the order of their virtual keys
+    tap_dances: &'static [&'static TapDance],
+    /// Tap dance being tapped
+    pending: Option<Pending>,
+    /// Virtual keys held by the tap dances resolved as held
+    held: Vec<(&'static TapDance, u8), MAX_HELD>,
+}
+
+impl TapDances {
+    /// Create a new tap dance engine
+    pub fn new(tap_dances: &'static [&'static TapDance]) -> Self {
+        TapDances {
+            tap_dances,
+            pending: None,
+            held: Vec::new(),
+        }
+    }
+
+    /// Column of the virtual key of `tap_dance` after `taps` taps
+    fn column(&self, tap_dance: &TapDance, taps: usize, held: bool) -> Option<u8> {
+        let mut column = 0;
+        for td in self.tap_dances {
+            if core::ptr::eq(*td, tap_dance) {
+                let index = taps.min(td.taps.len()).checked_sub(1)?;
+                return Some(if held && index < td.holds.len() {
+                    column + td.taps.len() + index
+                } else {
+                    column + index
+                } as u8);
+            }
+            column += td.taps.len() + td.holds.len();
+        }
+        error!("Tap dance missing from the keymap");
+        None
+    }
+
+    /// Trigger the action of the pending tap dance
+    fn resolve(&mut self, emit: &mut impl FnMut(Event)) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        let Some(column) = self.column(pending.tap_dance, pending.taps, pending.held) else {
+            return;
+        };
+        emit(Event::Press(TAP_DANCE_ROW, column));
+        if !pending.held {
+            emit(Event::Release(TAP_DANCE_ROW, column));
+        } else if self.held.push((pending.tap_dance, column)).is_err() {
+            warn!("Too many tap dances held");
+            emit(Event::Release(TAP_DANCE_ROW, column));
+        }
+    }
+
+    /// Process a custom event of the layout, counting the taps of the tap
+    /// dances
+    pub fn custom_event(
+        &mut self,
+        event: &KbCustomEvent<CustomEvent>,
+        mut emit: impl FnMut(Event),
+    ) {
+        match *event {
+            KbCustomEvent::Press(CustomEvent::TapDance(td)) => {
+                match &mut self.pending {
+                    Some(pending) if core::ptr::eq(pending.tap_dance, *td) => {
+                        pending.taps += 1;
+                        pending.held = true;
+                        pending.elapsed = 0;
+                        return;
+                    }
+                    Some(_) => self.resolve(&mut emit),
+                    None => {}
+                }
+                self.pending = Some(Pending {
+                    tap_dance: td,
+                    taps: 1,
+                    held: true,
+                    elapsed: 0,
+                });
+            }
+            KbCustomEvent::Release(CustomEvent::TapDance(td)) => match &mut self.pending {
+                Some(pending) if core::ptr::eq(pending.tap_dance, *td) => {
+                    pending.held = false;
+                    pending.elapsed = 0;
+                    // No more taps to wait for
+                    if pending.taps >= td.taps.len() {
+                        self.resolve(&mut emit);
+                    }
+                }
+                _ => {
+                    if let Some(index) = self.held.iter().position(|(t, _)| core::ptr::eq(*t, *td))
+                    {
+                        let (_, column) = self.held.swap_remove(index);
+                        emit(Event::Release(TAP_DANCE_ROW, column));
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// Process a key event with `action`, sending it to the layout through
+    /// `emit`
+    ///
+    /// Pressing another key ends the pending tap dance: it is a hold if its
+    /// key is still held.
+    pub fn event(
+        &mut self,
+        event: Event,
+        action: &Action<CustomEvent>,
+        mut emit: impl FnMut(Event),
+    ) {
+        if let (Event::Press(..), Some(pending)) = (event, &self.pending) {
+            let same_key = matches!(action,
+                Action::Custom(CustomEvent::TapDance(td)) if core::ptr::eq(*td, pending.tap_dance));
+            if !same_key {
+                self.resolve(&mut emit);
+            }
+        }
+        emit(event);
+    }
+
+    /// Count the time elapsed, resolving the pending tap dance on timeout
+    pub fn tick(&mut self, mut emit: impl FnMut(Event)) {
+        if let Some(pending) = &mut self.pending {
+            pending.elapsed = pending.elapsed.saturating_add(1);
+            if pending.elapsed >= pending.tap_dance.timeout {
+                self.resolve(&mut emit);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::action::{k, l};
+    use keyberon::key_code::KeyCode;
+    use std::vec;
+
+    /// A on tap, B on double tap, layer 1 when held after a tap, or the tap
+    /// action when held after two taps
+    static TD: TapDance = TapDance {
+        timeout: 200,
+        taps: &[k(KeyCode::A), k(KeyCode::B)],
+        holds: &[l(1)],
+    };
+    /// Tap dances of the tests
+    static TAP_DANCES: [&TapDance; 1] = [&TD];
+    /// Tap dance event of the layout
+    const TD_EVENT: CustomEvent = CustomEvent::TapDance(&TD);
+
+    /// Press or release the tap dance key, returning the events emitted
+    fn td(tap_dances: &mut TapDances, press: bool) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        let event = if press {
+            KbCustomEvent::Press(&TD_EVENT)
+        } else {
+            KbCustomEvent::Release(&TD_EVENT)
+        };
+        tap_dances.custom_event(&event, |e| events.push(e));
+        events
+    }
+
+    /// Count `ms` of time, returning the events emitted
+    fn tick(tap_dances: &mut TapDances, ms: u16) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        for _ in 0..ms {
+            tap_dances.tick(|e| events.push(e));
+        }
+        events
+    }
+
+    /// Tap of the virtual key at `column`
+    fn tap(column: u8) -> [Event; 2] {
+        [
+            Event::Press(TAP_DANCE_ROW, column),
+            Event::Release(TAP_DANCE_ROW, column),
+        ]
+    }
+
+    #[test]
+    fn single_tap() {
+        let mut tap_dances = TapDances::new(&TAP_DANCES);
+        td(&mut tap_dances, true);
+        td(&mut tap_dances, false);
+        assert!(tick(&mut tap_dances, 199).is_empty());
+        assert_eq!(tick(&mut tap_dances, 1), tap(0));
+    }
+
+    #[test]
+    fn double_tap() {
+        let mut tap_dances = TapDances::new(&TAP_DANCES);
+        td(&mut tap_dances, true);
+        td(&mut tap_dances, false);
+        tick(&mut tap_dances, 100);
+        td(&mut tap_dances, true);
+        // No more taps to wait for, resolved on release
+        assert_eq!(td(&mut tap_dances, false), tap(1));
+        assert!(tick(&mut tap_dances, 200).is_empty());
+    }
+
+    #[test]
+    fn hold() {
+        let mut tap_dances = TapDances::new(&TAP_DANCES);
+        td(&mut tap_dances, true);
+        assert_eq!(tick(&mut tap_dances, 200), [Event::Press(TAP_DANCE_ROW, 2)]);
+        assert_eq!(
+            td(&mut tap_dances, false),
+            [Event::Release(TAP_DANCE_ROW, 2)]
+        );
+    }
+
+    #[test]
+    fn hold_without_hold_action() {
+        let mut tap_dances = TapDances::new(&TAP_DANCES);
+        td(&mut tap_dances, true);
+        td(&mut tap_dances, false);
+        td(&mut tap_dances, true);
+        // The tap action is held instead
+        assert_eq!(tick(&mut tap_dances, 200), [Event::Press(TAP_DANCE_ROW, 1)]);
+        assert_eq!(
+            td(&mut tap_dances, false),
+            [Event::Release(TAP_DANCE_ROW, 1)]
+        );
+    }
+
+    #[test]
+    fn interrupted_after_tap() {
+        let mut tap_dances = TapDances::new(&TAP_DANCES);
+        td(&mut tap_dances, true);
+        td(&mut tap_dances, false);
+        let mut events = vec![];
+        tap_dances.event(Event::Press(0, 0), &k(KeyCode::C), |e| events.push(e));
+        assert_eq!(events, [tap(0).as_slice(), &[Event::Press(0, 0)]].concat());
+    }
+
+    #[test]
+    fn interrupted_while_held() {
+        let mut tap_dances = TapDances::new(&TAP_DANCES);
+        td(&mut tap_dances, true);
+        let mut events = vec![];
+        tap_dances.event(Event::Press(0, 0), &k(KeyCode::C), |e| events.push(e));
+        assert_eq!(events, [Event::Press(TAP_DANCE_ROW, 2), Event::Press(0, 0)]);
+        assert_eq!(
+            td(&mut tap_dances, false),
+            [Event::Release(TAP_DANCE_ROW, 2)]
+        );
+    }
+
+    #[test]
+    fn release_not_interrupting() {
+        let mut tap_dances = TapDances::new(&TAP_DANCES);
+        td(&mut tap_dances, true);
+        td(&mut tap_dances, false);
+        let mut events = vec![];
+        tap_dances.event(Event::Release(0, 0), &k(KeyCode::C), |e| events.push(e));
+        assert_eq!(events, [Event::Release(0, 0)]);
+        assert_eq!(tick(&mut tap_dances, 200), tap(0));
+    }
+}
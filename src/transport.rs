@@ -0,0 +1,271 @@
+#[cfg(feature = "half_duplex")]
+use core::cell::Cell;
+#[cfg(not(test))]
+use embassy_stm32::usart::{self, BufferedUart, BufferedUartRx, BufferedUartTx};
+#[cfg(feature = "half_duplex")]
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+#[cfg(test)]
+use embassy_sync::blocking_mutex::raw::RawMutex;
+#[cfg(feature = "half_duplex")]
+use embassy_sync::blocking_mutex::Mutex;
+#[cfg(test)]
+use embassy_sync::pipe::Pipe;
+#[cfg(feature = "half_duplex")]
+use embassy_time::{Duration, Instant, Timer};
+#[cfg(not(test))]
+use embedded_io_async::{Read, Write};
+
+/// Errors reported by a split link transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LinkError {
+    /// Bytes were received faster than they were read and some were lost
+    Overrun,
+    /// Bytes were corrupted on the line: framing, noise or parity error
+    Line,
+}
+
+#[cfg(not(test))]
+impl From<usart::Error> for LinkError {
+    fn from(e: usart::Error) -> Self {
+        match e {
+            usart::Error::Overrun => LinkError::Overrun,
+            _ => LinkError::Line,
+        }
+    }
+}
+
+/// Receiving side of a split link transport
+pub trait SplitRx {
+    /// Receive bytes from the other half into `buf`, returning how many
+    /// were received
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, LinkError>;
+}
+
+/// Sending side of a split link transport
+pub trait SplitTx {
+    /// Send all the bytes of `buf` to the other half and wait until they
+    /// are on the line
+    async fn send(&mut self, buf: &[u8]) -> Result<(), LinkError>;
+}
+
+/// Transport linking both halves of the keyboard
+pub trait SplitTransport {
+    /// Receiving side
+    type Rx: SplitRx;
+    /// Sending side
+    type Tx: SplitTx;
+
+    /// Split the transport into its receiving and sending sides, to be used
+    /// concurrently
+    fn split_link(self) -> (Self::Rx, Self::Tx);
+}
+
+#[cfg(not(test))]
+impl SplitRx for BufferedUartRx<'_> {
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, LinkError> {
+        Ok(Read::read(self, buf).await?)
+    }
+}
+
+#[cfg(not(test))]
+impl SplitTx for BufferedUartTx<'_> {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), LinkError> {
+        Write::write_all(self, buf).await?;
+        Ok(Write::flush(self).await?)
+    }
+}
+
+/// Full-duplex USART, or single-wire USART when created with
+/// `BufferedUart::new_half_duplex()` and wrapped in `HalfDuplex`
+#[cfg(not(test))]
+impl<'d> SplitTransport for BufferedUart<'d> {
+    type Rx = BufferedUartRx<'d>;
+    type Tx = BufferedUartTx<'d>;
+
+    fn split_link(self) -> (Self::Rx, Self::Tx) {
+        let (tx, rx) = self.split();
+        (rx, tx)
+    }
+}
+
+/// Time the line must be silent before sending on a half-duplex line
+#[cfg(feature = "half_duplex")]
+const LINE_IDLE: Duration = Duration::from_millis(1);
+/// Last time bytes were received on the half-duplex line
+#[cfg(feature = "half_duplex")]
+static LAST_RECEIVED: Mutex<CriticalSectionRawMutex, Cell<Instant>> =
+    Mutex::new(Cell::new(Instant::MIN));
+
+/// Transport over a single wire shared by both halves
+///
+/// Both halves may talk at the same time on the wire: before sending, wait
+/// for the line to be idle to limit the collisions. The frames corrupted by
+/// the remaining collisions are dropped by the frame decoder.
+#[cfg(feature = "half_duplex")]
+pub struct HalfDuplex<T>(pub T);
+
+/// Receiving side of a half-duplex transport
+#[cfg(feature = "half_duplex")]
+pub struct HalfDuplexRx<R>(R);
+
+/// Sending side of a half-duplex transport
+#[cfg(feature = "half_duplex")]
+pub struct HalfDuplexTx<T>(T);
+
+#[cfg(feature = "half_duplex")]
+impl<T: SplitTransport> SplitTransport for HalfDuplex<T> {
+    type Rx = HalfDuplexRx<T::Rx>;
+    type Tx = HalfDuplexTx<T::Tx>;
+
+    fn split_link(self) -> (Self::Rx, Self::Tx) {
+        let (rx, tx) = self.0.split_link();
+        (HalfDuplexRx(rx), HalfDuplexTx(tx))
+    }
+}
+
+#[cfg(feature = "half_duplex")]
+impl<R: SplitRx> SplitRx for HalfDuplexRx<R> {
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, LinkError> {
+        let res = self.0.receive(buf).await;
+        LAST_RECEIVED.lock(|c| c.set(Instant::now()));
+        res
+    }
+}
+
+#[cfg(feature = "half_duplex")]
+impl<T: SplitTx> SplitTx for HalfDuplexTx<T> {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), LinkError> {
+        loop {
+            let idle_at = LAST_RECEIVED.lock(|c| c.get()) + LINE_IDLE;
+            if Instant::now() >= idle_at {
+                break;
+            }
+            Timer::at(idle_at).await;
+        }
+        self.0.send(buf).await
+    }
+}
+
+/// In-memory link between two simulated halves of the keyboard
+///
+/// Each end of the link is a transport: what is sent on an end is received
+/// on the other one.
+#[cfg(test)]
+pub struct Loopback<M: RawMutex, const N: usize> {
+    /// Bytes sent from the first end to the second one
+    first_to_second: Pipe<M, N>,
+    /// Bytes sent from the second end to the first one
+    second_to_first: Pipe<M, N>,
+}
+
+/// An end of a loopback link
+#[cfg(test)]
+pub struct LoopbackEnd<'a, M: RawMutex, const N: usize> {
+    /// Bytes received by this end
+    rx: &'a Pipe<M, N>,
+    /// Bytes sent by this end
+    tx: &'a Pipe<M, N>,
+}
+
+#[cfg(test)]
+impl<M: RawMutex, const N: usize> Loopback<M, N> {
+    /// Create a new loopback link
+    pub const fn new() -> Self {
+        Loopback {
+            first_to_second: Pipe::new(),
+            second_to_first: Pipe::new(),
+        }
+    }
+
+    /// Get both ends of the link
+    pub fn ends(&self) -> (LoopbackEnd<'_, M, N>, LoopbackEnd<'_, M, N>) {
+        (
+            LoopbackEnd {
+                rx: &self.second_to_first,
+                tx: &self.first_to_second,
+            },
+            LoopbackEnd {
+                rx: &self.first_to_second,
+                tx: &self.second_to_first,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+impl<M: RawMutex, const N: usize> SplitRx for &Pipe<M, N> {
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, LinkError> {
+        Ok(Pipe::read(self, buf).await)
+    }
+}
+
+#[cfg(test)]
+impl<M: RawMutex, const N: usize> SplitTx for &Pipe<M, N> {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), LinkError> {
+        Pipe::write_all(self, buf).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl<'a, M: RawMutex, const N: usize> SplitTransport for LoopbackEnd<'a, M, N> {
+    type Rx = &'a Pipe<M, N>;
+    type Tx = &'a Pipe<M, N>;
+
+    fn split_link(self) -> (Self::Rx, Self::Tx) {
+        (self.rx, self.tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{FrameDecoder, FrameEncoder, MAX_FRAME_SIZE};
+    use embassy_futures::block_on;
+    use embassy_futures::join::join;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use heapless::Vec;
+
+    /// Payloads sent by each simulated half
+    const PAYLOADS: [&[u8]; 3] = [b"P\x01\x02", b"R\x01\x02", b"H\x01\x01"];
+
+    /// Simulated half: send the payloads to the other half, then receive
+    /// as many frames from it
+    async fn half(link: impl SplitTransport) -> Vec<Vec<u8, 8>, 4> {
+        let (mut rx, mut tx) = link.split_link();
+        let mut encoder = FrameEncoder::new();
+        let mut buf = [0; MAX_FRAME_SIZE];
+        for payload in PAYLOADS {
+            tx.send(encoder.encode(payload, &mut buf)).await.unwrap();
+        }
+        let mut decoder = FrameDecoder::new();
+        let mut received = Vec::new();
+        while received.len() < PAYLOADS.len() {
+            let n = rx.receive(&mut buf).await.unwrap();
+            for &byte in &buf[..n] {
+                decoder.push(byte);
+                while let Some(frame) = decoder.poll() {
+                    let frame = frame.unwrap();
+                    assert_eq!(frame.lost, 0);
+                    received
+                        .push(Vec::from_slice(&frame.payload).unwrap())
+                        .unwrap();
+                }
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn loopback() {
+        let link = Loopback::<NoopRawMutex, 64>::new();
+        let (first, second) = link.ends();
+        let (from_second, from_first) = block_on(join(half(first), half(second)));
+        for received in [from_second, from_first] {
+            assert_eq!(received.len(), PAYLOADS.len());
+            for (r, p) in received.iter().zip(PAYLOADS) {
+                assert_eq!(r.as_slice(), p);
+            }
+        }
+    }
+}
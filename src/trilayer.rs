@@ -0,0 +1,254 @@
+use crate::layout::{CustomEvent, TRI_LAYER_ROW};
+use defmt::warn;
+use heapless::Vec;
+use keyberon::action::Action;
+use keyberon::layout::Event;
+
+/// Maximum number of layer keys held at the same time
+const MAX_LAYER_KEYS: usize = 4;
+/// Maximum number of events held back while a tri-layer is activated
+const MAX_PENDING: usize = 8;
+
+/// Coordinates of a key, as (row, column)
+type Key = (u8, u8);
+
+/// Layer activated while two other layers are active
+///
+/// Keyberon adds up the layers held, the layer activated has to be above the
+/// sum of the two others.
+#[derive(Debug, Clone, Copy)]
+pub struct TriLayer {
+    /// Layers to hold
+    pub layers: [usize; 2],
+    /// Layer activated while both are held
+    pub layer: usize,
+}
+
+impl TriLayer {
+    /// Layer to add to the layers held to activate the layer
+    pub const fn offset(&self) -> usize {
+        assert!(
+            self.layer > self.layers[0] + self.layers[1],
+            "Tri-layer below the sum of its layers"
+        );
+        self.layer - self.layers[0] - self.layers[1]
+    }
+}
+
+/// Layer held by a key with `action`, if any
+fn held_layer(action: &Action<CustomEvent>) -> Option<usize> {
+    match action {
+        Action::Layer(layer) => Some(*layer),
+        Action::HoldTap(ht) => held_layer(&ht.hold),
+        _ => None,
+    }
+}
+
+/// Tri-layer rules, checked when the layer changes
+///
+/// The layer keys sent to the layout are tracked. When the current layer is
+/// made of the layers of a rule, the virtual key at the column of its index
+/// in the tri-layer row is pressed, until a key of these layers is released.
+///
+/// The layout activates the layers of the keys on its own ticks, the layer
+/// made of the sum of the layers of a rule being active until the virtual
+/// key is. Once the keys of both layers are held, the other events are held
+/// back until the virtual key is pressed, or a layer key is released.
+pub struct TriLayers {
+    /// Rules of the keymap
+    rules: &'static [TriLayer],
+    /// Layer keys held, with their layer
+    held: Vec<(Key, usize), MAX_LAYER_KEYS>,
+    /// Layer at the last check
+    layer: usize,
+    /// Rule whose virtual key is pressed
+    active: Option<usize>,
+    /// Events held back until the virtual key of a rule is pressed
+    pending: Vec<Event, MAX_PENDING>,
+}
+
+impl TriLayers {
+    /// Create a new tri-layer engine
+    pub fn new(rules: &'static [TriLayer]) -> Self {
+        TriLayers {
+            rules,
+            held: Vec::new(),
+            layer: 0,
+            active: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Whether a key of `layer` is held
+    fn is_held(&self, layer: usize) -> bool {
+        self.held.iter().any(|&(_, l)| l == layer)
+    }
+
+    /// Whether the keys of the layers of a rule are held, its virtual key
+    /// not being pressed yet
+    fn is_activating(&self) -> bool {
+        self.active.is_none()
+            && self
+                .rules
+                .iter()
+                .any(|r| r.layers.iter().all(|&l| self.is_held(l)))
+    }
+
+    /// Send the events held back
+    fn flush(&mut self, emit: &mut impl FnMut(Event)) {
+        for event in core::mem::take(&mut self.pending) {
+            emit(event);
+        }
+    }
+
+    /// Track the layer keys of the events sent to the layout, `action`
+    /// being the action of the key of the event
+    ///
+    /// The events to send to the layout are given to `emit`.
+    pub fn event(
+        &mut self,
+        event: Event,
+        action: &Action<CustomEvent>,
+        mut emit: impl FnMut(Event),
+    ) {
+        match event {
+            Event::Press(i, j) => {
+                if let Some(layer) = held_layer(action) {
+                    if self.held.push(((i, j), layer)).is_err() {
+                        warn!("Too many layer keys held");
+                    }
+                    if self.pending.is_empty() {
+                        emit(event);
+                        return;
+                    }
+                }
+            }
+            Event::Release(i, j) => {
+                if self.held.iter().any(|&(k, _)| k == (i, j)) {
+                    // The layer key may not be held long enough for its
+                    // layer to be active
+                    self.flush(&mut emit);
+                    self.held.retain(|&(k, _)| k != (i, j));
+                    emit(event);
+                    return;
+                }
+            }
+        }
+        if self.pending.is_empty() && !self.is_activating() {
+            emit(event);
+        } else if self.pending.push(event).is_err() {
+            warn!("Too many events held back by the tri-layer rules");
+            self.flush(&mut emit);
+            emit(event);
+        }
+    }
+
+    /// Apply the rules if the current `layer` changed
+    pub fn check(&mut self, layer: usize, mut emit: impl FnMut(Event)) {
+        if layer == self.layer {
+            return;
+        }
+        self.layer = layer;
+        match self.active {
+            Some(index) => {
+                let rule = &self.rules[index];
+                if !rule.layers.iter().all(|&l| self.is_held(l)) {
+                    emit(Event::Release(TRI_LAYER_ROW, index as u8));
+                    self.active = None;
+                }
+            }
+            None => {
+                let index = self.rules.iter().position(|r| {
+                    layer == r.layers[0] + r.layers[1] && r.layers.iter().all(|&l| self.is_held(l))
+                });
+                if let Some(index) = index {
+                    emit(Event::Press(TRI_LAYER_ROW, index as u8));
+                    self.active = Some(index);
+                    self.flush(&mut emit);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyberon::action::{k, l};
+    use keyberon::key_code::KeyCode;
+    use std::vec;
+
+    /// Layer 4 while the layers 1 and 2 are held
+    static RULES: [TriLayer; 1] = [TriLayer {
+        layers: [1, 2],
+        layer: 4,
+    }];
+    /// Key of layer 1
+    static LOWER: Action<CustomEvent> = l(1);
+    /// Key of layer 2
+    static RAISE: Action<CustomEvent> = l(2);
+    /// Other key
+    static KEY: Action<CustomEvent> = k(KeyCode::A);
+
+    /// Process `event` of a key with `action`, returning the events emitted
+    fn event(
+        tri_layers: &mut TriLayers,
+        event: Event,
+        action: &Action<CustomEvent>,
+    ) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        tri_layers.event(event, action, |e| events.push(e));
+        events
+    }
+
+    /// Check the rules on `layer`, returning the events emitted
+    fn check(tri_layers: &mut TriLayers, layer: usize) -> std::vec::Vec<Event> {
+        let mut events = vec![];
+        tri_layers.check(layer, |e| events.push(e));
+        events
+    }
+
+    #[test]
+    fn tri_layer() {
+        let mut tri_layers = TriLayers::new(&RULES);
+        event(&mut tri_layers, Event::Press(3, 3), &LOWER);
+        assert!(check(&mut tri_layers, 1).is_empty());
+        assert_eq!(
+            event(&mut tri_layers, Event::Press(3, 6), &RAISE),
+            [Event::Press(3, 6)]
+        );
+        // Not typed on layer 3, the sum of the layers held
+        assert!(event(&mut tri_layers, Event::Press(0, 0), &KEY).is_empty());
+        assert_eq!(
+            check(&mut tri_layers, 3),
+            [Event::Press(TRI_LAYER_ROW, 0), Event::Press(0, 0)]
+        );
+        assert_eq!(
+            event(&mut tri_layers, Event::Release(3, 6), &RAISE),
+            [Event::Release(3, 6)]
+        );
+        assert_eq!(
+            check(&mut tri_layers, 5),
+            [Event::Release(TRI_LAYER_ROW, 0)]
+        );
+        assert_eq!(
+            event(&mut tri_layers, Event::Release(0, 0), &KEY),
+            [Event::Release(0, 0)]
+        );
+    }
+
+    #[test]
+    fn layer_key_tapped() {
+        let mut tri_layers = TriLayers::new(&RULES);
+        event(&mut tri_layers, Event::Press(3, 3), &LOWER);
+        check(&mut tri_layers, 1);
+        event(&mut tri_layers, Event::Press(3, 6), &RAISE);
+        assert!(event(&mut tri_layers, Event::Press(0, 0), &KEY).is_empty());
+        // The events held back are sent before the release
+        assert_eq!(
+            event(&mut tri_layers, Event::Release(3, 6), &RAISE),
+            [Event::Press(0, 0), Event::Release(3, 6)]
+        );
+        assert!(check(&mut tri_layers, 1).is_empty());
+    }
+}
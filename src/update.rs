@@ -0,0 +1,717 @@
+use crate::image::{crc32_update, MAX_IMAGE_SIZE};
+use crate::side::{is_host, is_link_up, pause_link_monitor};
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_sync::{pipe::Pipe, signal::Signal};
+use embassy_time::{with_timeout, Duration};
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::Handler;
+use heapless::Vec;
+
+#[cfg(feature = "bootloader")]
+use crate::image::{ImageHeader, DFU_OFFSET, DFU_SIZE, IMAGE_OFFSET};
+#[cfg(feature = "bootloader")]
+use embassy_futures::yield_now;
+#[cfg(feature = "bootloader")]
+use embassy_time::Timer;
+#[cfg(feature = "bootloader")]
+use embedded_storage::nor_flash::NorFlash;
+
+/// Size of the image data carried by a chunk
+pub const CHUNK_SIZE: usize = 24;
+/// Maximum size of a serialized update message: a chunk with its type and
+/// offset
+pub const MAX_MESSAGE_SIZE: usize = 1 + 4 + CHUNK_SIZE;
+/// Number of times a message is sent again when not acknowledged
+const MAX_RETRIES: usize = 5;
+/// Time to wait for a chunk to be acknowledged
+const ACK_TIMEOUT: Duration = Duration::from_millis(250);
+/// Time to wait for the DFU partition to be erased
+const ERASE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time to wait for the image to be checked
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time to wait for the image to be received over USB
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time to wait for the next chunk before giving up the update
+#[cfg(feature = "bootloader")]
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Errors aborting a firmware update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum UpdateError {
+    /// The other half was not built to be updated
+    Unsupported,
+    /// The image does not fit in the DFU partition
+    TooLarge,
+    /// Erasing or writing the flash failed
+    Flash,
+    /// A message was received out of order
+    Sequence,
+    /// The CRC of the image received does not match
+    Crc,
+    /// The other half stopped answering
+    Timeout,
+    /// The image could not be read from the host
+    Source,
+    /// This half is not connected to the host
+    NotHost,
+}
+
+impl UpdateError {
+    /// Code of the error on the wire
+    fn code(self) -> u8 {
+        match self {
+            UpdateError::Unsupported => 1,
+            UpdateError::TooLarge => 2,
+            UpdateError::Flash => 3,
+            UpdateError::Sequence => 4,
+            UpdateError::Crc => 5,
+            UpdateError::Timeout => 6,
+            UpdateError::Source => 7,
+            UpdateError::NotHost => 8,
+        }
+    }
+
+    /// Error from its code on the wire
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(UpdateError::Unsupported),
+            2 => Some(UpdateError::TooLarge),
+            3 => Some(UpdateError::Flash),
+            4 => Some(UpdateError::Sequence),
+            5 => Some(UpdateError::Crc),
+            6 => Some(UpdateError::Timeout),
+            7 => Some(UpdateError::Source),
+            8 => Some(UpdateError::NotHost),
+            _ => None,
+        }
+    }
+}
+
+/// Messages of the firmware update protocol
+///
+/// The half connected to USB sends the image chunk by chunk, waiting for
+/// each chunk to be acknowledged before sending the next one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateMessage {
+    /// Start an update with an image of `size` bytes
+    Begin {
+        /// Size of the image
+        size: u32,
+        /// CRC-32 of the image
+        crc: u32,
+    },
+    /// Part of the image, starting at `offset`
+    Chunk {
+        /// Offset of the data in the image
+        offset: u32,
+        /// Data of the image
+        data: Vec<u8, CHUNK_SIZE>,
+    },
+    /// The whole image was sent
+    End,
+    /// Everything before `offset` was received
+    Ack {
+        /// Offset of the next data expected
+        offset: u32,
+    },
+    /// The update is aborted
+    Error(UpdateError),
+}
+
+impl UpdateMessage {
+    /// Deserialize a message, without the prefix of the update messages
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, ()> {
+        let word = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        match *bytes {
+            [b'B', ref b @ ..] if b.len() == 8 => Ok(UpdateMessage::Begin {
+                size: word(&b[0..4]),
+                crc: word(&b[4..8]),
+            }),
+            [b'C', ref b @ ..] if b.len() >= 4 => Ok(UpdateMessage::Chunk {
+                offset: word(&b[0..4]),
+                data: Vec::from_slice(&b[4..]).map_err(|_| ())?,
+            }),
+            [b'E'] => Ok(UpdateMessage::End),
+            [b'A', ref b @ ..] if b.len() == 4 => Ok(UpdateMessage::Ack { offset: word(b) }),
+            [b'X', code] => UpdateError::from_code(code)
+                .map(UpdateMessage::Error)
+                .ok_or(()),
+            _ => Err(()),
+        }
+    }
+
+    /// Serialize a message, without the prefix of the update messages
+    pub fn serialize(&self) -> Vec<u8, MAX_MESSAGE_SIZE> {
+        let mut bytes = Vec::new();
+        // Cannot fail since all messages fit in `MAX_MESSAGE_SIZE`
+        let _ = match self {
+            UpdateMessage::Begin { size, crc } => bytes
+                .extend_from_slice(b"B")
+                .and_then(|_| bytes.extend_from_slice(&size.to_le_bytes()))
+                .and_then(|_| bytes.extend_from_slice(&crc.to_le_bytes())),
+            UpdateMessage::Chunk { offset, data } => bytes
+                .extend_from_slice(b"C")
+                .and_then(|_| bytes.extend_from_slice(&offset.to_le_bytes()))
+                .and_then(|_| bytes.extend_from_slice(data)),
+            UpdateMessage::End => bytes.extend_from_slice(b"E"),
+            UpdateMessage::Ack { offset } => bytes
+                .extend_from_slice(b"A")
+                .and_then(|_| bytes.extend_from_slice(&offset.to_le_bytes())),
+            UpdateMessage::Error(e) => bytes.extend_from_slice(&[b'X', e.code()]),
+        };
+        bytes
+    }
+}
+
+/// Number of update messages in each channel
+const NB_MESSAGES: usize = 4;
+/// Update messages to send to the other half
+pub static UPDATE_TO_REMOTE: Channel<CriticalSectionRawMutex, UpdateMessage, NB_MESSAGES> =
+    Channel::new();
+/// Update messages received from the other half
+pub static UPDATE_FROM_REMOTE: Channel<CriticalSectionRawMutex, UpdateMessage, NB_MESSAGES> =
+    Channel::new();
+
+/// Link carrying the update messages between both halves
+///
+/// On the keyboard, the messages go through the split link.  Wiring two
+/// links crosswise over in-memory channels runs both sides of the protocol
+/// without any hardware.
+pub struct ChannelLink<'a> {
+    /// Messages to send to the other side
+    to_remote: &'a Channel<CriticalSectionRawMutex, UpdateMessage, NB_MESSAGES>,
+    /// Messages received from the other side
+    from_remote: &'a Channel<CriticalSectionRawMutex, UpdateMessage, NB_MESSAGES>,
+}
+
+impl<'a> ChannelLink<'a> {
+    /// Create a link from its channels
+    pub const fn new(
+        to_remote: &'a Channel<CriticalSectionRawMutex, UpdateMessage, NB_MESSAGES>,
+        from_remote: &'a Channel<CriticalSectionRawMutex, UpdateMessage, NB_MESSAGES>,
+    ) -> Self {
+        ChannelLink {
+            to_remote,
+            from_remote,
+        }
+    }
+
+    /// Send a message to the other side
+    async fn send(&mut self, msg: UpdateMessage) {
+        self.to_remote.send(msg).await;
+    }
+
+    /// Receive a message from the other side, unless `timeout` expires
+    async fn receive(&mut self, timeout: Duration) -> Option<UpdateMessage> {
+        with_timeout(timeout, self.from_remote.receive()).await.ok()
+    }
+
+    /// Wait for the acknowledgement of everything before `offset`
+    ///
+    /// Returns whether it was received before `timeout` expired.
+    async fn wait_ack(&mut self, offset: u32, timeout: Duration) -> Result<bool, UpdateError> {
+        while let Some(msg) = self.receive(timeout).await {
+            match msg {
+                UpdateMessage::Ack { offset: o } if o == offset => return Ok(true),
+                // Acknowledgement of a message sent again, or of a lost one
+                UpdateMessage::Ack { .. } => return Ok(false),
+                UpdateMessage::Error(e) => return Err(e),
+                _ => warn!("Unexpected update message: {:?}", Debug2Format(&msg)),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Send `msg` until everything before `offset` is acknowledged
+    async fn send_until_ack(
+        &mut self,
+        msg: UpdateMessage,
+        offset: u32,
+        timeout: Duration,
+    ) -> Result<(), UpdateError> {
+        for _ in 0..=MAX_RETRIES {
+            self.send(msg.clone()).await;
+            if self.wait_ack(offset, timeout).await? {
+                return Ok(());
+            }
+        }
+        Err(UpdateError::Timeout)
+    }
+}
+
+/// Fill `buf` with bytes read from `source`
+async fn read_exact<const N: usize>(source: &Pipe<CriticalSectionRawMutex, N>, mut buf: &mut [u8]) {
+    while !buf.is_empty() {
+        let n = source.read(buf).await;
+        buf = &mut buf[n..];
+    }
+}
+
+/// Send the image of `size` bytes read from `source` to the other half
+pub async fn send_image<const N: usize>(
+    link: &mut ChannelLink<'_>,
+    source: &Pipe<CriticalSectionRawMutex, N>,
+    size: u32,
+    crc: u32,
+) -> Result<(), UpdateError> {
+    if size > MAX_IMAGE_SIZE {
+        return Err(UpdateError::TooLarge);
+    }
+    // Drop the acknowledgements of a previous update
+    link.from_remote.clear();
+    link.send_until_ack(UpdateMessage::Begin { size, crc }, 0, ERASE_TIMEOUT)
+        .await?;
+    let mut offset = 0;
+    let mut computed_crc = 0;
+    while offset < size {
+        let len = CHUNK_SIZE.min((size - offset) as usize);
+        let mut data = Vec::<u8, CHUNK_SIZE>::new();
+        // Cannot fail since `len <= CHUNK_SIZE`
+        let _ = data.resize(len, 0);
+        with_timeout(SOURCE_TIMEOUT, read_exact(source, &mut data))
+            .await
+            .map_err(|_| UpdateError::Source)?;
+        computed_crc = crc32_update(computed_crc, &data);
+        let next = offset + len as u32;
+        link.send_until_ack(UpdateMessage::Chunk { offset, data }, next, ACK_TIMEOUT)
+            .await?;
+        offset = next;
+        UPDATE_PROGRESS.store(offset, Ordering::Relaxed);
+    }
+    if computed_crc != crc {
+        link.send(UpdateMessage::Error(UpdateError::Crc)).await;
+        return Err(UpdateError::Crc);
+    }
+    link.send_until_ack(UpdateMessage::End, size, CHECK_TIMEOUT)
+        .await
+}
+
+/// Data of the image not yet written to the flash, to write it by blocks
+/// of `NorFlash::WRITE_SIZE` bytes
+#[cfg(feature = "bootloader")]
+struct FlashWriter<'a, F: NorFlash> {
+    /// Flash to write to
+    flash: &'a mut F,
+    /// Offset in the flash of the data pending
+    offset: u32,
+    /// Data pending
+    pending: Vec<u8, { 2 * CHUNK_SIZE }>,
+}
+
+#[cfg(feature = "bootloader")]
+impl<'a, F: NorFlash> FlashWriter<'a, F> {
+    /// Write data to the flash, starting at `offset`
+    fn new(flash: &'a mut F, offset: u32) -> Self {
+        FlashWriter {
+            flash,
+            offset,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Append `data` to what is written
+    fn write(&mut self, data: &[u8]) -> Result<(), UpdateError> {
+        self.pending
+            .extend_from_slice(data)
+            .map_err(|_| UpdateError::Flash)?;
+        let len = self.pending.len() - self.pending.len() % F::WRITE_SIZE;
+        self.flash
+            .write(self.offset, &self.pending[..len])
+            .map_err(|_| UpdateError::Flash)?;
+        self.offset += len as u32;
+        self.pending.copy_within(len.., 0);
+        self.pending.truncate(self.pending.len() - len);
+        Ok(())
+    }
+
+    /// Write what is pending, padded with erased bytes
+    fn flush(&mut self) -> Result<(), UpdateError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let len = self.pending.len().next_multiple_of(F::WRITE_SIZE);
+        self.pending
+            .resize(len, 0xff)
+            .map_err(|_| UpdateError::Flash)?;
+        self.write(&[])
+    }
+}
+
+/// Compute the CRC-32 of the `size` bytes of the flash at `offset`
+#[cfg(feature = "bootloader")]
+fn flash_crc(flash: &mut impl NorFlash, offset: u32, size: u32) -> Result<u32, UpdateError> {
+    let mut buf = [0u8; 64];
+    let mut crc = 0;
+    let mut pos = 0;
+    while pos < size {
+        let len = buf.len().min((size - pos) as usize);
+        flash
+            .read(offset + pos, &mut buf[..len])
+            .map_err(|_| UpdateError::Flash)?;
+        crc = crc32_update(crc, &buf[..len]);
+        pos += len as u32;
+    }
+    Ok(crc)
+}
+
+/// Receive an image of `size` bytes into the DFU partition of `flash`
+///
+/// The header of the image is written once the whole image is received and
+/// its CRC checked, for the bootloader to install it on the next reset.
+#[cfg(feature = "bootloader")]
+pub async fn receive_image<F: NorFlash>(
+    link: &mut ChannelLink<'_>,
+    flash: &mut F,
+    size: u32,
+    crc: u32,
+) -> Result<(), UpdateError> {
+    if size > MAX_IMAGE_SIZE {
+        return Err(UpdateError::TooLarge);
+    }
+    info!("Receiving a firmware update of {} bytes", size);
+    // Erase sector by sector, letting the other tasks run in between
+    let mut from = DFU_OFFSET;
+    while from < DFU_OFFSET + DFU_SIZE {
+        let to = (from + F::ERASE_SIZE as u32).min(DFU_OFFSET + DFU_SIZE);
+        flash.erase(from, to).map_err(|_| UpdateError::Flash)?;
+        from = to;
+        yield_now().await;
+    }
+    link.send(UpdateMessage::Ack { offset: 0 }).await;
+    let mut writer = FlashWriter::new(flash, IMAGE_OFFSET);
+    let mut expected = 0;
+    loop {
+        match link.receive(RECEIVE_TIMEOUT).await {
+            Some(UpdateMessage::Chunk { offset, data }) => {
+                if offset == expected && expected + data.len() as u32 <= size {
+                    writer.write(&data)?;
+                    expected += data.len() as u32;
+                }
+                // Acknowledge again the chunks already received, their
+                // acknowledgement was lost
+                link.send(UpdateMessage::Ack { offset: expected }).await;
+            }
+            Some(UpdateMessage::Begin { .. }) if expected == 0 => {
+                // The acknowledgement of the erase was lost
+                link.send(UpdateMessage::Ack { offset: 0 }).await;
+            }
+            Some(UpdateMessage::End) if expected == size => break,
+            Some(UpdateMessage::Error(e)) => return Err(e),
+            Some(_) => return Err(UpdateError::Sequence),
+            None => return Err(UpdateError::Timeout),
+        }
+    }
+    writer.flush()?;
+    if flash_crc(flash, IMAGE_OFFSET, size)? != crc {
+        return Err(UpdateError::Crc);
+    }
+    let header = ImageHeader { size, crc }.to_bytes();
+    flash
+        .write(DFU_OFFSET, &header)
+        .map_err(|_| UpdateError::Flash)?;
+    link.send(UpdateMessage::Ack { offset: size }).await;
+    Ok(())
+}
+
+/// Size of the buffer of the image received over USB
+const IMAGE_PIPE_SIZE: usize = 512;
+/// Image received over USB, to be sent to the other half
+static IMAGE_PIPE: Pipe<CriticalSectionRawMutex, IMAGE_PIPE_SIZE> = Pipe::new();
+/// Signaled with the size and the CRC-32 of the image when an update is
+/// requested over USB
+static UPDATE_REQUESTED: Signal<CriticalSectionRawMutex, (u32, u32)> = Signal::new();
+/// State of the update, reported over USB
+static UPDATE_STATUS: AtomicU8 = AtomicU8::new(UpdateStatus::Idle as u8);
+/// Number of bytes of the image acknowledged by the other half
+static UPDATE_PROGRESS: AtomicU32 = AtomicU32::new(0);
+
+/// State of the update of the other half
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+enum UpdateStatus {
+    /// No update was requested
+    Idle = 0,
+    /// The image is being sent
+    InProgress = 1,
+    /// The other half received and checked the image, it is restarting
+    Done = 2,
+    /// The update failed
+    Failed = 3,
+}
+
+/// Vendor request starting an update, with the size and the CRC-32 of the
+/// image as data
+const REQ_BEGIN: u8 = 1;
+/// Vendor request carrying data of the image
+const REQ_DATA: u8 = 2;
+/// Vendor request reading the status of the update and the number of bytes
+/// acknowledged by the other half
+const REQ_STATUS: u8 = 3;
+
+/// Handler of the vendor requests used to update the other half
+///
+/// The image is pushed with `REQ_DATA` requests, rejected while the buffer
+/// is full: the host is expected to retry them.
+pub struct UpdateHandler {}
+
+impl UpdateHandler {
+    /// Create a new update handler
+    pub fn new() -> Self {
+        UpdateHandler {}
+    }
+}
+
+impl Handler for UpdateHandler {
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if req.request_type != RequestType::Vendor || req.recipient != Recipient::Device {
+            return None;
+        }
+        match (req.request, data) {
+            (REQ_BEGIN, [s0, s1, s2, s3, c0, c1, c2, c3]) => {
+                IMAGE_PIPE.clear();
+                UPDATE_PROGRESS.store(0, Ordering::Relaxed);
+                UPDATE_STATUS.store(UpdateStatus::InProgress as u8, Ordering::Relaxed);
+                UPDATE_REQUESTED.signal((
+                    u32::from_le_bytes([*s0, *s1, *s2, *s3]),
+                    u32::from_le_bytes([*c0, *c1, *c2, *c3]),
+                ));
+                Some(OutResponse::Accepted)
+            }
+            (REQ_DATA, data) if IMAGE_PIPE.free_capacity() >= data.len() => {
+                // Cannot fail since there is room for the data
+                let _ = IMAGE_PIPE.try_write(data);
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.request_type != RequestType::Vendor || req.recipient != Recipient::Device {
+            return None;
+        }
+        if req.request != REQ_STATUS || buf.len() < 5 {
+            return Some(InResponse::Rejected);
+        }
+        buf[0] = UPDATE_STATUS.load(Ordering::Relaxed);
+        buf[1..5].copy_from_slice(&UPDATE_PROGRESS.load(Ordering::Relaxed).to_le_bytes());
+        Some(InResponse::Accepted(&buf[..5]))
+    }
+}
+
+/// Update the other half with an image received over USB
+async fn update_remote(link: &mut ChannelLink<'_>, size: u32, crc: u32) -> Result<(), UpdateError> {
+    if !is_host() {
+        return Err(UpdateError::NotHost);
+    }
+    if !is_link_up() {
+        return Err(UpdateError::Timeout);
+    }
+    info!(
+        "Sending a firmware update of {} bytes to the other half",
+        size
+    );
+    send_image(link, &IMAGE_PIPE, size, crc).await
+}
+
+/// Handle the firmware updates
+///
+/// The half connected to USB sends the image it receives to the other half,
+/// which writes it for the bootloader to install it. The link monitor is
+/// paused during the update on both halves: erasing a 128K sector of the
+/// flash blocks the half receiving the image for longer than the link
+/// timeout.
+pub async fn update_handler(#[cfg(feature = "bootloader")] mut flash: impl NorFlash) {
+    let mut link = ChannelLink::new(&UPDATE_TO_REMOTE, &UPDATE_FROM_REMOTE);
+    loop {
+        match select(UPDATE_REQUESTED.wait(), UPDATE_FROM_REMOTE.receive()).await {
+            Either::First((size, crc)) => {
+                pause_link_monitor(true);
+                let res = update_remote(&mut link, size, crc).await;
+                pause_link_monitor(false);
+                let status = match res {
+                    Ok(()) => {
+                        info!("Firmware update sent to the other half");
+                        UpdateStatus::Done
+                    }
+                    Err(e) => {
+                        error!("Firmware update of the other half failed: {:?}", e);
+                        UpdateStatus::Failed
+                    }
+                };
+                UPDATE_STATUS.store(status as u8, Ordering::Relaxed);
+            }
+            Either::Second(UpdateMessage::Begin { size, crc }) => {
+                #[cfg(feature = "bootloader")]
+                {
+                    pause_link_monitor(true);
+                    let res = receive_image(&mut link, &mut flash, size, crc).await;
+                    pause_link_monitor(false);
+                    match res {
+                        Ok(()) => {
+                            info!("Firmware update received, restarting");
+                            // Let the acknowledgement reach the other half
+                            Timer::after(Duration::from_millis(100)).await;
+                            cortex_m::peripheral::SCB::sys_reset();
+                        }
+                        Err(e) => {
+                            error!("Firmware update failed: {:?}", e);
+                            link.send(UpdateMessage::Error(e)).await;
+                        }
+                    }
+                }
+                #[cfg(not(feature = "bootloader"))]
+                {
+                    warn!(
+                        "Firmware update of {} bytes ({=u32:#x}) refused: no bootloader",
+                        size, crc
+                    );
+                    link.send(UpdateMessage::Error(UpdateError::Unsupported))
+                        .await;
+                }
+            }
+            // Leftovers of an aborted update
+            Either::Second(_) => (),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "bootloader"))]
+mod tests {
+    use super::*;
+    use crate::image::HEADER_SIZE;
+    use core::{assert, assert_eq};
+    use embassy_futures::{block_on, join::join3};
+    use embedded_storage::nor_flash::{ErrorType, ReadNorFlash};
+    use std::vec;
+
+    /// Flash in memory, up to the end of the DFU partition
+    struct RamFlash {
+        /// Content of the flash
+        data: std::vec::Vec<u8>,
+        /// Number of erase operations
+        erases: usize,
+    }
+
+    impl RamFlash {
+        /// Create a flash full of garbage
+        fn new() -> Self {
+            RamFlash {
+                data: vec![0x42; (DFU_OFFSET + DFU_SIZE) as usize],
+                erases: 0,
+            }
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = core::convert::Infallible;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 0x4000;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.data[from as usize..to as usize].fill(0xff);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            assert_eq!(offset as usize % Self::WRITE_SIZE, 0);
+            assert_eq!(bytes.len() % Self::WRITE_SIZE, 0);
+            let offset = offset as usize;
+            assert!(self.data[offset..offset + bytes.len()]
+                .iter()
+                .all(|&b| b == 0xff));
+            self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    /// Send `image`, announced with `crc`, from one side to the other
+    ///
+    /// Returns the results of the sending and receiving sides, and the flash
+    /// of the receiving side.
+    fn transfer(
+        image: &[u8],
+        crc: u32,
+    ) -> (Result<(), UpdateError>, Result<(), UpdateError>, RamFlash) {
+        let to_receiver = Channel::new();
+        let to_sender = Channel::new();
+        let source: Pipe<CriticalSectionRawMutex, 256> = Pipe::new();
+        let mut sender = ChannelLink::new(&to_receiver, &to_sender);
+        let mut receiver = ChannelLink::new(&to_sender, &to_receiver);
+        let mut flash = RamFlash::new();
+        let size = image.len() as u32;
+        let (sent, received, _) = block_on(join3(
+            send_image(&mut sender, &source, size, crc),
+            receive_image(&mut receiver, &mut flash, size, crc),
+            source.write_all(image),
+        ));
+        (sent, received, flash)
+    }
+
+    /// Image of `size` bytes, not a multiple of the chunk or write sizes
+    fn image(size: usize) -> std::vec::Vec<u8> {
+        (0..size).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn send_and_receive() {
+        let image = image(1000);
+        let crc = crc32_update(0, &image);
+        let (sent, received, flash) = transfer(&image, crc);
+        assert_eq!(sent, Ok(()));
+        assert_eq!(received, Ok(()));
+        // Erased by steps, letting the other tasks run in between
+        assert_eq!(flash.erases, (DFU_SIZE as usize) / RamFlash::ERASE_SIZE);
+        let header: &[u8; HEADER_SIZE as usize] = flash.data
+            [DFU_OFFSET as usize..IMAGE_OFFSET as usize]
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            ImageHeader::from_bytes(header),
+            Some(ImageHeader { size: 1000, crc })
+        );
+        let start = IMAGE_OFFSET as usize;
+        assert_eq!(&flash.data[start..start + image.len()], image.as_slice());
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let image = image(300);
+        let crc = crc32_update(0, &image) ^ 1;
+        let (sent, received, flash) = transfer(&image, crc);
+        assert_eq!(sent, Err(UpdateError::Crc));
+        assert_eq!(received, Err(UpdateError::Crc));
+        // No header is written, the bootloader ignores the partition
+        let header = &flash.data[DFU_OFFSET as usize..IMAGE_OFFSET as usize];
+        assert!(header.iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn too_large() {
+        let mut link = ChannelLink::new(&UPDATE_TO_REMOTE, &UPDATE_FROM_REMOTE);
+        let source: Pipe<CriticalSectionRawMutex, 16> = Pipe::new();
+        let res = block_on(send_image(&mut link, &source, MAX_IMAGE_SIZE + 1, 0));
+        assert_eq!(res, Err(UpdateError::TooLarge));
+    }
+}
@@ -0,0 +1,91 @@
+use heapless::String;
+
+/// Name of the keymap
+#[cfg(feature = "keymap_basic")]
+const KEYMAP_NAME: &str = "keymap_basic";
+/// Name of the keymap
+#[cfg(feature = "keymap_borisfaure")]
+const KEYMAP_NAME: &str = "keymap_borisfaure";
+/// Name of the keymap
+#[cfg(feature = "keymap_test")]
+const KEYMAP_NAME: &str = "keymap_test";
+
+// Hash of the source of the keymap, generated by the build script
+include!(concat!(env!("OUT_DIR"), "/keymap_hash.rs"));
+
+/// Version of the protocol spoken over the split link
+///
+/// To be bumped on any incompatible change of the messages.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Maximum length of a keymap name
+pub const MAX_KEYMAP_NAME_LEN: usize = 20;
+
+/// Identity of the firmware running on a half of the keyboard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Version of the split link protocol
+    pub protocol: u8,
+    /// Firmware version: major, minor, patch
+    pub firmware: [u8; 3],
+    /// Name of the keymap
+    pub keymap_name: String<MAX_KEYMAP_NAME_LEN>,
+    /// Hash of the source of the keymap
+    pub keymap_hash: u32,
+}
+
+/// Outcome of the comparison of the identities of both halves
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Compatibility {
+    /// Both halves run the same firmware
+    Same,
+    /// The firmware version or the keymap differ, both halves can still
+    /// talk to each other
+    Mismatch,
+    /// The split link protocols differ
+    Incompatible,
+}
+
+impl Identity {
+    /// Identity of the firmware of this half
+    pub fn local() -> Self {
+        let mut keymap_name = String::new();
+        // The keymap names are shorter than `MAX_KEYMAP_NAME_LEN`
+        let _ = keymap_name.push_str(KEYMAP_NAME);
+        Identity {
+            protocol: PROTOCOL_VERSION,
+            firmware: [
+                env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+                env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+                env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+            ],
+            keymap_name,
+            keymap_hash: KEYMAP_HASH,
+        }
+    }
+
+    /// Compare with the identity of the other half
+    pub fn compare(&self, other: &Identity) -> Compatibility {
+        if self.protocol != other.protocol {
+            Compatibility::Incompatible
+        } else if self != other {
+            Compatibility::Mismatch
+        } else {
+            Compatibility::Same
+        }
+    }
+}
+
+impl defmt::Format for Identity {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "protocol v{}, firmware v{}.{}.{}, keymap {} ({=u32:#x})",
+            self.protocol,
+            self.firmware[0],
+            self.firmware[1],
+            self.firmware[2],
+            self.keymap_name.as_str(),
+            self.keymap_hash
+        )
+    }
+}
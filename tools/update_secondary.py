@@ -0,0 +1,80 @@
+#!/usr/bin/env python3
+"""Update the firmware of the half of the Cantor36 not connected to USB.
+
+The image is sent to the half connected to USB, which forwards it to the
+other half over the split link.  Requires pyusb.
+
+Usage: update_secondary.py firmware.bin
+"""
+
+import struct
+import sys
+import time
+import zlib
+
+import usb.core
+
+VID = 0x16C0
+PID = 0x27DB
+
+# Vendor requests, see src/update.rs
+REQ_BEGIN = 1
+REQ_DATA = 2
+REQ_STATUS = 3
+
+STATUS_IN_PROGRESS = 1
+STATUS_DONE = 2
+STATUS_FAILED = 3
+
+# Size of the data of each request, the size of the control buffer
+DATA_SIZE = 64
+# Host to device, vendor, device
+OUT = 0x40
+# Device to host, vendor, device
+IN = 0xC0
+
+
+def status(dev):
+    data = dev.ctrl_transfer(IN, REQ_STATUS, 0, 0, 5)
+    return struct.unpack("<BI", bytes(data))
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+    with open(sys.argv[1], "rb") as f:
+        image = f.read()
+    dev = usb.core.find(idVendor=VID, idProduct=PID)
+    if dev is None:
+        sys.exit("Keyboard not found")
+
+    crc = zlib.crc32(image)
+    dev.ctrl_transfer(OUT, REQ_BEGIN, 0, 0, struct.pack("<II", len(image), crc))
+    for offset in range(0, len(image), DATA_SIZE):
+        chunk = image[offset : offset + DATA_SIZE]
+        # Rejected while the keyboard buffer is full
+        while True:
+            try:
+                dev.ctrl_transfer(OUT, REQ_DATA, 0, 0, chunk)
+                break
+            except usb.core.USBError:
+                state, _ = status(dev)
+                if state == STATUS_FAILED:
+                    sys.exit("Update failed")
+                time.sleep(0.01)
+        print(f"\r{offset + len(chunk)}/{len(image)} bytes", end="", flush=True)
+    print()
+
+    while True:
+        state, acked = status(dev)
+        if state == STATUS_DONE:
+            print("Update done, the other half is restarting")
+            return
+        if state != STATUS_IN_PROGRESS:
+            sys.exit("Update failed")
+        print(f"\r{acked}/{len(image)} bytes acknowledged", end="", flush=True)
+        time.sleep(0.1)
+
+
+if __name__ == "__main__":
+    main()